@@ -0,0 +1,59 @@
+use std::{hint::black_box, thread};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sha2::{Digest, Sha256};
+
+use blockchain_core::{blockchain::{Block, BlockHeader}, hash::Hash256, miner::{CancelToken, Miner}, pow::{self, U256}, transaction::Transaction};
+
+const NONCES: u64 = 10_000;
+
+fn template(bits: u32) -> Block {
+    Block::new(Hash256::digest(b"parent"), 1, vec![Transaction::new_coinbase(String::from("M"), 50)], bits).unwrap()
+}
+
+// 바이트 해싱으로 바꾸기 전의 헤더 해시: hex 문자열과 10진수 문자열을 이어서 해시하고, 비교하려고 다시 hex 를 푼다.
+fn string_hash_meets_target(header: &BlockHeader, nonce: u64, target: U256) -> bool {
+    let mut hasher = Sha256::new();
+    hasher.update(header.previous_hash.to_hex().as_bytes());
+    hasher.update(header.merkle_root.to_hex().as_bytes());
+    hasher.update(header.timestamp.to_string().as_bytes());
+    hasher.update(nonce.to_string().as_bytes());
+    let block_hash = hex::encode(hasher.finalize());
+
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(&block_hash, &mut hash).is_ok() && U256::from_be_bytes(hash) <= target
+}
+
+fn bench_header_hash(c: &mut Criterion) {
+    let block = template(0x0300_0001);
+    let target = pow::target_from_bits(block.header.bits).unwrap();
+
+    let mut group = c.benchmark_group("header_hash");
+    group.throughput(Throughput::Elements(NONCES));
+    group.bench_function("string", |b| b.iter(|| {
+        (0..NONCES).filter(|nonce| string_hash_meets_target(&block.header, *nonce, target)).count()
+    }));
+    group.bench_function("bytes", |b| b.iter(|| {
+        let prefix = block.header.prefix_hasher();
+        (0..NONCES).filter(|nonce| U256::from_be_bytes(BlockHeader::hash_with_nonce(&prefix, *nonce).0) <= target).count()
+    }));
+    group.finish();
+}
+
+// 평균 4096 번 해시하면 찾는 난이도
+fn bench_miner(c: &mut Criterion) {
+    let block = template(0x1f10_0000);
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+
+    let mut group = c.benchmark_group("miner");
+    for threads in [1, threads] {
+        let miner = Miner::new(threads);
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &miner, |b, miner| {
+            b.iter(|| miner.mine(black_box(block.clone()), &CancelToken::new()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_header_hash, bench_miner);
+criterion_main!(benches);
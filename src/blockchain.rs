@@ -1,30 +1,76 @@
 #![allow(unused)]
-use std::collections::{HashMap, HashSet};
+use std::{collections::{HashMap, HashSet}, io, path::Path};
 use sha2::{Sha256, Digest};
 use p256::ecdsa::{signature::{self, Signer, Verifier}, Signature, SigningKey, VerifyingKey};
 use rand::Rng;
-use hex;
 
-use crate::{merkle_tree::MerkleTree, transaction::{self, Transaction}, utils::current_timestamp};
+use crate::{error::{BlockError, ChainError, TxError}, hash::Hash256, ledger::{AccountLedger, Ledger}, merkle_tree::{self, MerkleProof, MerkleTree}, miner::{CancelToken, Miner}, pow::{self, U256}, storage::BlockStore, transaction::{self, Transaction}, utils::current_timestamp, wallet::address_from_public_key};
+
+// 블록 타임스탬프는 최근 블록들의 중앙값보다 커야 한다.
+const MEDIAN_TIME_SPAN: usize = 11;
+// 미래 시각으로 너무 앞선 블록은 받지 않는다. (2시간)
+const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+// 이보다 낮은 버전의 헤더는 받지 않는다. 규칙이 바뀔 때 올린다.
+pub const BLOCK_VERSION: u32 = 1;
+// 새 체인의 기본 난이도 파라미터. 1번 블록의 목표값, 블록 간격(ms), 조정 주기
+pub const INITIAL_BITS: u32 = 0x1f10_0000;
+pub const BLOCK_TIME: u128 = 60000;
+pub const ADJUSTMENT_INTERVAL: usize = 10;
+
 
 #[derive(Debug, Clone)]
 pub struct BlockHeader {
-    pub previous_hash: String,
-    pub block_hash: String,
-    pub merkle_tree: MerkleTree,
+    pub version: u32,
+    pub previous_hash: Hash256,
+    pub block_hash: Hash256,
+    // 잎은 본문의 트랜잭션으로 다시 만든다. 헤더는 크기가 고정이다.
+    pub merkle_root: Hash256,
+    pub tx_count: u64,
+    // 이 블록을 적용한 뒤의 상태 루트 (Ledger::state_root)
+    pub state_root: Hash256,
     pub timestamp: u128,
+    pub height: u64,
     pub nonce: u64,
-    pub difficulty: usize,
+    // 목표값의 compact 표현 (pow::target_from_bits)
+    pub bits: u32,
 }
 
 impl BlockHeader {
-    pub fn calculate_hash(&self) -> String {
+    // version(u32) || previous_hash(32) || merkle_root(32) || tx_count(u64) || state_root(32) || timestamp(u128) || height(u64) || bits(u32) || nonce(u64), 정수는 LE
+    pub fn calculate_hash(&self) -> Hash256 {
+        BlockHeader::hash_with_nonce(&self.prefix_hasher(), self.nonce)
+    }
+
+    // nonce 앞까지 넣어 둔 hasher. 채굴할 때는 이것을 복사해서 nonce 만 더 넣는다.
+    pub fn prefix_hasher(&self) -> Sha256 {
         let mut hasher = Sha256::new();
+        hasher.update(self.version.to_le_bytes());
         hasher.update(self.previous_hash.as_bytes());
-        hasher.update(self.merkle_tree.root.as_bytes());
-        hasher.update(self.timestamp.to_string().as_bytes());
-        hasher.update(self.nonce.to_string().as_bytes());
-        hex::encode(hasher.finalize())
+        hasher.update(self.merkle_root.as_bytes());
+        hasher.update(self.tx_count.to_le_bytes());
+        hasher.update(self.state_root.as_bytes());
+        hasher.update(self.timestamp.to_le_bytes());
+        hasher.update(self.height.to_le_bytes());
+        hasher.update(self.bits.to_le_bytes());
+        hasher
+    }
+
+    pub fn hash_with_nonce(prefix: &Sha256, nonce: u64) -> Hash256 {
+        let mut hasher = prefix.clone();
+        hasher.update(nonce.to_le_bytes());
+        Hash256::from_hasher(hasher)
+    }
+
+    pub fn work(&self) -> U256 {
+        pow::work_from_bits(self.bits)
+    }
+
+    // 해시를 256비트 정수로 봤을 때 목표값 이하여야 한다. 잘못된 bits 나 pow_limit 보다 쉬운 목표값은 통과하지 못한다.
+    pub fn meets_target(&self) -> bool {
+        let Some(target) = pow::target_from_bits(self.bits).filter(|target| *target <= pow::pow_limit()) else {
+            return false;
+        };
+        U256::from_be_bytes(self.block_hash.0) <= target
     }
 }
 
@@ -35,199 +81,747 @@ pub struct Block {
 }
 
 impl Block {
-    pub fn new(previous_hash: String, transactions: Vec<Transaction>, difficulty: usize) -> Block {
+    // state_root 는 빈 상태의 루트로 시작한다. 상태가 있으면 채굴 전에 채워야 한다.
+    pub fn new(previous_hash: Hash256, height: u64, transactions: Vec<Transaction>, bits: u32) -> Result<Block, TxError> {
 
         let timestamp = current_timestamp();
-        let merkle_tree = MerkleTree::new(&transactions);
+        let merkle_tree = MerkleTree::new(&transactions)?;
         let nonce = 0;
 
         let mut header = BlockHeader {
+            version: BLOCK_VERSION,
             previous_hash,
-            block_hash: String::new(),
-            merkle_tree,
+            block_hash: Hash256::ZERO,
+            merkle_root: merkle_tree.root,
+            tx_count: merkle_tree.len() as u64,
+            state_root: Hash256::ZERO,
             timestamp,
+            height,
             nonce,
-            difficulty
+            bits
         };
 
         header.block_hash = header.calculate_hash();
 
-        Block {
+        Ok(Block {
             header,
             transactions
-        }
+        })
     }
 
-    // PoW
-    pub fn mine_block(&mut self) {
+    // PoW. 스레드 하나로 끝까지 찾는다. 여러 스레드나 취소가 필요하면 miner::Miner 를 쓴다.
+    // 취소하지 않으므로 bits 가 잘못됐을 때만 실패한다.
+    pub fn mine_block(&mut self) -> Result<(), BlockError> {
+        let report = Miner::new(1).mine(self.clone(), &CancelToken::new());
+        *self = report.block.ok_or(BlockError::UnminableBits(self.header.bits))?;
+        Ok(())
+    }
 
-        let difficulty = self.header.difficulty;
-        let target = "0".repeat(difficulty);
+    // 증명을 만들 때 본문에서 트리를 다시 만든다.
+    pub fn merkle_tree(&self) -> Result<MerkleTree, TxError> {
+        MerkleTree::new(&self.transactions)
+    }
 
-        while &self.header.block_hash[..difficulty] != target {
-            self.header.nonce += 1;
-            self.header.block_hash = self.header.calculate_hash();
+    // 본문이 헤더의 머클 루트와 트랜잭션 수에 맞는지 본다.
+    pub fn check_merkle_root(&self) -> Result<(), BlockError> {
+        let txids: Vec<Hash256> = self.transactions.iter().filter_map(|tx| tx.calculate_hash().ok()).collect();
+        if let Some(i) = merkle_tree::first_duplicate(&txids) {
+            return Err(BlockError::Transaction(i, TxError::Duplicate));
+        }
+        let merkle_tree = self.merkle_tree().map_err(|e| BlockError::Transaction(0, e))?;
+        if merkle_tree.root != self.header.merkle_root || merkle_tree.len() as u64 != self.header.tx_count {
+            return Err(BlockError::MerkleRootMismatch);
         }
+        Ok(())
+    }
+}
 
-        println!("블록이 성공적으로 채굴되었습니다. Nonce: {}, Hash: {}",
-            self.header.nonce, self.header.calculate_hash());
+// 블록 트리의 노드. 활성 체인에 없는 사이드 브랜치 블록도 여기에 들어간다.
+#[derive(Debug, Clone)]
+pub struct BlockNode<U> {
+    pub block: Block,
+    pub height: usize,
+    pub chain_work: U256,
+    // 한 번이라도 활성 체인에 연결됐던 블록만 가진다.
+    pub undo: Option<U>,
+}
 
-        self.header.block_hash = self.header.calculate_hash();
-    }
+#[derive(Debug)]
+pub enum BlockStatus {
+    Connected,
+    SideBranch,
+    // 활성 체인이 바뀜. 새 체인에 들어가지 못한 트랜잭션들은 mempool 로 돌려보내야 한다.
+    Reorganized(Vec<Transaction>),
 }
 
+// 상태 모델은 L 로 고른다. 기본은 계정 잔액 모델
 #[derive(Debug)]
-pub struct BlockChain {
+pub struct BlockChain<L: Ledger = AccountLedger> {
+    // 활성 체인 (누적 작업량이 가장 큰 브랜치)
     pub chain: Vec<Block>,
-    pub accounts: HashMap<String, u64>,
-    pub transaction_history: HashSet<String>,
+    pub block_index: HashMap<Hash256, BlockNode<L::Undo>>,
+    pub ledger: L,
 
-    pub difficulty: usize,
+    // 1번 블록의 목표값. 이후는 조정 주기마다 next_bits 에서 다시 계산한다.
+    pub initial_bits: u32,
     pub block_time: u128,
     pub adjustment_interval: usize,
+
+    // 블록 보상: halving_interval 블록마다 절반, 누적 발행량은 max_supply 까지
+    pub initial_subsidy: u64,
+    pub halving_interval: u64,
+    pub max_supply: u64,
+
+    // None 이면 메모리에만 존재하는 체인
+    pub store: Option<BlockStore>,
 }
 
 impl BlockChain {
     pub fn new() -> BlockChain {
-        let mut blockchain = BlockChain {
-            chain: Vec::new(),
-            accounts: HashMap::new(),
-            transaction_history: HashSet::new(),
-            difficulty: 3,
-            block_time: 60000,
-            adjustment_interval: 10
-        };
-        blockchain.add_genesis_block();
+        BlockChain::with_ledger(AccountLedger::default())
+    }
+
+    pub fn open(data_dir: impl AsRef<Path>) -> Result<BlockChain, ChainError> {
+        BlockChain::open_with_ledger(data_dir, AccountLedger::default())
+    }
+}
+
+impl Default for BlockChain {
+    fn default() -> BlockChain {
+        BlockChain::new()
+    }
+}
+
+impl<L: Ledger> BlockChain<L> {
+    // ledger 는 제네시스 블록 이전 상태
+    pub fn with_ledger(ledger: L) -> BlockChain<L> {
+        let mut blockchain = BlockChain::empty(ledger);
+        blockchain.add_genesis_block().expect("In-memory chain has no store to fail.");
         blockchain
     }
 
-    pub fn add_genesis_block(&mut self) {
-        let root_tx = Transaction::new(
-            "".to_string(),
-            "".to_string(),
-            0,
+    // 다른 노드와 같은 제네시스로 시작한다. 같은 네트워크의 노드끼리는 제네시스가 같아야 동기화할 수 있다.
+    pub fn with_genesis(ledger: L, genesis: Block) -> BlockChain<L> {
+        let mut blockchain = BlockChain::empty(ledger);
+        blockchain.index_block(genesis.clone());
+        blockchain.store_block(genesis).expect("In-memory chain has no store to fail.");
+        blockchain
+    }
+
+    // data_dir 에 저장된 블록들로 블록 트리를 다시 만들고, 가장 무거운 브랜치를 적용해서 상태를 복원한다.
+    pub fn open_with_ledger(data_dir: impl AsRef<Path>, ledger: L) -> Result<BlockChain<L>, ChainError> {
+        BlockChain::open_inner(data_dir, ledger, None)
+    }
+
+    // 저장된 블록이 없으면 genesis 로 시작한다.
+    pub fn open_with_genesis(data_dir: impl AsRef<Path>, ledger: L, genesis: Block) -> Result<BlockChain<L>, ChainError> {
+        BlockChain::open_inner(data_dir, ledger, Some(genesis))
+    }
+
+    // 받은 블록은 사이드 브랜치까지 모두 저장되어 있다. undo 기록이 있으면 한 번 연결되면서 검증된 블록이고,
+    // 없으면 아직 활성화된 적이 없는 사이드 브랜치 블록이다. 검증된 블록 중 가장 무거운 브랜치를 먼저 적용하고,
+    // 그보다 무거운 사이드 브랜치가 있으면 reorganize 로 검증하면서 옮겨 간다.
+    fn open_inner(data_dir: impl AsRef<Path>, ledger: L, genesis: Option<Block>) -> Result<BlockChain<L>, ChainError> {
+        let store = BlockStore::open(data_dir)?;
+        let blocks = store.load_blocks()?;
+
+        let mut blockchain = BlockChain::empty(ledger);
+        let mut best_tip: Option<Hash256> = None;
+        for (i, block) in blocks.into_iter().enumerate() {
+            if i > 0 && !blockchain.block_index.contains_key(&block.header.previous_hash) {
+                return Err(ChainError::BrokenLink(i));
+            }
+            let block_hash = block.header.block_hash;
+            blockchain.index_block(block);
+            if i > 0 && !store.has_undo(&block_hash) {
+                continue;
+            }
+
+            let is_better = best_tip.as_ref()
+                .is_none_or(|best| blockchain.block_index[&block_hash].chain_work > blockchain.block_index[best].chain_work);
+            if is_better {
+                best_tip = Some(block_hash);
+            }
+        }
+        blockchain.store = Some(store);
+
+        match best_tip {
+            Some(tip) => {
+                // 제네시스 전 상태는 저장하지 않으므로, 같은 ledger 로 열었는지 블록마다 state_root 로 확인한다.
+                for block_hash in blockchain.branch_from_fork(&tip) {
+                    let block = blockchain.block_index[&block_hash].block.clone();
+                    let (height, found) = (block.header.height, block.header.state_root);
+                    blockchain.connect_block(block);
+                    let expected = blockchain.ledger.state_root();
+                    if expected != found {
+                        return Err(ChainError::StateRootMismatch { height, expected, found });
+                    }
+                }
+            }
+            None => match genesis {
+                Some(genesis) => {
+                    blockchain.index_block(genesis.clone());
+                    blockchain.store_block(genesis)?;
+                }
+                None => blockchain.add_genesis_block()?,
+            },
+        }
+
+        // 잘못된 브랜치는 reorganize 가 block_index 에서 지우므로 반복은 끝난다.
+        while let Some(tip) = blockchain.heavier_tip() {
+            if let Err(BlockError::Storage(e)) = blockchain.reorganize(&tip) {
+                return Err(ChainError::Storage(e));
+            }
+        }
+        Ok(blockchain)
+    }
+
+    // 활성 체인보다 누적 작업량이 큰 블록 중 가장 무거운 것
+    fn heavier_tip(&self) -> Option<Hash256> {
+        let tip_work = self.tip_work();
+        self.block_index.iter()
+            .filter(|(_, node)| node.chain_work > tip_work)
+            .max_by_key(|(_, node)| node.chain_work)
+            .map(|(block_hash, _)| *block_hash)
+    }
+
+    fn empty(ledger: L) -> BlockChain<L> {
+        BlockChain {
+            chain: Vec::new(),
+            block_index: HashMap::new(),
+            ledger,
+            initial_bits: INITIAL_BITS,
+            block_time: BLOCK_TIME,
+            adjustment_interval: ADJUSTMENT_INTERVAL,
+            initial_subsidy: 50,
+            halving_interval: 210_000,
+            max_supply: 21_000_000,
+            store: None,
+        }
+    }
+
+    pub fn add_genesis_block(&mut self) -> io::Result<()> {
+        let mut genesis_block = Block::new(
+            Hash256::ZERO,
             0,
-        );
-        let genesis_block = Block::new(
-            "0".to_string(),
-            vec![root_tx],
-            self.difficulty
-        );
-        self.chain.push(genesis_block);
+            Vec::new(),
+            self.initial_bits
+        ).expect("Genesis block has no transactions to sign.");
+        genesis_block.header.state_root = self.ledger.state_root();
+        genesis_block.header.block_hash = genesis_block.header.calculate_hash();
+        self.index_block(genesis_block.clone());
+        self.store_block(genesis_block)
     }
 
-    pub fn add_block(&mut self, transactions: &[Transaction]) {
+    pub fn add_block(&mut self, transactions: &[Transaction]) -> Result<(), BlockError> {
+        let mut new_block = self.block_template(transactions)?;
+        new_block.mine_block()?;
+        self.accept_block(&new_block)?;
+
+        println!("New block added: {:?}", self.chain.last());
+        Ok(())
+    }
+
+    // 현재 팁 위에 올릴, 아직 채굴하지 않은 블록
+    pub fn block_template(&self, transactions: &[Transaction]) -> Result<Block, BlockError> {
         let previous_block = self.chain.last().unwrap();
-        let previous_hash = previous_block.header.calculate_hash();
 
         let mut new_block = Block::new(
-            previous_block.header.block_hash.clone(),
+            previous_block.header.block_hash,
+            self.chain.len() as u64,
             transactions.to_vec(),
-            self.difficulty
-        );
+            self.next_bits(&previous_block.header.block_hash)
+        ).map_err(|e| {
+            let i = transactions.iter().position(|tx| tx.signature.is_none() && !tx.is_coinbase()).unwrap_or(0);
+            BlockError::Transaction(i, e)
+        })?;
 
-        new_block.mine_block();
-        self.chain.push(new_block);
+        // 같은 밀리초 안에 블록이 이어 나오면 중앙값 규칙에 걸리므로 한 칸 밀어준다.
+        new_block.header.timestamp = new_block.header.timestamp.max(self.median_time_past() + 1);
+        new_block.header.state_root = self.ledger.state_root_after(transactions);
+        new_block.header.block_hash = new_block.header.calculate_hash();
+        Ok(new_block)
+    }
 
-        println!("New block added: {:?}", self.chain.last());
+    // 후보를 순서대로 하나씩 붙여 보면서 한 블록에 함께 넣을 수 있는 것만 limit 개까지 고른다.
+    // 앞 nonce 가 빠졌거나 앞서 고른 트랜잭션과 합쳐 잔액을 넘는 것은 건너뛴다.
+    pub fn mineable_transactions(&self, candidates: impl IntoIterator<Item = Transaction>, limit: usize) -> Vec<Transaction> {
+        let mut transactions = Vec::new();
+        for tx in candidates {
+            if transactions.len() >= limit {
+                break;
+            }
+            if receive_transaction(&tx).is_err() {
+                continue;
+            }
+            transactions.push(tx);
+            if self.ledger.check_transactions(&transactions).is_err() {
+                transactions.pop();
+            }
+        }
+        transactions
+    }
+
+    // 맨 앞에 보상 + 수수료를 miner_address 로 보내는 coinbase 를 붙인다.
+    pub fn with_coinbase(&self, miner_address: &str, transactions: &[Transaction]) -> Vec<Transaction> {
+        let fees = transactions.iter().fold(0u64, |sum, tx| sum.saturating_add(tx.fee));
+        let reward = self.block_subsidy(self.chain.len()).saturating_add(fees);
+
+        let mut block_transactions = vec![Transaction::new_coinbase(miner_address.to_string(), reward)];
+        block_transactions.extend_from_slice(transactions);
+        block_transactions
+    }
+
+    pub fn mine_next_block(&mut self, miner_address: &str, transactions: &[Transaction]) -> Result<(), BlockError> {
+        let block_transactions = self.with_coinbase(miner_address, transactions);
+        self.add_block(&block_transactions)
+    }
+
+    pub fn block_subsidy(&self, height: usize) -> u64 {
+        if height == 0 {
+            return 0;
+        }
+        let remaining = self.max_supply.saturating_sub(self.issued_before(height));
+        self.scheduled_subsidy(height as u64).min(remaining)
+    }
+
+    fn scheduled_subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 { 0 } else { self.initial_subsidy >> halvings }
+    }
+
+    // 높이 1 부터 height - 1 까지 일정대로 발행된 양 (max_supply 적용 전)
+    fn issued_before(&self, height: usize) -> u64 {
+        let height = height as u64;
+        let mut issued: u128 = 0;
+        let mut era = 0;
+        while era < 64 && era * self.halving_interval < height {
+            let start = (era * self.halving_interval).max(1);
+            let end = ((era + 1) * self.halving_interval).min(height);
+            if end > start {
+                issued += (end - start) as u128 * (self.initial_subsidy >> era) as u128;
+            }
+            era += 1;
+        }
+        issued.min(u64::MAX as u128) as u64
+    }
+
+    pub fn tip_hash(&self) -> Hash256 {
+        self.chain.last().unwrap().header.block_hash
+    }
+
+    pub fn tip_work(&self) -> U256 {
+        self.block_index[&self.tip_hash()].chain_work
+    }
+
+    pub fn is_active(&self, block_hash: &Hash256) -> bool {
+        self.block_index.get(block_hash).is_some_and(|node| {
+            self.chain.get(node.height).is_some_and(|block| block.header.block_hash == *block_hash)
+        })
+    }
+
+    pub fn get_block(&self, block_hash: &Hash256) -> Option<&Block> {
+        self.block_index.get(block_hash).map(|node| &node.block)
+    }
+
+    // 헤더만 가진 노드에게 줄 포함 증명. 헤더에는 루트만 있으므로 본문에서 트리를 다시 만든다.
+    pub fn prove_transaction(&self, block_hash: &Hash256, txid: &Hash256) -> Option<MerkleProof> {
+        let block = self.get_block(block_hash)?;
+        let index = block.transactions.iter().position(|tx| tx.calculate_hash().ok().as_ref() == Some(txid))?;
+        block.merkle_tree().ok()?.proof(index)
+    }
+
+    // 다른 노드에게 우리 체인이 어디까지인지 알려주는 해시들 (locator_heights 참고)
+    pub fn block_locator(&self) -> Vec<Hash256> {
+        locator_heights(self.chain.len() - 1).into_iter()
+            .map(|height| self.chain[height].header.block_hash)
+            .collect()
+    }
+
+    // locator 중 활성 체인에 있는 첫 해시 다음 블록부터 stop 까지(포함) 최대 max 개의 헤더.
+    // 아는 해시가 없으면 제네시스 다음부터 보낸다.
+    pub fn headers_after(&self, locator: &[Hash256], stop: &Hash256, max: usize) -> Vec<BlockHeader> {
+        let start = locator.iter()
+            .find(|block_hash| self.is_active(block_hash))
+            .map_or(0, |block_hash| self.block_index[block_hash].height);
+
+        let mut headers = Vec::new();
+        for block in self.chain.iter().skip(start + 1).take(max) {
+            headers.push(block.header.clone());
+            if block.header.block_hash == *stop {
+                break;
+            }
+        }
+        headers
+    }
+
+    // 검증을 통과한 블록을 블록 트리에 넣고, 더 무거운 브랜치가 생기면 그쪽으로 재구성한다.
+    pub fn accept_block(&mut self, block: &Block) -> Result<BlockStatus, BlockError> {
+        let block_hash = block.header.block_hash;
+        if self.block_index.contains_key(&block_hash) {
+            return Err(BlockError::AlreadyKnown);
+        }
+        self.check_block(block)?;
+
+        if block.header.previous_hash == self.tip_hash() {
+            self.check_transactions(&block.transactions)?;
+            self.check_state_root(block)?;
+            self.index_block(block.clone());
+            if let Err(e) = self.store_block(block.clone()) {
+                self.block_index.remove(&block_hash);
+                return Err(BlockError::Storage(e));
+            }
+            return Ok(BlockStatus::Connected);
+        }
+
+        // 사이드 브랜치 블록의 트랜잭션은 그 브랜치가 활성화될 때 검증한다.
+        // 다시 열었을 때 포크를 잃지 않도록 연결하지 않은 블록도 저장한다.
+        self.index_block(block.clone());
+        if let Some(store) = self.store.as_mut()
+            && let Err(e) = store.append(block, self.block_index[&block_hash].height as u64) {
+            self.block_index.remove(&block_hash);
+            return Err(BlockError::Storage(e));
+        }
+        if self.block_index[&block_hash].chain_work <= self.tip_work() {
+            return Ok(BlockStatus::SideBranch);
+        }
+        self.reorganize(&block_hash).map(BlockStatus::Reorganized)
+    }
+
+    // 부모를 기준으로 한 헤더 검증과 머클 루트 검증. 상태(잔액)는 보지 않는다.
+    pub fn check_block(&self, block: &Block) -> Result<(), BlockError> {
+        let header = &block.header;
+        let height = self.check_header(header)?;
+
+        if let Some(i) = block.transactions.iter().position(|tx| tx.signature.is_none() && !tx.is_coinbase()) {
+            return Err(BlockError::Transaction(i, TxError::Unsigned));
+        }
+        block.check_merkle_root()?;
+
+        self.check_coinbase(&block.transactions, height)
+    }
+
+    // coinbase 는 없어도 되지만, 있다면 맨 앞에 하나만 있고 보상 + 수수료를 넘지 않아야 한다.
+    fn check_coinbase(&self, transactions: &[Transaction], height: usize) -> Result<(), BlockError> {
+        if let Some(i) = transactions.iter().skip(1).position(|tx| tx.is_coinbase()) {
+            return Err(BlockError::Transaction(i + 1, TxError::MisplacedCoinbase));
+        }
+        let Some(coinbase) = transactions.first().filter(|tx| tx.is_coinbase()) else {
+            return Ok(());
+        };
+        if coinbase.fee != 0 {
+            return Err(BlockError::Transaction(0, TxError::InvalidCoinbase));
+        }
+
+        let fees = transactions[1..].iter().fold(0u64, |sum, tx| sum.saturating_add(tx.fee));
+        let allowed = self.block_subsidy(height).saturating_add(fees);
+        if coinbase.amount > allowed {
+            return Err(BlockError::CoinbaseOverpaid { claimed: coinbase.amount, allowed });
+        }
+        Ok(())
+    }
+
+    // 포크 지점까지 활성 체인을 되돌리고 new_tip 까지의 브랜치를 적용한다.
+    // 새 브랜치에 잘못된 블록이 있으면 원래 체인으로 복구하고 그 블록부터는 버린다.
+    pub fn reorganize(&mut self, new_tip: &Hash256) -> Result<Vec<Transaction>, BlockError> {
+        let branch = self.branch_from_fork(new_tip);
+        let fork_height = self.block_index[&branch[0]].height - 1;
+
+        let mut disconnected = Vec::new();
+        while self.chain.len() > fork_height + 1 {
+            disconnected.push(self.disconnect_tip());
+        }
+        disconnected.reverse();
+
+        for (i, block_hash) in branch.iter().enumerate() {
+            let block = self.block_index[block_hash].block.clone();
+            let result = self.check_transactions(&block.transactions)
+                .and_then(|_| self.check_state_root(&block))
+                .and_then(|_| self.store_block(block).map_err(BlockError::Storage));
+
+            if let Err(e) = result {
+                while self.chain.len() > fork_height + 1 {
+                    self.disconnect_tip();
+                }
+                for block in disconnected {
+                    self.connect_block(block);
+                }
+                if !matches!(e, BlockError::Storage(_)) {
+                    for invalid_hash in &branch[i..] {
+                        self.block_index.remove(invalid_hash);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        let returned = disconnected.into_iter()
+            .flat_map(|block| block.transactions)
+            .filter(|tx| !tx.is_coinbase())
+            .filter(|tx| self.ledger.is_pending(tx))
+            .collect();
+        Ok(returned)
+    }
+
+    // 활성 체인과 만나는 지점 다음 블록부터 block_hash 까지의 해시 (오래된 순)
+    fn branch_from_fork(&self, block_hash: &Hash256) -> Vec<Hash256> {
+        let mut branch = Vec::new();
+        let mut current = block_hash;
+        while let Some(node) = self.block_index.get(current) {
+            if self.is_active(current) {
+                break;
+            }
+            branch.push(*current);
+            current = &node.block.header.previous_hash;
+        }
+        branch.reverse();
+        branch
+    }
+
+    fn index_block(&mut self, block: Block) {
+        let (height, parent_work) = match self.block_index.get(&block.header.previous_hash) {
+            Some(parent) => (parent.height + 1, parent.chain_work),
+            None => (0, U256::ZERO),
+        };
+        let chain_work = parent_work.saturating_add(block.header.work());
+        self.block_index.insert(block.header.block_hash, BlockNode { block, height, chain_work, undo: None });
+    }
+
+    // 서명을 확인한 뒤 상태 규칙(잔액, nonce, 입력)은 ledger 에 맡긴다.
+    // coinbase 규칙은 check_coinbase 에서 이미 봤다.
+    fn check_transactions(&self, transactions: &[Transaction]) -> Result<(), BlockError> {
+        for (i, tx) in transactions.iter().enumerate() {
+            if !(i == 0 && tx.is_coinbase()) {
+                receive_transaction(tx).map_err(|e| BlockError::Transaction(i, e))?;
+            }
+        }
+        self.ledger.check_transactions(transactions)
+    }
 
-        if self.chain.len() % self.adjustment_interval == 0 {
-            self.adjust_difficulty();
+    // 블록을 현재 상태에 적용한 결과가 헤더의 state_root 와 같아야 한다.
+    fn check_state_root(&self, block: &Block) -> Result<(), BlockError> {
+        let state_root = self.ledger.state_root_after(&block.transactions);
+        if block.header.state_root != state_root {
+            return Err(BlockError::StateRootMismatch { expected: state_root, found: block.header.state_root });
         }
+        Ok(())
+    }
+
+    pub fn median_time_past(&self) -> u128 {
+        self.median_time_past_of(&self.tip_hash())
+    }
+
+    // 디스크에 먼저 기록(fsync)한 뒤에 메모리 상태를 바꾼다. 블록은 block_index 에 먼저 들어가 있어야 한다.
+    // undo 기록은 적용한 뒤에야 나오므로 나중에 쓴다. 기록 전에 죽더라도 다시 열 때 재적용하면서 만들어진다.
+    fn store_block(&mut self, block: Block) -> io::Result<()> {
+        let block_hash = block.header.block_hash;
+        if let Some(store) = self.store.as_mut() && !store.contains(&block_hash) {
+            let height = self.block_index[&block_hash].height;
+            store.append(&block, height as u64)?;
+        }
+        self.connect_block(block);
+
+        if let Some(store) = self.store.as_mut() && !store.has_undo(&block_hash) {
+            let undo = self.block_index[&block_hash].undo.as_ref().expect("Connected block has undo data.");
+            // undo 가 없으면 다시 열 때 연결할 수 없는 블록이므로 메모리 상태도 되돌린다.
+            if let Err(e) = store.append_undo(&block_hash, undo) {
+                self.disconnect_tip();
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn connect_block(&mut self, block: Block) {
+        let undo = self.ledger.apply_block(&block);
+        if let Some(node) = self.block_index.get_mut(&block.header.block_hash) {
+            node.undo = Some(undo);
+        }
+        self.chain.push(block);
+    }
+
+    fn disconnect_tip(&mut self) -> Block {
+        let block = self.chain.pop().expect("Cannot disconnect an empty chain.");
+        let undo = self.block_index[&block.header.block_hash].undo.clone()
+            .expect("Connected block has undo data.");
+        self.ledger.revert_block(&block, &undo);
+        block
     }
 
     pub fn is_chain_valid(&self) -> bool {
+        match self.verify_chain() {
+            Ok(()) => true,
+            Err(e) => {
+                println!("Blockchain is invalid: {}", e);
+                false
+            }
+        }
+    }
+
+    pub fn verify_chain(&self) -> Result<(), ChainError> {
         for i in 1..self.chain.len() {
             let cur_block = &self.chain[i];
             let prev_block = &self.chain[i-1];
 
             if cur_block.header.previous_hash != prev_block.header.block_hash {
-                println!("Blockchain is invalid at block {}.", i);
-                return false;
+                return Err(ChainError::BrokenLink(i));
             }
 
             if cur_block.header.block_hash != cur_block.header.calculate_hash() {
-                println!("Blockchain hash is invalid at block {}.",i);
-                return false;
+                return Err(ChainError::InvalidHash(i));
             }
         }
-        println!("Blockchain is valid.");
-        true
+        Ok(())
     }
 
-    pub fn validate_transaction(&mut self, transaction: &Transaction, public_key: &VerifyingKey) -> bool {
+    // mempool 에 넣기 전 확인
+    pub fn validate_transaction(&self, transaction: &Transaction) -> Result<(), TxError> {
+        receive_transaction(transaction)?;
+        self.ledger.validate_transaction(transaction)
+    }
+}
 
-        if receive_transaction(transaction).is_err() {
-            println!("invalid signature.");
-            return false;
-        }
+impl<L: Ledger> HeaderIndex for BlockChain<L> {
+    fn lookup(&self, block_hash: &Hash256) -> Option<(&BlockHeader, usize)> {
+        self.block_index.get(block_hash).map(|node| (&node.block.header, node.height))
+    }
 
-        if let Some(sender_balance) = self.accounts.get(&transaction.sender) {
-            if sender_balance < &transaction.amount {
-                println!("Insufficient funds.");
-                return false;
-            }
-        } else {
-            println!("Sender account not found.");
-            return false;
+    fn initial_bits(&self) -> u32 {
+        self.initial_bits
+    }
+
+    fn block_time(&self) -> u128 {
+        self.block_time
+    }
+
+    fn adjustment_interval(&self) -> usize {
+        self.adjustment_interval
+    }
+}
+
+// 팁에서 제네시스까지 locator 에 넣을 높이. 처음 10개는 하나씩, 그 뒤로는 간격을 두 배씩 늘리고 제네시스로 끝난다.
+// 포크가 있어도 상대는 이 중 자기가 아는 가장 높은 블록부터 이어서 보내주면 된다.
+pub fn locator_heights(tip_height: usize) -> Vec<usize> {
+    let mut heights = Vec::new();
+    let mut height = tip_height;
+    let mut step = 1;
+    while height > 0 {
+        heights.push(height);
+        if heights.len() >= 10 {
+            step *= 2;
         }
+        height = height.saturating_sub(step);
+    }
+    heights.push(0);
+    heights
+}
 
-        let transaction_hash = transaction.calculate_hash().expect("The transaction is not signed.");
+// 헤더만 가지고 볼 수 있는 합의 규칙. 전체 노드(BlockChain)와 light_client::HeaderChain 이 같이 쓴다.
+pub trait HeaderIndex {
+    // 해시로 찾은 헤더와 그 높이
+    fn lookup(&self, block_hash: &Hash256) -> Option<(&BlockHeader, usize)>;
 
-        if self.transaction_history.contains(&transaction_hash) {
-            println!("Duplicate transaction.");
-            return false;
+    fn initial_bits(&self) -> u32;
+
+    fn block_time(&self) -> u128;
+
+    fn adjustment_interval(&self) -> usize;
+
+    // 부모를 기준으로 링크, PoW, 버전, 높이, 난이도, 타임스탬프를 확인하고 헤더의 높이를 돌려준다.
+    fn check_header(&self, header: &BlockHeader) -> Result<usize, BlockError> {
+        let Some((_, parent_height)) = self.lookup(&header.previous_hash) else {
+            return Err(BlockError::UnknownParent);
+        };
+        if header.block_hash != header.calculate_hash() {
+            return Err(BlockError::InvalidHash);
+        }
+        if !header.meets_target() {
+            return Err(BlockError::InsufficientWork);
+        }
+        if header.version < BLOCK_VERSION {
+            return Err(BlockError::UnsupportedVersion(header.version));
         }
 
-        self.transaction_history.insert(transaction_hash);
-        println!("Transaction is valid.");
-        true
-    }
+        // 높이와 난이도는 부모로부터 정해진다.
+        let height = parent_height + 1;
+        if header.height != height as u64 {
+            return Err(BlockError::InvalidHeight { expected: height as u64, found: header.height });
+        }
+        let bits = self.next_bits(&header.previous_hash);
+        if header.bits != bits {
+            return Err(BlockError::InvalidBits { expected: bits, found: header.bits });
+        }
 
-    pub fn adjust_difficulty(&mut self) {
-        let expected_time = self.block_time;
-        let actual_time = self.chain.last().unwrap().header.timestamp - self.chain[self.chain.len() - self.adjustment_interval].header.timestamp;
+        if header.timestamp <= self.median_time_past_of(&header.previous_hash) {
+            return Err(BlockError::TimestampTooOld);
+        }
+        if header.timestamp > current_timestamp() + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockError::TimestampTooNew);
+        }
+        Ok(height)
+    }
 
-        if actual_time < expected_time {
-            self.difficulty += 1;
-        } else if actual_time > expected_time {
-            self.difficulty -= 1;
+    // parent 위에 올라갈 블록이 가져야 하는 bits.
+    // adjustment_interval 의 배수 높이에서 직전 adjustment_interval 개 블록 사이의 시간을 기대값과 비교해서 목표값을 비례 조정한다.
+    fn next_bits(&self, parent_hash: &Hash256) -> u32 {
+        let (parent, parent_height) = self.lookup(parent_hash).expect("Parent must be indexed.");
+        let height = parent_height + 1;
+        if height == 1 {
+            return self.initial_bits();
+        }
+        let interval = self.adjustment_interval();
+        if !height.is_multiple_of(interval) {
+            return parent.bits;
         }
 
-        println!("Adjusted difficulty: {}", self.difficulty);
+        let first = self.ancestor(parent_hash, height - interval);
+        let actual_timespan = parent.timestamp.saturating_sub(first.timestamp);
+        let expected_timespan = self.block_time() * (interval as u128 - 1);
+        pow::retarget(parent.bits, actual_timespan, expected_timespan)
     }
 
-    pub fn apply_block(&mut self, block: &Block) {
-        for tx in block.transactions.iter() {
-            if let Some(sender_balance) = self.accounts.get_mut(&tx.sender) {
-                *sender_balance -= tx.amount;
-            }
-            if let Some(receiver_balance) = self.accounts.get_mut(&tx.receiver) {
-                *receiver_balance += tx.amount;
+    // block_hash 와 그 조상들 최근 MEDIAN_TIME_SPAN 개의 타임스탬프 중앙값
+    fn median_time_past_of(&self, block_hash: &Hash256) -> u128 {
+        let mut timestamps = Vec::new();
+        let mut current = block_hash;
+        while let Some((header, _)) = self.lookup(current) {
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
             }
+            timestamps.push(header.timestamp);
+            current = &header.previous_hash;
+        }
+        timestamps.sort();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    // block_hash 의 조상 중 height 높이에 있는 헤더. 사이드 브랜치에서도 쓸 수 있다.
+    fn ancestor(&self, block_hash: &Hash256, height: usize) -> &BlockHeader {
+        let (mut header, mut current) = self.lookup(block_hash).expect("Block must be indexed.");
+        while current > height {
+            (header, current) = self.lookup(&header.previous_hash).expect("Ancestors must be indexed.");
         }
-        println!("Blockchain state updated.");
+        header
     }
 }
 
-// 외부에서 signature가 정확한지 확인.
-pub fn receive_transaction(transaction: &Transaction) -> Result<(), p256::ecdsa::Error> {
+// 외부에서 signature가 정확한지, sender 가 서명한 키의 주소인지 확인.
+pub fn receive_transaction(transaction: &Transaction) -> Result<(), TxError> {
     let transaction_hash = transaction.calculate_hash_sign();
 
-    let signature_bytes = hex::decode(
-        transaction.signature.as_ref().expect("The transaction is not signed")
-    ).expect("Invalid signature format.");
-
-    let signature = Signature::from_slice(&signature_bytes).unwrap();
-    transaction.public_key.expect("The transaction is not signed")
-        .verify(transaction_hash.as_bytes(), &signature)
+    let signature = transaction.signature.ok_or(TxError::Unsigned)?;
+    let public_key = transaction.public_key.ok_or(TxError::MissingPublicKey)?;
+    if transaction.sender != address_from_public_key(&public_key) {
+        return Err(TxError::SenderMismatch);
+    }
+    public_key.verify(transaction_hash.as_bytes(), &signature)
+        .map_err(|_| TxError::InvalidSignature)
 }
 
 
 #[cfg(test)]
 mod test {
 
-    use crate::wallet::Wallet;
+    use crate::{ledger::{self, UtxoLedger}, pow::POW_LIMIT_BITS, state_tree, transaction::TxOutput, wallet::Wallet};
 
     use super::*;
     use p256::elliptic_curve::rand_core::OsRng;
@@ -235,36 +829,471 @@ mod test {
     #[test]
     fn test_mine_block() {
         let mut blockchain = BlockChain::new();
-
-        // 트랜잭션 생성
-        let tx1 = Transaction {
-            sender: "Alice".to_string(),
-            receiver: "Bob".to_string(),
-            amount: 50,
-            fee: 0,
-            signature: None,
-            public_key: None,
-            timestamp: current_timestamp(),
-        };
-    
-        let txs = vec![tx1];
+        let wallet = Wallet::new();
+        blockchain.ledger.set_balance(&wallet.generate_address(), 1000);
     
         // 20개의 블록을 추가하며 난이도 조정 테스트
-        for _ in 0..20 {
-            blockchain.add_block(&txs.clone());
+        for i in 0..20 {
+            // 트랜잭션 생성
+            let mut tx1 = Transaction {
+                sender: wallet.generate_address(),
+                receiver: "Bob".to_string(),
+                amount: 1 + i,
+                fee: 0,
+                nonce: i,
+                inputs: Vec::new(),
+                outputs: Vec::new(),
+                signature: None,
+                public_key: None,
+                timestamp: current_timestamp(),
+            };
+            wallet.sign_transaction(&mut tx1).unwrap();
+
+            blockchain.add_block(&[tx1]).unwrap();
         }
+        assert_eq!(blockchain.ledger.accounts()["Bob"], (1..=20).sum::<u64>());
+        // 기대 시간보다 훨씬 빨리 채굴했으니 두 번의 조정 모두 목표값을 최대폭(1/4)으로 줄인다.
+        let initial_target = pow::target_from_bits(0x1f10_0000).unwrap();
+        assert_eq!(pow::target_from_bits(blockchain.next_bits(&blockchain.tip_hash())), Some(initial_target >> 4));
         // 블록체인 상태 확인
         for (i, block) in blockchain.chain.iter().enumerate() {
             println!("Block {}: {:?}", i, block);
         }
     }    
 
+    #[test]
+    fn test_add_block_reports_unminable_bits() {
+        let mut blockchain = BlockChain::new();
+        // pow_limit 보다 쉬운 목표값
+        blockchain.initial_bits = 0x2100_ffff;
+        assert!(matches!(blockchain.add_block(&[]), Err(BlockError::UnminableBits(0x2100_ffff))));
+        assert_eq!(blockchain.chain.len(), 1);
+    }
+
+    fn signed_transaction(wallet: &Wallet, receiver: &str, amount: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction::new(wallet.generate_address(), receiver.to_string(), amount, 0, nonce);
+        wallet.sign_transaction(&mut tx).unwrap();
+        tx
+    }
+
+    fn mined_block<L: Ledger>(blockchain: &BlockChain<L>, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::new(
+            blockchain.tip_hash(),
+            blockchain.chain.len() as u64,
+            transactions,
+            blockchain.next_bits(&blockchain.tip_hash()),
+        ).unwrap();
+        block.header.timestamp = blockchain.median_time_past() + 1;
+        block.header.state_root = blockchain.ledger.state_root_after(&block.transactions);
+        block.mine_block().unwrap();
+        block
+    }
+
+    #[test]
+    fn test_accept_block() {
+        let mut blockchain = BlockChain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let wallet = Wallet::new();
+        blockchain.ledger.set_balance(&wallet.generate_address(), 100);
+
+        let tx = signed_transaction(&wallet, "B", 60, 0);
+
+        // 채굴 후 본문을 바꾸면 머클 루트가 맞지 않는다.
+        let mut tampered = mined_block(&blockchain, vec![tx.clone()]);
+        tampered.transactions[0] = signed_transaction(&wallet, "C", 60, 0);
+        assert!(matches!(blockchain.accept_block(&tampered), Err(BlockError::MerkleRootMismatch)));
+
+        // 같은 트랜잭션을 두 번 넣은 블록
+        let mut duplicated = mined_block(&blockchain, vec![tx.clone()]);
+        duplicated.transactions.push(tx.clone());
+        assert!(matches!(blockchain.accept_block(&duplicated), Err(BlockError::Transaction(1, TxError::Duplicate))));
+
+        let unmined = Block::new(blockchain.tip_hash(), 1, vec![tx.clone()], 0x0300_0001).unwrap();
+        assert!(matches!(blockchain.accept_block(&unmined), Err(BlockError::InsufficientWork)));
+
+        let mut stale = mined_block(&blockchain, vec![tx.clone()]);
+        stale.header.timestamp = 0;
+        stale.mine_block().unwrap();
+        assert!(matches!(blockchain.accept_block(&stale), Err(BlockError::TimestampTooOld)));
+
+        let block = mined_block(&blockchain, vec![tx.clone()]);
+        blockchain.accept_block(&block).unwrap();
+        assert_eq!(blockchain.ledger.accounts()[&wallet.generate_address()], 40);
+        assert_eq!(blockchain.ledger.accounts()["B"], 60);
+
+        // 같은 트랜잭션을 다시 넣으면 nonce 가 맞지 않는다.
+        assert_eq!(blockchain.validate_transaction(&tx), Err(TxError::InvalidNonce { expected: 1, found: 0 }));
+        let replay = mined_block(&blockchain, vec![tx]);
+        assert!(matches!(blockchain.accept_block(&replay), Err(BlockError::Transaction(0, TxError::InvalidNonce { expected: 1, found: 0 }))));
+        assert_eq!(blockchain.chain.len(), 2);
+
+        // 미래 nonce 는 MAX_NONCE_GAP 까지만 받는다.
+        blockchain.validate_transaction(&signed_transaction(&wallet, "B", 10, 1 + ledger::MAX_NONCE_GAP)).unwrap();
+        let far = 2 + ledger::MAX_NONCE_GAP;
+        assert_eq!(blockchain.validate_transaction(&signed_transaction(&wallet, "B", 10, far)), Err(TxError::InvalidNonce { expected: 1, found: far }));
+    }
+
+    #[test]
+    fn test_accept_block_checks_balances_in_order() {
+        let mut blockchain = BlockChain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let wallet = Wallet::new();
+        blockchain.ledger.set_balance(&wallet.generate_address(), 100);
+
+        // 하나씩은 괜찮지만 합치면 잔액 초과
+        let block = mined_block(&blockchain, vec![
+            signed_transaction(&wallet, "B", 60, 0),
+            signed_transaction(&wallet, "C", 60, 1),
+        ]);
+        assert!(matches!(blockchain.accept_block(&block), Err(BlockError::Transaction(1, TxError::InsufficientFunds))));
+
+        let block = mined_block(&blockchain, vec![signed_transaction(&Wallet::new(), "B", 1, 0)]);
+        assert!(matches!(blockchain.accept_block(&block), Err(BlockError::Transaction(0, TxError::UnknownSender))));
+
+        assert_eq!(blockchain.ledger.accounts()[&wallet.generate_address()], 100);
+    }
+
+    // state 는 parent 를 적용한 뒤의 상태
+    fn mined_block_on(parent: &Block, state: &impl Ledger, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::new(parent.header.block_hash, parent.header.height + 1, transactions, POW_LIMIT_BITS).unwrap();
+        block.header.timestamp = current_timestamp().max(parent.header.timestamp + 1);
+        block.header.state_root = state.state_root_after(&block.transactions);
+        block.mine_block().unwrap();
+        block
+    }
+
+    #[test]
+    fn test_reorganize_to_heavier_branch() {
+        let mut blockchain = BlockChain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let wallet = Wallet::new();
+        let other = Wallet::new();
+        blockchain.ledger.set_balance(&wallet.generate_address(), 100);
+        blockchain.ledger.set_balance(&other.generate_address(), 100);
+        let genesis = blockchain.chain[0].clone();
+
+        let tx_a = signed_transaction(&wallet, "B", 30, 0);
+        let tx_b = signed_transaction(&other, "C", 10, 0);
+
+        let state = blockchain.ledger.clone();
+        let a1 = mined_block_on(&genesis, &state, vec![tx_a.clone()]);
+        assert!(matches!(blockchain.accept_block(&a1), Ok(BlockStatus::Connected)));
+        assert!(matches!(blockchain.accept_block(&a1), Err(BlockError::AlreadyKnown)));
+
+        // 작업량이 같으면 먼저 받은 체인을 유지
+        let b1 = mined_block_on(&genesis, &state, vec![tx_b.clone()]);
+        assert!(matches!(blockchain.accept_block(&b1), Ok(BlockStatus::SideBranch)));
+        assert_eq!(blockchain.tip_hash(), a1.header.block_hash);
+
+        let mut b_state = state.clone();
+        b_state.apply_block(&b1);
+        let b2 = mined_block_on(&b1, &b_state, Vec::new());
+        let returned = match blockchain.accept_block(&b2) {
+            Ok(BlockStatus::Reorganized(transactions)) => transactions,
+            other => panic!("expected reorganization, got {:?}", other),
+        };
+
+        assert_eq!(blockchain.tip_hash(), b2.header.block_hash);
+        assert_eq!(blockchain.chain.len(), 3);
+        assert_eq!(returned.len(), 1);
+        assert_eq!(returned[0].calculate_hash().unwrap(), tx_a.calculate_hash().unwrap());
+        assert_eq!(blockchain.ledger.accounts()[&wallet.generate_address()], 100);
+        assert_eq!(blockchain.ledger.accounts()[&other.generate_address()], 90);
+        assert_eq!(blockchain.ledger.accounts()["C"], 10);
+        assert!(!blockchain.ledger.accounts().contains_key("B"));
+        assert_eq!(blockchain.ledger.nonce_of(&wallet.generate_address()), 0);
+    }
+
+    #[test]
+    fn test_reorganize_rejects_invalid_branch() {
+        let mut blockchain = BlockChain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let wallet = Wallet::new();
+        blockchain.ledger.set_balance(&wallet.generate_address(), 100);
+        let genesis = blockchain.chain[0].clone();
+
+        let state = blockchain.ledger.clone();
+        let a1 = mined_block_on(&genesis, &state, vec![signed_transaction(&wallet, "B", 30, 0)]);
+        blockchain.accept_block(&a1).unwrap();
+
+        // 더 무겁지만 잔액을 넘겨 쓰는 브랜치
+        let b1 = mined_block_on(&genesis, &state, vec![signed_transaction(&wallet, "C", 500, 0)]);
+        let mut b_state = state.clone();
+        b_state.apply_block(&b1);
+        let b2 = mined_block_on(&b1, &b_state, Vec::new());
+        blockchain.accept_block(&b1).unwrap();
+        assert!(matches!(blockchain.accept_block(&b2), Err(BlockError::Transaction(0, TxError::InsufficientFunds))));
+
+        assert_eq!(blockchain.tip_hash(), a1.header.block_hash);
+        assert_eq!(blockchain.ledger.accounts()[&wallet.generate_address()], 70);
+        assert_eq!(blockchain.ledger.accounts()["B"], 30);
+        assert!(!blockchain.block_index.contains_key(&b1.header.block_hash));
+    }
+
+    #[test]
+    fn test_block_subsidy_schedule() {
+        let mut blockchain = BlockChain::new();
+        blockchain.initial_subsidy = 8;
+        blockchain.halving_interval = 2;
+        blockchain.max_supply = 20;
+
+        let subsidies: Vec<u64> = (0..8).map(|height| blockchain.block_subsidy(height)).collect();
+        assert_eq!(subsidies, vec![0, 8, 4, 4, 2, 2, 0, 0]);
+        assert_eq!(subsidies.iter().sum::<u64>(), blockchain.max_supply);
+
+        blockchain.max_supply = u64::MAX;
+        assert_eq!(blockchain.block_subsidy(6), 1);
+        assert_eq!(blockchain.block_subsidy(200), 0);
+    }
+
+    #[test]
+    fn test_coinbase_pays_miner() {
+        let mut blockchain = BlockChain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let miner = Wallet::new();
+
+        // 잔액이 없으니 처음에는 보낼 수 없다.
+        let tx = signed_transaction(&miner, "B", 20, 0);
+        assert_eq!(blockchain.validate_transaction(&tx), Err(TxError::UnknownSender));
+
+        blockchain.mine_next_block(&miner.generate_address(), &[]).unwrap();
+        assert_eq!(blockchain.ledger.accounts()[&miner.generate_address()], blockchain.initial_subsidy);
+
+        let mut tx = Transaction::new(miner.generate_address(), "B".to_string(), 20, 5, 0);
+        miner.sign_transaction(&mut tx).unwrap();
+        blockchain.mine_next_block("M", &[tx]).unwrap();
+        assert_eq!(blockchain.ledger.accounts()[&miner.generate_address()], blockchain.initial_subsidy - 25);
+        assert_eq!(blockchain.ledger.accounts()["B"], 20);
+        assert_eq!(blockchain.ledger.accounts()["M"], blockchain.initial_subsidy + 5);
+
+        // 보상 + 수수료보다 많이 가져가는 coinbase
+        let overpaid = mined_block(&blockchain, vec![Transaction::new_coinbase("M".to_string(), blockchain.initial_subsidy + 1)]);
+        assert!(matches!(blockchain.accept_block(&overpaid), Err(BlockError::CoinbaseOverpaid { allowed: 50, .. })));
+
+        let misplaced = mined_block(&blockchain, vec![
+            signed_transaction(&miner, "C", 1, 1),
+            Transaction::new_coinbase("M".to_string(), 1),
+        ]);
+        assert!(matches!(blockchain.accept_block(&misplaced), Err(BlockError::Transaction(1, TxError::MisplacedCoinbase))));
+    }
+
+    #[test]
+    fn test_utxo_chain() {
+        let mut blockchain = BlockChain::with_ledger(UtxoLedger::default());
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let miner = Wallet::new();
+
+        blockchain.mine_next_block(&miner.generate_address(), &[]).unwrap();
+        let (reward, output) = blockchain.ledger.unspent_outputs_of(&miner.generate_address()).pop().unwrap();
+        assert_eq!(output.amount, blockchain.initial_subsidy);
+
+        let outputs = vec![
+            TxOutput { address: "B".to_string(), amount: 30 },
+            TxOutput { address: miner.generate_address(), amount: 18 },
+        ];
+        let mut spend = Transaction::new_spend(miner.generate_address(), vec![reward], outputs, 2);
+        miner.sign_transaction(&mut spend).unwrap();
+        blockchain.validate_transaction(&spend).unwrap();
+        blockchain.mine_next_block("M", &[spend.clone()]).unwrap();
+
+        assert_eq!(blockchain.ledger.balance_of("B"), 30);
+        assert_eq!(blockchain.ledger.balance_of(&miner.generate_address()), 18);
+        assert_eq!(blockchain.ledger.balance_of("M"), blockchain.initial_subsidy + 2);
+
+        // 쓴 출력은 상태 트리에서 빠진다.
+        let state_root = blockchain.chain.last().unwrap().header.state_root;
+        let spent = &spend.inputs[0];
+        assert!(state_tree::verify(&state_root, &UtxoLedger::output_key(spent), None, &blockchain.ledger.prove_output(spent)));
+
+        // 다음 블록에서 같은 출력을 다시 쓴다.
+        let replay = mined_block(&blockchain, vec![spend]);
+        assert!(matches!(blockchain.accept_block(&replay), Err(BlockError::Transaction(0, TxError::MissingOrSpentInput))));
+    }
+
+    #[test]
+    fn test_fee_is_signed_and_charged() {
+        let mut blockchain = BlockChain::new();
+        let wallet = Wallet::new();
+        blockchain.ledger.set_balance(&wallet.generate_address(), 100);
+
+        // 서명 후 수수료를 바꾸면 서명이 맞지 않는다.
+        let mut tx = Transaction::new(wallet.generate_address(), "B".to_string(), 90, 5, 0);
+        wallet.sign_transaction(&mut tx).unwrap();
+        let mut tampered = tx.clone();
+        tampered.fee = 0;
+        assert_eq!(receive_transaction(&tampered), Err(TxError::InvalidSignature));
+
+        // amount + fee 가 잔액을 넘으면 거부
+        let mut expensive = Transaction::new(wallet.generate_address(), "B".to_string(), 90, 11, 0);
+        wallet.sign_transaction(&mut expensive).unwrap();
+        assert_eq!(blockchain.validate_transaction(&expensive), Err(TxError::InsufficientFunds));
+        let block = mined_block(&blockchain, vec![expensive]);
+        assert!(matches!(blockchain.accept_block(&block), Err(BlockError::Transaction(0, TxError::InsufficientFunds))));
+
+        let block = mined_block(&blockchain, vec![tx]);
+        blockchain.accept_block(&block).unwrap();
+        assert_eq!(blockchain.ledger.accounts()[&wallet.generate_address()], 5);
+        assert_eq!(blockchain.ledger.accounts()["B"], 90);
+    }
+
     fn test_receive_transaction() {
         let wallet = Wallet::new();
-        let mut transaction = Transaction::new(String::from("A"), String::from("B"), 1000,1);
-        wallet.sign_transaction(&mut transaction);
+        let mut transaction = Transaction::new(wallet.generate_address(), String::from("B"), 1000, 1, 0);
+        wallet.sign_transaction(&mut transaction).unwrap();
 
         assert!(receive_transaction(&transaction).is_ok());
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_reject_forged_sender() {
+        let mut blockchain = BlockChain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let victim = Wallet::new();
+        let attacker = Wallet::new();
+        blockchain.ledger.set_balance(&victim.generate_address(), 100);
+
+        // 남의 주소를 sender 로 쓰고 자기 키로 서명
+        let mut forged = Transaction::new(victim.generate_address(), "M".to_string(), 100, 0, 0);
+        attacker.sign_transaction(&mut forged).unwrap();
+        assert_eq!(receive_transaction(&forged), Err(TxError::SenderMismatch));
+        assert_eq!(blockchain.validate_transaction(&forged), Err(TxError::SenderMismatch));
+
+        let block = mined_block(&blockchain, vec![forged.clone()]);
+        assert!(matches!(blockchain.accept_block(&block), Err(BlockError::Transaction(0, TxError::SenderMismatch))));
+
+        // 키만 피해자 것으로 바꾸면 서명이 맞지 않는다.
+        forged.public_key = Some(victim.public_key);
+        assert_eq!(receive_transaction(&forged), Err(TxError::InvalidSignature));
+        assert_eq!(blockchain.ledger.accounts()[&victim.generate_address()], 100);
+    }
+
+    #[test]
+    fn test_reject_malformed_transaction() {
+        let mut blockchain = BlockChain::new();
+        let wallet = Wallet::new();
+        blockchain.ledger.set_balance(&wallet.generate_address(), 100);
+
+        let mut transaction = Transaction::new(wallet.generate_address(), String::from("B"), 10, 1, 0);
+        assert_eq!(receive_transaction(&transaction), Err(TxError::Unsigned));
+        assert!(matches!(blockchain.add_block(&[transaction.clone()]), Err(BlockError::Transaction(0, TxError::Unsigned))));
+
+        wallet.sign_transaction(&mut transaction).unwrap();
+        transaction.amount = 20;
+        assert_eq!(blockchain.validate_transaction(&transaction), Err(TxError::InvalidSignature));
+
+        transaction.public_key = None;
+        assert_eq!(receive_transaction(&transaction), Err(TxError::MissingPublicKey));
+    }
+
+    #[test]
+    fn test_header_commits_to_difficulty() {
+        let mut blockchain = BlockChain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        blockchain.adjustment_interval = 3;
+        let genesis = blockchain.chain[0].clone();
+
+        // bits, 높이, 버전을 바꾸면 해시가 달라진다.
+        let block = mined_block(&blockchain, Vec::new());
+        let tampers: [fn(&mut BlockHeader); 3] = [|header| header.bits = 0x2000_ffff, |header| header.height += 1, |header| header.version = 0];
+        for tamper in tampers {
+            let mut tampered = block.clone();
+            tamper(&mut tampered.header);
+            assert!(matches!(blockchain.accept_block(&tampered), Err(BlockError::InvalidHash)));
+
+            // 다시 채굴해서 해시를 맞춰도 규칙에 맞지 않으면 거부한다. pow_limit 보다 쉬운 bits 는 채굴부터 안 된다.
+            if tampered.mine_block().is_err() {
+                assert_eq!(tampered.header.bits, 0x2000_ffff);
+            }
+            assert!(matches!(blockchain.accept_block(&tampered),
+                Err(BlockError::InvalidBits { .. } | BlockError::InvalidHeight { expected: 1, found: 2 } | BlockError::UnsupportedVersion(0))));
+        }
+
+        blockchain.accept_block(&block).unwrap();
+        blockchain.accept_block(&mined_block(&blockchain, Vec::new())).unwrap();
+
+        // 높이 3 에서 조정된다. 기대보다 빨리 나왔으니 더 어려워진다.
+        let bits = blockchain.next_bits(&blockchain.tip_hash());
+        assert!(pow::work_from_bits(bits) > pow::work_from_bits(POW_LIMIT_BITS));
+        let easy = mined_block_on(blockchain.chain.last().unwrap(), &blockchain.ledger, Vec::new());
+        assert!(matches!(blockchain.accept_block(&easy), Err(BlockError::InvalidBits { expected, found: POW_LIMIT_BITS }) if expected == bits));
+        let block = mined_block(&blockchain, Vec::new());
+        assert_eq!(block.header.bits, bits);
+        blockchain.accept_block(&block).unwrap();
+
+        // 사이드 브랜치도 자기 조상들의 시간으로 난이도를 계산한다.
+        // 같은 밀리초에 만들면 높이 1 의 활성 블록과 똑같아질 수 있어서 nonce 를 옮긴다.
+        let mut s1 = mined_block_on(&genesis, &blockchain.ledger, Vec::new());
+        s1.header.nonce += 1;
+        s1.mine_block().unwrap();
+        let s2 = mined_block_on(&s1, &blockchain.ledger, Vec::new());
+        let s3 = mined_block_on(&s2, &blockchain.ledger, Vec::new());
+        assert!(matches!(blockchain.accept_block(&s1), Ok(BlockStatus::SideBranch)));
+        assert!(matches!(blockchain.accept_block(&s2), Ok(BlockStatus::SideBranch)));
+        assert!(matches!(blockchain.accept_block(&s3), Err(BlockError::InvalidBits { .. })));
+    }
+
+    #[test]
+    fn test_header_commits_to_state() {
+        let mut blockchain = BlockChain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let wallet = Wallet::new();
+        blockchain.ledger.set_balance(&wallet.generate_address(), 100);
+
+        // 다른 상태 루트를 적고 채굴한 블록
+        let tx = signed_transaction(&wallet, "B", 60, 0);
+        let mut wrong = mined_block(&blockchain, vec![tx.clone()]);
+        let expected = wrong.header.state_root;
+        wrong.header.state_root = blockchain.ledger.state_root();
+        wrong.mine_block().unwrap();
+        assert!(matches!(blockchain.accept_block(&wrong), Err(BlockError::StateRootMismatch { expected: e, .. }) if e == expected));
+        assert_eq!(blockchain.ledger.accounts()[&wallet.generate_address()], 100);
+
+        blockchain.mine_next_block("M", &[tx]).unwrap();
+        let state_root = blockchain.chain.last().unwrap().header.state_root;
+        assert_eq!(state_root, blockchain.ledger.state_root());
+
+        // 헤더의 루트만으로 잔액과 계정이 없다는 것을 확인한다.
+        let proof = blockchain.ledger.prove_account("B");
+        assert!(proof.verify(&state_root));
+        assert_eq!(proof.balance(), 60);
+        let absent = blockchain.ledger.prove_account("nobody");
+        assert!(absent.account.is_none());
+        assert!(absent.verify(&state_root));
+
+        let mut forged = proof.clone();
+        forged.account = Some(ledger::AccountState { balance: 1000, nonce: 0 });
+        assert!(!forged.verify(&state_root));
+        let mut hidden = proof;
+        hidden.account = None;
+        assert!(!hidden.verify(&state_root));
+    }
+
+    #[test]
+    fn test_locator_and_headers_after() {
+        assert_eq!(locator_heights(0), vec![0]);
+        assert_eq!(locator_heights(12), vec![12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 1, 0]);
+        assert_eq!(locator_heights(40), vec![40, 39, 38, 37, 36, 35, 34, 33, 32, 31, 29, 25, 17, 1, 0]);
+
+        let mut blockchain = BlockChain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        for _ in 0..5 {
+            blockchain.mine_next_block("M", &[]).unwrap();
+        }
+        let hash_at = |height: usize| blockchain.chain[height].header.block_hash;
+
+        // 처음 아는 해시 다음부터. 모르는 해시는 건너뛴다.
+        let headers = blockchain.headers_after(&[Hash256::digest(b"unknown"), hash_at(2), hash_at(0)], &Hash256::ZERO, 10);
+        assert_eq!(headers.iter().map(|header| header.height).collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert_eq!(blockchain.headers_after(&[], &hash_at(2), 10).len(), 2);
+        assert_eq!(blockchain.headers_after(&[hash_at(0)], &Hash256::ZERO, 3).len(), 3);
+        assert!(blockchain.headers_after(&blockchain.block_locator(), &Hash256::ZERO, 10).is_empty());
+
+        // 같은 제네시스로 시작한 체인은 블록을 그대로 받을 수 있다.
+        let mut other = BlockChain::with_genesis(AccountLedger::default(), blockchain.chain[0].clone());
+        other.initial_bits = POW_LIMIT_BITS;
+        for block in blockchain.chain[1..].iter() {
+            other.accept_block(block).unwrap();
+        }
+        assert_eq!(other.tip_hash(), blockchain.tip_hash());
+    }
+}
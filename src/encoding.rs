@@ -0,0 +1,599 @@
+#![allow(unused)]
+use std::fmt;
+use p256::ecdsa::{Signature, VerifyingKey};
+
+use crate::{blockchain::{Block, BlockHeader}, hash::Hash256, ledger::{AccountProof, AccountState, AccountUndo, UtxoUndo}, merkle_tree::{MerkleProof, MerkleTree, MultiProof}, state_tree::SparseProof, transaction::{OutPoint, Transaction, TxOutput}};
+
+// 합의에 쓰이는 타입들의 바이너리 포맷 버전.
+// 최상위 encode()/decode() 에만 붙고, 중첩된 값에는 붙지 않는다. 배포된 포맷이 바뀔 때만 올린다.
+pub const ENCODING_VERSION: u8 = 1;
+
+pub const PUBLIC_KEY_LEN: usize = 33;
+pub const SIGNATURE_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEof,
+    TrailingBytes(usize),
+    UnsupportedVersion(u8),
+    InvalidFlag(u8),
+    InvalidUtf8,
+    InvalidPublicKey,
+    InvalidSignature,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of input"),
+            DecodeError::TrailingBytes(count) => write!(f, "{} trailing bytes after value", count),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported encoding version {}", version),
+            DecodeError::InvalidFlag(flag) => write!(f, "invalid option flag {}", flag),
+            DecodeError::InvalidUtf8 => write!(f, "invalid utf-8 string"),
+            DecodeError::InvalidPublicKey => write!(f, "invalid SEC1 compressed public key"),
+            DecodeError::InvalidSignature => write!(f, "invalid signature"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// 정수는 고정 길이 little-endian, 가변 길이 값은 u32 길이 접두사를 붙인다.
+pub trait Encode {
+    fn encode_to(&self, buf: &mut Vec<u8>);
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![ENCODING_VERSION];
+        self.encode_to(&mut buf);
+        buf
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError>;
+
+    // 버전이 다르거나 뒤에 남는 바이트가 있으면 거부한다.
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let version = reader.u8()?;
+        if version != ENCODING_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let value = Self::decode_from(&mut reader)?;
+        reader.finish()?;
+        Ok(value)
+    }
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    pub fn finish(&self) -> Result<(), DecodeError> {
+        match self.remaining() {
+            0 => Ok(()),
+            count => Err(DecodeError::TrailingBytes(count)),
+        }
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if len > self.remaining() {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn u128(&mut self) -> Result<u128, DecodeError> {
+        Ok(u128::from_le_bytes(self.array()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn string(&mut self) -> Result<String, DecodeError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    pub fn flag(&mut self) -> Result<bool, DecodeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            flag => Err(DecodeError::InvalidFlag(flag)),
+        }
+    }
+
+    // 원소 수를 믿고 미리 메모리를 잡지 않도록, 남은 바이트보다 큰 개수는 바로 거부한다.
+    pub fn count(&mut self) -> Result<usize, DecodeError> {
+        let count = self.u32()? as usize;
+        if count > self.remaining() {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        Ok(count)
+    }
+
+    pub fn vec<T: Decode>(&mut self) -> Result<Vec<T>, DecodeError> {
+        let count = self.count()?;
+        (0..count).map(|_| T::decode_from(self)).collect()
+    }
+}
+
+pub fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u128(buf: &mut Vec<u8>, value: u128) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(buf, bytes.len() as u32);
+    buf.extend_from_slice(bytes);
+}
+
+pub fn put_str(buf: &mut Vec<u8>, value: &str) {
+    put_bytes(buf, value.as_bytes());
+}
+
+pub fn put_vec<T: Encode>(buf: &mut Vec<u8>, values: &[T]) {
+    put_u32(buf, values.len() as u32);
+    for value in values {
+        value.encode_to(buf);
+    }
+}
+
+impl Encode for String {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        put_str(buf, self);
+    }
+}
+
+impl Decode for String {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        reader.string()
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                buf.push(1);
+                value.encode_to(buf);
+            }
+            None => buf.push(0),
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        if reader.flag()? {
+            Ok(Some(T::decode_from(reader)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// 해시는 길이 접두사 없이 32바이트 그대로
+impl Encode for Hash256 {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for Hash256 {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Hash256(reader.array()?))
+    }
+}
+
+// SEC1 압축 형식 33바이트
+impl Encode for VerifyingKey {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.to_encoded_point(true).as_bytes());
+    }
+}
+
+impl Decode for VerifyingKey {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let bytes: [u8; PUBLIC_KEY_LEN] = reader.array()?;
+        if bytes[0] != 0x02 && bytes[0] != 0x03 {
+            return Err(DecodeError::InvalidPublicKey);
+        }
+        VerifyingKey::from_sec1_bytes(&bytes).map_err(|_| DecodeError::InvalidPublicKey)
+    }
+}
+
+// r || s 64바이트
+impl Encode for Signature {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_bytes());
+    }
+}
+
+impl Decode for Signature {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let bytes: [u8; SIGNATURE_LEN] = reader.array()?;
+        Signature::from_slice(&bytes).map_err(|_| DecodeError::InvalidSignature)
+    }
+}
+
+impl Encode for OutPoint {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.txid.encode_to(buf);
+        put_u32(buf, self.index);
+    }
+}
+
+impl Decode for OutPoint {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(OutPoint { txid: Hash256::decode_from(reader)?, index: reader.u32()? })
+    }
+}
+
+impl Encode for TxOutput {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        put_str(buf, &self.address);
+        put_u64(buf, self.amount);
+    }
+}
+
+impl Decode for TxOutput {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(TxOutput { address: reader.string()?, amount: reader.u64()? })
+    }
+}
+
+impl Encode for Transaction {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        put_str(buf, &self.sender);
+        put_str(buf, &self.receiver);
+        put_u64(buf, self.amount);
+        put_u64(buf, self.fee);
+        put_u64(buf, self.nonce);
+        put_vec(buf, &self.inputs);
+        put_vec(buf, &self.outputs);
+        self.signature.encode_to(buf);
+        self.public_key.encode_to(buf);
+        put_u128(buf, self.timestamp);
+    }
+}
+
+impl Decode for Transaction {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Transaction {
+            sender: reader.string()?,
+            receiver: reader.string()?,
+            amount: reader.u64()?,
+            fee: reader.u64()?,
+            nonce: reader.u64()?,
+            inputs: reader.vec()?,
+            outputs: reader.vec()?,
+            signature: Option::decode_from(reader)?,
+            public_key: Option::decode_from(reader)?,
+            timestamp: reader.u128()?,
+        })
+    }
+}
+
+// 잎만 저장하고 내부 층과 루트는 읽을 때 다시 계산한다.
+impl Encode for MerkleTree {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        put_vec(buf, self.leaf_nodes());
+    }
+}
+
+impl Decode for MerkleTree {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(MerkleTree::from_leaves(reader.vec()?))
+    }
+}
+
+impl Encode for MerkleProof {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.leaf.encode_to(buf);
+        put_u64(buf, self.index);
+        put_vec(buf, &self.siblings);
+        put_u64(buf, self.tree_size);
+    }
+}
+
+impl Decode for MerkleProof {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(MerkleProof {
+            leaf: Hash256::decode_from(reader)?,
+            index: reader.u64()?,
+            siblings: reader.vec()?,
+            tree_size: reader.u64()?,
+        })
+    }
+}
+
+impl Encode for MultiProof {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        put_u64(buf, self.tree_size);
+        put_u32(buf, self.leaves.len() as u32);
+        for (index, leaf) in self.leaves.iter() {
+            put_u64(buf, *index);
+            leaf.encode_to(buf);
+        }
+        put_vec(buf, &self.siblings);
+    }
+}
+
+impl Decode for MultiProof {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let tree_size = reader.u64()?;
+        let count = reader.count()?;
+        let leaves = (0..count)
+            .map(|_| Ok((reader.u64()?, Hash256::decode_from(reader)?)))
+            .collect::<Result<Vec<_>, DecodeError>>()?;
+        Ok(MultiProof { tree_size, leaves, siblings: reader.vec()? })
+    }
+}
+
+// bitmap 은 길이 접두사 없이 32바이트
+impl Encode for SparseProof {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.bitmap);
+        put_vec(buf, &self.siblings);
+    }
+}
+
+impl Decode for SparseProof {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(SparseProof { bitmap: reader.array()?, siblings: reader.vec()? })
+    }
+}
+
+impl Encode for AccountState {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        put_u64(buf, self.balance);
+        put_u64(buf, self.nonce);
+    }
+}
+
+impl Decode for AccountState {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(AccountState { balance: reader.u64()?, nonce: reader.u64()? })
+    }
+}
+
+impl Encode for AccountProof {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        put_str(buf, &self.address);
+        self.account.encode_to(buf);
+        self.proof.encode_to(buf);
+    }
+}
+
+impl Decode for AccountProof {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(AccountProof {
+            address: reader.string()?,
+            account: Option::decode_from(reader)?,
+            proof: SparseProof::decode_from(reader)?,
+        })
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        put_u32(buf, self.version);
+        self.previous_hash.encode_to(buf);
+        self.block_hash.encode_to(buf);
+        self.merkle_root.encode_to(buf);
+        put_u64(buf, self.tx_count);
+        self.state_root.encode_to(buf);
+        put_u128(buf, self.timestamp);
+        put_u64(buf, self.height);
+        put_u64(buf, self.nonce);
+        put_u32(buf, self.bits);
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(BlockHeader {
+            version: reader.u32()?,
+            previous_hash: Hash256::decode_from(reader)?,
+            block_hash: Hash256::decode_from(reader)?,
+            merkle_root: Hash256::decode_from(reader)?,
+            tx_count: reader.u64()?,
+            state_root: Hash256::decode_from(reader)?,
+            timestamp: reader.u128()?,
+            height: reader.u64()?,
+            nonce: reader.u64()?,
+            bits: reader.u32()?,
+        })
+    }
+}
+
+impl Encode for Block {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.header.encode_to(buf);
+        put_vec(buf, &self.transactions);
+    }
+}
+
+impl Decode for Block {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Block {
+            header: BlockHeader::decode_from(reader)?,
+            transactions: reader.vec()?,
+        })
+    }
+}
+
+impl Encode for AccountUndo {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        put_account_values(buf, &self.prior_balances);
+        put_vec(buf, &self.created_accounts);
+        put_account_values(buf, &self.prior_nonces);
+    }
+}
+
+impl Decode for AccountUndo {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(AccountUndo {
+            prior_balances: account_values(reader)?,
+            created_accounts: reader.vec()?,
+            prior_nonces: account_values(reader)?,
+        })
+    }
+}
+
+impl Encode for UtxoUndo {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        put_u32(buf, self.spent.len() as u32);
+        for (outpoint, output) in self.spent.iter() {
+            outpoint.encode_to(buf);
+            output.encode_to(buf);
+        }
+        put_vec(buf, &self.created);
+    }
+}
+
+impl Decode for UtxoUndo {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let count = reader.count()?;
+        let spent = (0..count)
+            .map(|_| Ok((OutPoint::decode_from(reader)?, TxOutput::decode_from(reader)?)))
+            .collect::<Result<Vec<_>, DecodeError>>()?;
+        Ok(UtxoUndo { spent, created: reader.vec()? })
+    }
+}
+
+fn put_account_values(buf: &mut Vec<u8>, values: &[(String, u64)]) {
+    put_u32(buf, values.len() as u32);
+    for (account, value) in values.iter() {
+        put_str(buf, account);
+        put_u64(buf, *value);
+    }
+}
+
+fn account_values(reader: &mut Reader) -> Result<Vec<(String, u64)>, DecodeError> {
+    let count = reader.count()?;
+    (0..count).map(|_| Ok((reader.string()?, reader.u64()?))).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{pow::POW_LIMIT_BITS, wallet::Wallet};
+
+    use super::*;
+
+    fn signed_transaction() -> Transaction {
+        let wallet = Wallet::new();
+        let mut tx = Transaction::new(wallet.generate_address(), String::from("B"), 100, 2, 7);
+        wallet.sign_transaction(&mut tx).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tx = signed_transaction();
+        let bytes = tx.encode();
+        let decoded = Transaction::decode(&bytes).unwrap();
+        assert_eq!(decoded.signature, tx.signature);
+        assert_eq!(decoded.public_key, tx.public_key);
+        assert_eq!(decoded.encode(), bytes);
+
+        let block = Block::new(Hash256::ZERO, 1, vec![tx, signed_transaction()], POW_LIMIT_BITS).unwrap();
+        let bytes = block.encode();
+        let decoded = Block::decode(&bytes).unwrap();
+        assert_eq!(decoded.header.block_hash, block.header.block_hash);
+        assert_eq!(decoded.header.merkle_root, block.header.merkle_root);
+        assert_eq!(decoded.header.tx_count, 2);
+        assert_eq!(decoded.encode(), bytes);
+
+        let undo = AccountUndo {
+            prior_balances: vec![("A".to_string(), 10)],
+            created_accounts: vec!["B".to_string()],
+            prior_nonces: vec![("A".to_string(), 3)],
+        };
+        assert_eq!(AccountUndo::decode(&undo.encode()).unwrap(), undo);
+
+        let outpoint = OutPoint { txid: block.transactions[0].calculate_hash().unwrap(), index: 1 };
+        let output = TxOutput { address: "B".to_string(), amount: 60 };
+        let spend = Transaction::new_spend("A".to_string(), vec![outpoint.clone()], vec![output.clone()], 3);
+        assert_eq!(Transaction::decode(&spend.encode()).unwrap().encode(), spend.encode());
+
+        let undo = UtxoUndo { spent: vec![(outpoint.clone(), output)], created: vec![outpoint] };
+        assert_eq!(UtxoUndo::decode(&undo.encode()).unwrap(), undo);
+
+        let proof = block.merkle_tree().unwrap().proof(1).unwrap();
+        assert_eq!(MerkleProof::decode(&proof.encode()).unwrap(), proof);
+        let multi_proof = block.merkle_tree().unwrap().multi_proof(&[0, 1]).unwrap();
+        assert_eq!(MultiProof::decode(&multi_proof.encode()).unwrap(), multi_proof);
+
+        let mut ledger = crate::ledger::AccountLedger::default();
+        ledger.set_balance("A", 10);
+        ledger.set_balance("B", 20);
+        for address in ["A", "C"] {
+            let proof = ledger.prove_account(address);
+            assert_eq!(AccountProof::decode(&proof.encode()).unwrap(), proof);
+        }
+    }
+
+    #[test]
+    fn test_strict_decoding() {
+        let bytes = signed_transaction().encode();
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(Transaction::decode(&trailing).unwrap_err(), DecodeError::TrailingBytes(1));
+
+        assert_eq!(Transaction::decode(&bytes[..bytes.len() - 1]).unwrap_err(), DecodeError::UnexpectedEof);
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = ENCODING_VERSION + 1;
+        assert_eq!(Transaction::decode(&wrong_version).unwrap_err(), DecodeError::UnsupportedVersion(ENCODING_VERSION + 1));
+
+        // 버전(1) + sender(4+64) + receiver(4+1) + amount(8) + fee(8) + nonce(8) + 빈 inputs(4) + 빈 outputs(4) 다음이 signature 플래그
+        let flag_pos = 1 + 4 + 64 + 4 + 1 + 8 + 8 + 8 + 4 + 4;
+        let mut bad_flag = bytes.clone();
+        bad_flag[flag_pos] = 2;
+        assert_eq!(Transaction::decode(&bad_flag).unwrap_err(), DecodeError::InvalidFlag(2));
+
+        let key_pos = flag_pos + 1 + SIGNATURE_LEN + 1;
+        let mut bad_key = bytes.clone();
+        bad_key[key_pos] = 0x04;
+        assert_eq!(Transaction::decode(&bad_key).unwrap_err(), DecodeError::InvalidPublicKey);
+    }
+}
@@ -0,0 +1,260 @@
+#![allow(unused)]
+use std::{error::Error, fmt, io};
+
+use crate::{encoding::DecodeError, hash::Hash256};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    Unsigned,
+    MissingPublicKey,
+    InvalidSignature,
+    SenderMismatch,
+    UnknownSender,
+    EmptyReceiver,
+    InsufficientFunds,
+    Duplicate,
+    InvalidNonce { expected: u64, found: u64 },
+    MisplacedCoinbase,
+    InvalidCoinbase,
+    // 계정 모델 체인에 UTXO 트랜잭션이 오거나 그 반대
+    WrongLedgerModel,
+    DoubleSpend,
+    MissingOrSpentInput,
+    InputNotOwned,
+    UnbalancedOutputs,
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::Unsigned => write!(f, "transaction is not signed"),
+            TxError::MissingPublicKey => write!(f, "transaction has no public key"),
+            TxError::InvalidSignature => write!(f, "invalid signature"),
+            TxError::SenderMismatch => write!(f, "sender address does not belong to the signing key"),
+            TxError::UnknownSender => write!(f, "sender account not found"),
+            TxError::EmptyReceiver => write!(f, "receiver address is empty"),
+            TxError::InsufficientFunds => write!(f, "insufficient funds"),
+            TxError::Duplicate => write!(f, "duplicate transaction"),
+            TxError::InvalidNonce { expected, found } => write!(f, "expected nonce {} but found {}", expected, found),
+            TxError::MisplacedCoinbase => write!(f, "coinbase must be the first transaction of a block"),
+            TxError::InvalidCoinbase => write!(f, "coinbase must not carry a fee"),
+            TxError::WrongLedgerModel => write!(f, "transaction does not fit the ledger model"),
+            TxError::DoubleSpend => write!(f, "output is spent twice in the same block"),
+            TxError::MissingOrSpentInput => write!(f, "input refers to a missing or already spent output"),
+            TxError::InputNotOwned => write!(f, "input does not belong to the sender"),
+            TxError::UnbalancedOutputs => write!(f, "inputs exceed outputs plus fee"),
+        }
+    }
+}
+
+impl Error for TxError {}
+
+#[derive(Debug)]
+pub enum BlockError {
+    AlreadyKnown,
+    UnknownParent,
+    InvalidHash,
+    InsufficientWork,
+    UnsupportedVersion(u32),
+    InvalidHeight { expected: u64, found: u64 },
+    // 부모 체인에서 계산한 난이도와 헤더의 bits 가 다르다.
+    InvalidBits { expected: u32, found: u32 },
+    // 목표값으로 풀 수 없는 bits 라서 채굴할 수 없다.
+    UnminableBits(u32),
+    MerkleRootMismatch,
+    // 블록을 적용한 뒤의 상태 루트가 헤더와 다르다.
+    StateRootMismatch { expected: Hash256, found: Hash256 },
+    CoinbaseOverpaid { claimed: u64, allowed: u64 },
+    TimestampTooOld,
+    TimestampTooNew,
+    // 블록 안에서 몇 번째 트랜잭션이 왜 거부됐는지
+    Transaction(usize, TxError),
+    Storage(io::Error),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::AlreadyKnown => write!(f, "block is already known"),
+            BlockError::UnknownParent => write!(f, "previous block is unknown"),
+            BlockError::InvalidHash => write!(f, "block hash does not match header"),
+            BlockError::InsufficientWork => write!(f, "block hash does not meet target"),
+            BlockError::UnsupportedVersion(version) => write!(f, "unsupported block version {}", version),
+            BlockError::InvalidHeight { expected, found } => write!(f, "expected height {} but found {}", expected, found),
+            BlockError::InvalidBits { expected, found } => write!(f, "expected bits {:#010x} but found {:#010x}", expected, found),
+            BlockError::UnminableBits(bits) => write!(f, "bits {:#010x} do not encode a minable target", bits),
+            BlockError::MerkleRootMismatch => write!(f, "merkle root does not match transactions"),
+            BlockError::StateRootMismatch { expected, found } => write!(f, "expected state root {} but found {}", expected, found),
+            BlockError::CoinbaseOverpaid { claimed, allowed } => write!(f, "coinbase claims {} but only {} is allowed", claimed, allowed),
+            BlockError::TimestampTooOld => write!(f, "timestamp is not after median of recent blocks"),
+            BlockError::TimestampTooNew => write!(f, "timestamp is too far in the future"),
+            BlockError::Transaction(i, e) => write!(f, "transaction {} rejected: {}", i, e),
+            BlockError::Storage(e) => write!(f, "failed to store block: {}", e),
+        }
+    }
+}
+
+impl Error for BlockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BlockError::Transaction(_, e) => Some(e),
+            BlockError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ChainError {
+    // 저장된 체인의 몇 번째 블록이 잘못됐는지
+    BrokenLink(usize),
+    InvalidHash(usize),
+    // 저장된 블록을 다시 적용한 상태가 헤더의 state_root 와 다르다. 제네시스 전 상태가 다르게 주어진 경우다.
+    StateRootMismatch { height: u64, expected: Hash256, found: Hash256 },
+    Storage(io::Error),
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::BrokenLink(i) => write!(f, "block {} does not link to its predecessor", i),
+            ChainError::InvalidHash(i) => write!(f, "block {} hash does not match header", i),
+            ChainError::StateRootMismatch { height, expected, found } => {
+                write!(f, "replayed state root {} at height {} does not match header {}", expected, height, found)
+            }
+            ChainError::Storage(e) => write!(f, "block store error: {}", e),
+        }
+    }
+}
+
+impl Error for ChainError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ChainError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ChainError {
+    fn from(e: io::Error) -> Self {
+        ChainError::Storage(e)
+    }
+}
+
+// light client 가 헤더만 가지고 증명을 확인하다 실패한 이유
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpvError {
+    UnknownHeader,
+    // 헤더는 알지만 가장 무거운 체인에 있지 않다.
+    NotInBestChain,
+    InvalidProof,
+}
+
+impl fmt::Display for SpvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpvError::UnknownHeader => write!(f, "header is unknown"),
+            SpvError::NotInBestChain => write!(f, "header is not in the best chain"),
+            SpvError::InvalidProof => write!(f, "proof does not match header"),
+        }
+    }
+}
+
+impl Error for SpvError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalletError {
+    SigningFailed,
+    Unsigned,
+    KeyMismatch,
+    InvalidSignature,
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::SigningFailed => write!(f, "failed to sign transaction"),
+            WalletError::Unsigned => write!(f, "transaction is not signed"),
+            WalletError::KeyMismatch => write!(f, "transaction was signed by another key"),
+            WalletError::InvalidSignature => write!(f, "invalid signature"),
+        }
+    }
+}
+
+impl Error for WalletError {}
+
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),
+    Disconnected,
+    InvalidMagic([u8; 4]),
+    // 명령어 칸이 ASCII + 0 채움 형식이 아니다.
+    InvalidCommand,
+    UnknownCommand(String),
+    PayloadTooLarge(usize),
+    InvalidChecksum,
+    TooManyItems(usize),
+    // 프레임은 온전하지만 payload 를 명령어에 맞게 읽을 수 없다.
+    Malformed(DecodeError),
+    WrongNetwork { expected: u32, found: u32 },
+    UnsupportedVersion(u32),
+    // 지금 받을 차례가 아닌 메시지 (handshake 중의 다른 메시지 등)
+    UnexpectedMessage(String),
+    // peer 가 보낸 헤더나 블록이 합의 규칙에 맞지 않는다.
+    Rejected(BlockError),
+    Timeout,
+    // 보내기 큐가 가득 찼다. 상대가 받는 속도가 따라오지 못한다.
+    QueueFull,
+    // 같은 주소와 이미 연결되어 있다. 나중에 맺은 연결을 닫는다.
+    AlreadyConnected,
+    // 이 peer 의 응답을 이미 다른 요청이 기다리고 있다.
+    Busy,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::Io(e) => write!(f, "network error: {}", e),
+            NetError::Disconnected => write!(f, "peer disconnected"),
+            NetError::InvalidMagic(magic) => write!(f, "unexpected network magic {}", hex::encode(magic)),
+            NetError::InvalidCommand => write!(f, "malformed command field"),
+            NetError::UnknownCommand(command) => write!(f, "unknown command {:?}", command),
+            NetError::PayloadTooLarge(len) => write!(f, "payload of {} bytes exceeds limit", len),
+            NetError::InvalidChecksum => write!(f, "payload checksum mismatch"),
+            NetError::TooManyItems(count) => write!(f, "message carries too many items ({})", count),
+            NetError::Malformed(e) => write!(f, "malformed payload: {}", e),
+            NetError::WrongNetwork { expected, found } => write!(f, "peer is on network {:#010x}, expected {:#010x}", found, expected),
+            NetError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {}", version),
+            NetError::UnexpectedMessage(command) => write!(f, "unexpected {} message", command),
+            NetError::Rejected(e) => write!(f, "peer sent invalid data: {}", e),
+            NetError::Timeout => write!(f, "peer timed out"),
+            NetError::QueueFull => write!(f, "outbound queue is full"),
+            NetError::AlreadyConnected => write!(f, "peer is already connected"),
+            NetError::Busy => write!(f, "another request is already waiting on this peer"),
+        }
+    }
+}
+
+impl Error for NetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NetError::Io(e) => Some(e),
+            NetError::Malformed(e) => Some(e),
+            NetError::Rejected(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NetError {
+    fn from(e: io::Error) -> Self {
+        NetError::Io(e)
+    }
+}
+
+impl From<DecodeError> for NetError {
+    fn from(e: DecodeError) -> Self {
+        NetError::Malformed(e)
+    }
+}
@@ -0,0 +1,58 @@
+#![allow(unused)]
+use std::fmt;
+use sha2::{Sha256, Digest};
+
+// SHA-256 결과. 합의 규칙에서는 이 32바이트만 쓰고, hex 는 출력할 때만 만든다.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Hash256(pub [u8; 32]);
+
+impl Hash256 {
+    pub const ZERO: Hash256 = Hash256([0; 32]);
+
+    pub fn digest(data: &[u8]) -> Hash256 {
+        Hash256(Sha256::digest(data).into())
+    }
+
+    pub fn from_hasher(hasher: Sha256) -> Hash256 {
+        Hash256(hasher.finalize().into())
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Hash256> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(hex, &mut bytes).ok()?;
+        Some(Hash256(bytes))
+    }
+
+    pub fn to_hex(self) -> String {
+        hex::encode(self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hex_round_trip() {
+        let hash = Hash256::digest(b"abc");
+        assert_eq!(hash.to_string(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(Hash256::from_hex(&hash.to_hex()), Some(hash));
+        assert_eq!(Hash256::from_hex("00"), None);
+    }
+}
@@ -0,0 +1,754 @@
+#![allow(unused)]
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, fmt::Debug};
+
+use crate::{blockchain::Block, encoding::{Decode, Encode}, error::{BlockError, TxError}, hash::Hash256, state_tree::{self, SparseMerkleTree, SparseProof}, transaction::{OutPoint, Transaction, TxOutput}};
+
+// mempool 에 받아 두는 미래 nonce 의 한도. 송신자 하나가 풀을 채우지 못하게 한다.
+pub const MAX_NONCE_GAP: u64 = 64;
+
+// 블록체인 상태 모델. 계정 잔액 모델(AccountLedger)과 UTXO 모델(UtxoLedger)이 있다.
+// 서명과 coinbase 위치, 보상 한도는 BlockChain 이 먼저 확인하고 여기서는 상태에 대한 규칙만 본다.
+pub trait Ledger: Debug {
+    // apply_block 이전 상태로 되돌리기 위한 기록
+    type Undo: Debug + Clone + Encode + Decode;
+
+    // 블록 안의 트랜잭션을 순서대로 적용해 보면서 확인한다. 상태는 바꾸지 않는다.
+    fn check_transactions(&self, transactions: &[Transaction]) -> Result<(), BlockError>;
+
+    // mempool 에 넣기 전 확인
+    fn validate_transaction(&self, transaction: &Transaction) -> Result<(), TxError>;
+
+    fn apply_block(&mut self, block: &Block) -> Self::Undo;
+
+    fn revert_block(&mut self, block: &Block, undo: &Self::Undo);
+
+    // 아직 상태에 반영되지 않아서 mempool 에 남아 있어도 되는 트랜잭션인지
+    fn is_pending(&self, transaction: &Transaction) -> bool;
+
+    fn balance_of(&self, address: &str) -> u64;
+
+    // 현재 상태 전체에 대한 sparse merkle 루트. 블록 헤더의 state_root 와 비교한다.
+    fn state_root(&self) -> Hash256;
+
+    // transactions 를 적용했을 때의 상태 루트. 상태는 바꾸지 않는다.
+    fn state_root_after(&self, transactions: &[Transaction]) -> Hash256;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccountLedger {
+    accounts: HashMap<String, u64>,
+    // 계정마다 다음에 써야 할 nonce. 없으면 0
+    nonces: HashMap<String, u64>,
+    // 계정 상태의 sparse merkle tree. 계정이 바뀔 때마다 그 잎만 고친다.
+    tree: SparseMerkleTree,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AccountUndo {
+    // 블록이 건드린 기존 계정의 적용 전 잔액
+    pub prior_balances: Vec<(String, u64)>,
+    // 블록이 새로 만든 계정
+    pub created_accounts: Vec<String>,
+    // 블록이 nonce 를 올린 송신자의 적용 전 nonce
+    pub prior_nonces: Vec<(String, u64)>,
+}
+
+// 상태 트리의 잎 하나. 잔액이나 nonce 중 하나라도 있으면 계정이 있는 것으로 본다.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AccountState {
+    pub balance: u64,
+    pub nonce: u64,
+}
+
+impl AccountState {
+    // balance(u64) || nonce(u64), LE
+    pub fn hash(&self) -> Hash256 {
+        let mut data = [0u8; 16];
+        data[..8].copy_from_slice(&self.balance.to_le_bytes());
+        data[8..].copy_from_slice(&self.nonce.to_le_bytes());
+        Hash256::digest(&data)
+    }
+}
+
+// 상태 트리에서 계정 자리는 주소의 해시로 정한다.
+pub fn account_key(address: &str) -> Hash256 {
+    Hash256::digest(address.as_bytes())
+}
+
+// 상태 루트만 가지고 주소의 잔액을 확인하는 증명. account 가 None 이면 계정이 없다는 증명이다.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountProof {
+    pub address: String,
+    pub account: Option<AccountState>,
+    pub proof: SparseProof,
+}
+
+impl AccountProof {
+    pub fn verify(&self, state_root: &Hash256) -> bool {
+        let value = self.account.map(|account| account.hash());
+        state_tree::verify(state_root, &account_key(&self.address), value.as_ref(), &self.proof)
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.account.map_or(0, |account| account.balance)
+    }
+}
+
+impl AccountLedger {
+    pub fn from_state(accounts: HashMap<String, u64>, nonces: HashMap<String, u64>) -> AccountLedger {
+        let mut ledger = AccountLedger { accounts, nonces, tree: SparseMerkleTree::new() };
+        let addresses: BTreeSet<String> = ledger.accounts.keys().chain(ledger.nonces.keys()).cloned().collect();
+        ledger.refresh(addresses.iter().map(String::as_str));
+        ledger
+    }
+
+    pub fn accounts(&self) -> &HashMap<String, u64> {
+        &self.accounts
+    }
+
+    pub fn nonces(&self) -> &HashMap<String, u64> {
+        &self.nonces
+    }
+
+    // 블록 밖에서 잔액을 정한다. 제네시스 전 초기 분배나 테스트에서 쓴다.
+    pub fn set_balance(&mut self, address: &str, balance: u64) {
+        self.accounts.insert(address.to_string(), balance);
+        self.refresh([address]);
+    }
+
+    pub fn nonce_of(&self, account: &str) -> u64 {
+        self.nonces.get(account).copied().unwrap_or(0)
+    }
+
+    pub fn account(&self, address: &str) -> Option<AccountState> {
+        if !self.accounts.contains_key(address) && !self.nonces.contains_key(address) {
+            return None;
+        }
+        Some(AccountState { balance: self.balance_of(address), nonce: self.nonce_of(address) })
+    }
+
+    pub fn state_tree(&self) -> &SparseMerkleTree {
+        &self.tree
+    }
+
+    pub fn prove_account(&self, address: &str) -> AccountProof {
+        AccountProof {
+            address: address.to_string(),
+            account: self.account(address),
+            proof: self.tree.prove(&account_key(address)),
+        }
+    }
+
+    // 바뀐 계정들의 잎을 현재 상태로 맞춘다.
+    fn refresh<'a>(&mut self, addresses: impl IntoIterator<Item = &'a str>) {
+        for address in addresses {
+            match self.account(address) {
+                Some(account) => self.tree.insert(account_key(address), account.hash()),
+                None => self.tree.remove(&account_key(address)),
+            };
+        }
+    }
+
+    // transactions 를 차례로 적용한 뒤 바뀌는 잔액과 nonce. 상태는 바꾸지 않는다.
+    // 검증되지 않은 블록이 들어와도 패닉하지 않도록 잔액 계산은 포화 연산으로 한다.
+    fn changes<'a>(&self, transactions: &'a [Transaction]) -> (BTreeMap<&'a str, u64>, BTreeMap<&'a str, u64>) {
+        let mut balances: BTreeMap<&str, u64> = BTreeMap::new();
+        let mut nonces: BTreeMap<&str, u64> = BTreeMap::new();
+
+        for tx in transactions.iter() {
+            // 수수료는 coinbase 로 채굴자에게 간다. coinbase 는 새로 발행하므로 빼는 쪽이 없다.
+            if !tx.is_coinbase() {
+                // 없는 송신자는 바뀌지 않는다.
+                if let Some(balance) = balances.get(tx.sender.as_str()).or(self.accounts.get(&tx.sender)).copied() {
+                    balances.insert(&tx.sender, balance.saturating_sub(tx.amount).saturating_sub(tx.fee));
+                }
+                let nonce = nonces.get(tx.sender.as_str()).copied().unwrap_or_else(|| self.nonce_of(&tx.sender));
+                nonces.insert(&tx.sender, nonce.saturating_add(1));
+            }
+            let balance = balances.get(tx.receiver.as_str()).or(self.accounts.get(&tx.receiver)).copied().unwrap_or(0);
+            balances.insert(&tx.receiver, balance.saturating_add(tx.amount));
+        }
+        (balances, nonces)
+    }
+
+    fn apply(&mut self, transactions: &[Transaction]) -> AccountUndo {
+        let (balances, nonces) = self.changes(transactions);
+        let mut undo = AccountUndo::default();
+
+        for (account, balance) in balances.iter() {
+            match self.accounts.insert(account.to_string(), *balance) {
+                Some(prior) => undo.prior_balances.push((account.to_string(), prior)),
+                None => undo.created_accounts.push(account.to_string()),
+            }
+        }
+        for (account, nonce) in nonces.iter() {
+            let prior = self.nonces.insert(account.to_string(), *nonce).unwrap_or(0);
+            undo.prior_nonces.push((account.to_string(), prior));
+        }
+        let touched: BTreeSet<&str> = balances.keys().chain(nonces.keys()).copied().collect();
+        self.refresh(touched);
+        undo
+    }
+}
+
+impl Ledger for AccountLedger {
+    type Undo = AccountUndo;
+
+    fn check_transactions(&self, transactions: &[Transaction]) -> Result<(), BlockError> {
+        let mut balances: HashMap<&str, u64> = HashMap::new();
+        let mut nonces: HashMap<&str, u64> = HashMap::new();
+
+        for (i, tx) in transactions.iter().enumerate() {
+            let reject = |e| BlockError::Transaction(i, e);
+            if !tx.inputs.is_empty() || !tx.outputs.is_empty() {
+                return Err(reject(TxError::WrongLedgerModel));
+            }
+            if tx.receiver.is_empty() {
+                return Err(reject(TxError::EmptyReceiver));
+            }
+
+            if !tx.is_coinbase() {
+                let expected = nonces.get(tx.sender.as_str()).copied().unwrap_or_else(|| self.nonce_of(&tx.sender));
+                if tx.nonce != expected {
+                    return Err(reject(TxError::InvalidNonce { expected, found: tx.nonce }));
+                }
+                nonces.insert(&tx.sender, expected + 1);
+
+                let sender_balance = balances.get(tx.sender.as_str()).copied()
+                    .or_else(|| self.accounts.get(&tx.sender).copied())
+                    .ok_or(reject(TxError::UnknownSender))?;
+                let cost = tx.total_cost().filter(|cost| *cost <= sender_balance)
+                    .ok_or(reject(TxError::InsufficientFunds))?;
+                balances.insert(&tx.sender, sender_balance - cost);
+            }
+
+            let receiver_balance = balances.get(tx.receiver.as_str()).copied()
+                .or_else(|| self.accounts.get(&tx.receiver).copied())
+                .unwrap_or(0);
+            balances.insert(&tx.receiver, receiver_balance.saturating_add(tx.amount));
+        }
+        Ok(())
+    }
+
+    // 앞 nonce 가 아직 풀에 있을 수 있으니 미래 nonce 는 허용하되, MAX_NONCE_GAP 보다 멀리 앞선 것은 받지 않는다.
+    fn validate_transaction(&self, transaction: &Transaction) -> Result<(), TxError> {
+        if !transaction.inputs.is_empty() || !transaction.outputs.is_empty() {
+            return Err(TxError::WrongLedgerModel);
+        }
+        // 빈 주소는 coinbase 의 송신자 자리다. 아무도 가질 수 없는 계정에 보내지 못하게 한다.
+        if transaction.receiver.is_empty() {
+            return Err(TxError::EmptyReceiver);
+        }
+
+        let expected = self.nonce_of(&transaction.sender);
+        if transaction.nonce < expected || transaction.nonce - expected > MAX_NONCE_GAP {
+            return Err(TxError::InvalidNonce { expected, found: transaction.nonce });
+        }
+
+        let sender_balance = self.accounts.get(&transaction.sender).ok_or(TxError::UnknownSender)?;
+        if transaction.total_cost().is_none_or(|cost| cost > *sender_balance) {
+            return Err(TxError::InsufficientFunds);
+        }
+        Ok(())
+    }
+
+    fn apply_block(&mut self, block: &Block) -> AccountUndo {
+        self.apply(&block.transactions)
+    }
+
+    fn revert_block(&mut self, block: &Block, undo: &AccountUndo) {
+        for account in undo.created_accounts.iter() {
+            self.accounts.remove(account);
+        }
+        for (account, balance) in undo.prior_balances.iter() {
+            self.accounts.insert(account.clone(), *balance);
+        }
+        for (account, nonce) in undo.prior_nonces.iter() {
+            match nonce {
+                0 => self.nonces.remove(account),
+                _ => self.nonces.insert(account.clone(), *nonce),
+            };
+        }
+        let touched = undo.created_accounts.iter()
+            .chain(undo.prior_balances.iter().map(|(account, _)| account))
+            .chain(undo.prior_nonces.iter().map(|(account, _)| account));
+        let touched: BTreeSet<String> = touched.cloned().collect();
+        self.refresh(touched.iter().map(String::as_str));
+    }
+
+    fn is_pending(&self, transaction: &Transaction) -> bool {
+        transaction.nonce >= self.nonce_of(&transaction.sender)
+    }
+
+    fn balance_of(&self, address: &str) -> u64 {
+        self.accounts.get(address).copied().unwrap_or(0)
+    }
+
+    fn state_root(&self) -> Hash256 {
+        self.tree.root()
+    }
+
+    // 건드린 계정의 잎만 바꿔서 계산한다. 건드린 계정은 적용 뒤에 잔액이나 nonce 가 있으므로 지워지지 않는다.
+    fn state_root_after(&self, transactions: &[Transaction]) -> Hash256 {
+        let (balances, nonces) = self.changes(transactions);
+        let touched: BTreeSet<&str> = balances.keys().chain(nonces.keys()).copied().collect();
+        let changes = touched.into_iter().map(|address| {
+            let account = AccountState {
+                balance: balances.get(address).copied().unwrap_or_else(|| self.balance_of(address)),
+                nonce: nonces.get(address).copied().unwrap_or_else(|| self.nonce_of(address)),
+            };
+            (account_key(address), Some(account.hash()))
+        });
+        self.tree.root_after(changes)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UtxoLedger {
+    utxos: HashMap<OutPoint, TxOutput>,
+    // 쓰이지 않은 출력들의 sparse merkle tree. 출력이 생기거나 쓰일 때마다 그 잎만 고친다.
+    tree: SparseMerkleTree,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct UtxoUndo {
+    // 블록이 쓴 출력. 되돌릴 때 다시 넣는다.
+    pub spent: Vec<(OutPoint, TxOutput)>,
+    // 블록이 만든 출력
+    pub created: Vec<OutPoint>,
+}
+
+impl UtxoLedger {
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        self.utxos.get(outpoint)
+    }
+
+    pub fn utxos(&self) -> &HashMap<OutPoint, TxOutput> {
+        &self.utxos
+    }
+
+    // 블록 밖에서 출력을 만든다. 제네시스 전 초기 분배나 테스트에서 쓴다.
+    pub fn insert_output(&mut self, outpoint: OutPoint, output: TxOutput) {
+        self.utxos.insert(outpoint.clone(), output);
+        self.refresh([&outpoint]);
+    }
+
+    pub fn unspent_outputs_of(&self, address: &str) -> Vec<(OutPoint, TxOutput)> {
+        self.utxos.iter()
+            .filter(|(_, output)| output.address == address)
+            .map(|(outpoint, output)| (outpoint.clone(), output.clone()))
+            .collect()
+    }
+
+    // 출력마다 잎 하나. 키는 outpoint 인코딩의 해시, 값은 출력 인코딩의 해시
+    pub fn state_tree(&self) -> &SparseMerkleTree {
+        &self.tree
+    }
+
+    // outpoint 가 쓰이지 않은 출력인지(또는 없는지) 증명한다.
+    pub fn prove_output(&self, outpoint: &OutPoint) -> SparseProof {
+        self.tree.prove(&UtxoLedger::output_key(outpoint))
+    }
+
+    fn refresh<'a>(&mut self, outpoints: impl IntoIterator<Item = &'a OutPoint>) {
+        for outpoint in outpoints {
+            let key = UtxoLedger::output_key(outpoint);
+            match self.utxos.get(outpoint) {
+                Some(output) => self.tree.insert(key, UtxoLedger::output_hash(output)),
+                None => self.tree.remove(&key),
+            };
+        }
+    }
+
+    fn refresh_undo(&mut self, undo: &UtxoUndo) {
+        let outpoints: HashSet<OutPoint> = undo.spent.iter().map(|(outpoint, _)| outpoint).chain(undo.created.iter()).cloned().collect();
+        self.refresh(outpoints.iter());
+    }
+
+    // transactions 를 차례로 적용했을 때 바뀌는 출력. None 은 쓰여서 사라지는 출력이다. 상태는 바꾸지 않는다.
+    fn changes(&self, transactions: &[Transaction]) -> HashMap<OutPoint, Option<TxOutput>> {
+        let mut changes: HashMap<OutPoint, Option<TxOutput>> = HashMap::new();
+        let exists = |changes: &HashMap<OutPoint, Option<TxOutput>>, outpoint: &OutPoint| {
+            changes.get(outpoint).map_or_else(|| self.utxos.contains_key(outpoint), Option::is_some)
+        };
+
+        for tx in transactions.iter() {
+            for input in tx.inputs.iter() {
+                if exists(&changes, input) {
+                    changes.insert(input.clone(), None);
+                }
+            }
+
+            let Ok(txid) = tx.calculate_hash() else {
+                continue;
+            };
+            for (index, output) in UtxoLedger::created_outputs(tx).into_iter().enumerate() {
+                let outpoint = OutPoint { txid, index: index as u32 };
+                if !exists(&changes, &outpoint) {
+                    changes.insert(outpoint, Some(output));
+                }
+            }
+        }
+        changes
+    }
+
+    pub fn output_key(outpoint: &OutPoint) -> Hash256 {
+        let mut buf = Vec::new();
+        outpoint.encode_to(&mut buf);
+        Hash256::digest(&buf)
+    }
+
+    pub fn output_hash(output: &TxOutput) -> Hash256 {
+        let mut buf = Vec::new();
+        output.encode_to(&mut buf);
+        Hash256::digest(&buf)
+    }
+
+    fn apply(&mut self, transactions: &[Transaction]) -> UtxoUndo {
+        let mut undo = UtxoUndo::default();
+
+        for tx in transactions.iter() {
+            for input in tx.inputs.iter() {
+                if let Some(output) = self.utxos.remove(input) {
+                    undo.spent.push((input.clone(), output));
+                }
+            }
+
+            let Ok(txid) = tx.calculate_hash() else {
+                continue;
+            };
+            for (index, output) in UtxoLedger::created_outputs(tx).into_iter().enumerate() {
+                let outpoint = OutPoint { txid, index: index as u32 };
+                // 검증되지 않은 블록이 기존 출력을 덮어쓰면 되돌릴 수 없으니 건너뛴다.
+                if !self.utxos.contains_key(&outpoint) {
+                    self.utxos.insert(outpoint.clone(), output);
+                    undo.created.push(outpoint);
+                }
+            }
+        }
+        undo
+    }
+
+    // coinbase 는 receiver 에게 가는 출력 하나를 만든다.
+    fn created_outputs(transaction: &Transaction) -> Vec<TxOutput> {
+        if transaction.is_coinbase() {
+            return vec![TxOutput { address: transaction.receiver.clone(), amount: transaction.amount }];
+        }
+        transaction.outputs.clone()
+    }
+
+    // created 는 같은 블록의 앞선 트랜잭션이 만든 출력, spent 는 같은 블록에서 이미 쓴 출력
+    fn check_inputs(
+        &self,
+        transaction: &Transaction,
+        created: &HashMap<OutPoint, TxOutput>,
+        spent: &mut HashSet<OutPoint>,
+    ) -> Result<(), TxError> {
+        if transaction.inputs.is_empty() || transaction.amount != 0 {
+            return Err(TxError::WrongLedgerModel);
+        }
+
+        let mut input_value: u64 = 0;
+        for input in transaction.inputs.iter() {
+            if !spent.insert(input.clone()) {
+                return Err(TxError::DoubleSpend);
+            }
+            let output = created.get(input).or_else(|| self.utxos.get(input))
+                .ok_or(TxError::MissingOrSpentInput)?;
+            if output.address != transaction.sender {
+                return Err(TxError::InputNotOwned);
+            }
+            input_value = input_value.saturating_add(output.amount);
+        }
+
+        let required = transaction.outputs.iter()
+            .try_fold(transaction.fee, |sum, output| sum.checked_add(output.amount));
+        match required {
+            Some(required) if required == input_value => Ok(()),
+            Some(required) if required < input_value => Err(TxError::UnbalancedOutputs),
+            _ => Err(TxError::InsufficientFunds),
+        }
+    }
+}
+
+impl Ledger for UtxoLedger {
+    type Undo = UtxoUndo;
+
+    fn check_transactions(&self, transactions: &[Transaction]) -> Result<(), BlockError> {
+        let mut created: HashMap<OutPoint, TxOutput> = HashMap::new();
+        let mut spent = HashSet::new();
+
+        for (i, tx) in transactions.iter().enumerate() {
+            let reject = |e| BlockError::Transaction(i, e);
+            if !tx.is_coinbase() {
+                self.check_inputs(tx, &created, &mut spent).map_err(reject)?;
+            }
+
+            let txid = tx.calculate_hash().map_err(reject)?;
+            for (index, output) in UtxoLedger::created_outputs(tx).into_iter().enumerate() {
+                let outpoint = OutPoint { txid, index: index as u32 };
+                if self.utxos.contains_key(&outpoint) || created.contains_key(&outpoint) {
+                    return Err(reject(TxError::Duplicate));
+                }
+                created.insert(outpoint, output);
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_transaction(&self, transaction: &Transaction) -> Result<(), TxError> {
+        self.check_inputs(transaction, &HashMap::new(), &mut HashSet::new())
+    }
+
+    fn apply_block(&mut self, block: &Block) -> UtxoUndo {
+        let undo = self.apply(&block.transactions);
+        self.refresh_undo(&undo);
+        undo
+    }
+
+    // 같은 블록에서 만들고 쓴 출력도 있으니 쓴 출력을 먼저 되돌리고 만든 출력을 지운다.
+    fn revert_block(&mut self, block: &Block, undo: &UtxoUndo) {
+        for (outpoint, output) in undo.spent.iter() {
+            self.utxos.insert(outpoint.clone(), output.clone());
+        }
+        for outpoint in undo.created.iter() {
+            self.utxos.remove(outpoint);
+        }
+        self.refresh_undo(undo);
+    }
+
+    fn is_pending(&self, transaction: &Transaction) -> bool {
+        !transaction.inputs.is_empty() && transaction.inputs.iter().all(|input| self.utxos.contains_key(input))
+    }
+
+    fn balance_of(&self, address: &str) -> u64 {
+        self.utxos.values()
+            .filter(|output| output.address == address)
+            .fold(0u64, |sum, output| sum.saturating_add(output.amount))
+    }
+
+    fn state_root(&self) -> Hash256 {
+        self.tree.root()
+    }
+
+    fn state_root_after(&self, transactions: &[Transaction]) -> Hash256 {
+        let changes = self.changes(transactions).into_iter().map(|(outpoint, output)| {
+            (UtxoLedger::output_key(&outpoint), output.map(|output| UtxoLedger::output_hash(&output)))
+        });
+        self.tree.root_after(changes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{hash::Hash256, wallet::Wallet};
+
+    use super::*;
+
+    const NAMES: [&str; 5] = ["A", "B", "C", "D", "E"];
+
+    fn arbitrary_block(wallet: &Wallet, transfers: &[(usize, usize, u64)]) -> Block {
+        let transactions = transfers.iter().enumerate().map(|(i, (sender, receiver, amount))| {
+            let mut tx = Transaction::new(NAMES[*sender].to_string(), NAMES[*receiver].to_string(), *amount, amount % 7, i as u64);
+            tx.timestamp = i as u128;
+            wallet.sign_transaction(&mut tx).unwrap();
+            tx
+        }).collect();
+        Block::new(Hash256::ZERO, 1, transactions, 0).unwrap()
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_apply_then_revert_is_identity(
+            balances in proptest::collection::hash_map(0..NAMES.len(), 0..1000u64, 0..NAMES.len()),
+            transfers in proptest::collection::vec((0..NAMES.len(), 0..NAMES.len(), 0..2000u64), 0..8),
+            prior_nonces in proptest::collection::hash_map(0..NAMES.len(), 1..5u64, 0..NAMES.len()),
+        ) {
+            // 이미 nonce 가 있는 계정은 0 이 아니라 원래 값으로 돌아가야 한다.
+            let mut ledger = AccountLedger::from_state(
+                balances.into_iter().map(|(i, balance)| (NAMES[i].to_string(), balance)).collect(),
+                prior_nonces.into_iter().map(|(i, nonce)| (NAMES[i].to_string(), nonce)).collect(),
+            );
+
+            let block = arbitrary_block(&Wallet::new(), &transfers);
+
+            let accounts = ledger.accounts.clone();
+            let nonces = ledger.nonces.clone();
+            let root = ledger.state_root();
+            let root_after = ledger.state_root_after(&block.transactions);
+
+            // 고쳐 가며 유지한 트리는 처음부터 만든 트리와 같아야 한다.
+            let undo = ledger.apply_block(&block);
+            proptest::prop_assert_eq!(ledger.state_root(), root_after);
+            proptest::prop_assert_eq!(AccountLedger::from_state(ledger.accounts.clone(), ledger.nonces.clone()).state_root(), root_after);
+            ledger.revert_block(&block, &undo);
+
+            proptest::prop_assert_eq!(&ledger.accounts, &accounts);
+            proptest::prop_assert_eq!(&ledger.nonces, &nonces);
+            proptest::prop_assert_eq!(ledger.state_root(), root);
+        }
+
+        #[test]
+        fn prop_revert_blocks_in_reverse_order(
+            balances in proptest::collection::hash_map(0..NAMES.len(), 0..1000u64, 0..NAMES.len()),
+            blocks in proptest::collection::vec(
+                proptest::collection::vec((0..NAMES.len(), 0..NAMES.len(), 0..500u64), 0..4), 1..4),
+        ) {
+            let mut ledger = AccountLedger::from_state(
+                balances.into_iter().map(|(i, balance)| (NAMES[i].to_string(), balance)).collect(),
+                HashMap::new(),
+            );
+            let accounts = ledger.accounts.clone();
+            let nonces = ledger.nonces.clone();
+
+            let wallet = Wallet::new();
+            let mut applied = Vec::new();
+            for (i, transfers) in blocks.iter().enumerate() {
+                let mut block = arbitrary_block(&wallet, transfers);
+                for tx in block.transactions.iter_mut() {
+                    tx.timestamp += 100 * i as u128;
+                    wallet.sign_transaction(tx).unwrap();
+                }
+                let undo = ledger.apply_block(&block);
+                applied.push((block, undo));
+            }
+            for (block, undo) in applied.iter().rev() {
+                ledger.revert_block(block, undo);
+            }
+
+            proptest::prop_assert_eq!(&ledger.accounts, &accounts);
+            proptest::prop_assert_eq!(&ledger.nonces, &nonces);
+        }
+    }
+
+    #[test]
+    fn test_coinbase_does_not_debit_empty_account() {
+        let wallet = Wallet::new();
+        let address = wallet.generate_address();
+        let mut ledger = AccountLedger::default();
+        ledger.set_balance(&address, 100);
+
+        // 빈 주소로는 보낼 수 없다.
+        let mut burn = Transaction::new(address.clone(), String::new(), 10, 0, 0);
+        wallet.sign_transaction(&mut burn).unwrap();
+        assert_eq!(ledger.validate_transaction(&burn), Err(TxError::EmptyReceiver));
+        assert!(matches!(ledger.check_transactions(&[burn]), Err(BlockError::Transaction(0, TxError::EmptyReceiver))));
+
+        // 이미 빈 주소에 잔액이 있더라도 coinbase 가 그것을 깎지 않는다.
+        ledger.set_balance("", 30);
+        let block = Block::new(Hash256::ZERO, 1, vec![Transaction::new_coinbase("M".to_string(), 50)], 0).unwrap();
+        let before = ledger.accounts.clone();
+        let undo = ledger.apply_block(&block);
+        assert_eq!(ledger.balance_of(""), 30);
+        assert_eq!(ledger.balance_of("M"), 50);
+
+        ledger.revert_block(&block, &undo);
+        assert_eq!(ledger.accounts, before);
+    }
+
+    fn spend(wallet: &Wallet, inputs: Vec<OutPoint>, outputs: &[(&str, u64)], fee: u64) -> Transaction {
+        let outputs = outputs.iter()
+            .map(|(address, amount)| TxOutput { address: address.to_string(), amount: *amount })
+            .collect();
+        let mut tx = Transaction::new_spend(wallet.generate_address(), inputs, outputs, fee);
+        wallet.sign_transaction(&mut tx).unwrap();
+        tx
+    }
+
+    fn funded_ledger(wallet: &Wallet, amount: u64) -> (UtxoLedger, OutPoint) {
+        let mut ledger = UtxoLedger::default();
+        let outpoint = OutPoint { txid: Hash256::digest(b"funding"), index: 0 };
+        ledger.insert_output(outpoint.clone(), TxOutput { address: wallet.generate_address(), amount });
+        (ledger, outpoint)
+    }
+
+    #[test]
+    fn test_utxo_spend() {
+        let wallet = Wallet::new();
+        let (mut ledger, funding) = funded_ledger(&wallet, 100);
+
+        let tx = spend(&wallet, vec![funding.clone()], &[("B", 60), (&wallet.generate_address(), 35)], 5);
+        ledger.validate_transaction(&tx).unwrap();
+        let block = Block::new(Hash256::ZERO, 1, vec![tx.clone()], 0).unwrap();
+        ledger.check_transactions(&block.transactions).unwrap();
+        ledger.apply_block(&block);
+
+        let txid = tx.calculate_hash().unwrap();
+        assert!(ledger.get(&funding).is_none());
+        assert_eq!(ledger.get(&OutPoint { txid, index: 0 }).unwrap().amount, 60);
+        assert_eq!(ledger.balance_of("B"), 60);
+        assert_eq!(ledger.balance_of(&wallet.generate_address()), 35);
+        assert_eq!(ledger.unspent_outputs_of(&wallet.generate_address()), vec![
+            (OutPoint { txid, index: 1 }, TxOutput { address: wallet.generate_address(), amount: 35 }),
+        ]);
+        assert!(!ledger.is_pending(&tx));
+    }
+
+    #[test]
+    fn test_utxo_double_spend() {
+        let wallet = Wallet::new();
+        let (mut ledger, funding) = funded_ledger(&wallet, 100);
+
+        // 같은 블록 안에서 같은 출력을 두 번 쓴다.
+        let first = spend(&wallet, vec![funding.clone()], &[("B", 100)], 0);
+        let second = spend(&wallet, vec![funding.clone()], &[("C", 100)], 0);
+        assert!(first.conflicts_with(&second));
+        assert!(matches!(ledger.check_transactions(&[first.clone(), second.clone()]), Err(BlockError::Transaction(1, TxError::DoubleSpend))));
+
+        let twice = spend(&wallet, vec![funding.clone(), funding.clone()], &[("C", 200)], 0);
+        assert!(matches!(ledger.check_transactions(&[twice]), Err(BlockError::Transaction(0, TxError::DoubleSpend))));
+
+        // 앞 블록에서 이미 쓴 출력
+        let block = Block::new(Hash256::ZERO, 1, vec![first], 0).unwrap();
+        ledger.apply_block(&block);
+        assert_eq!(ledger.validate_transaction(&second), Err(TxError::MissingOrSpentInput));
+        assert!(matches!(ledger.check_transactions(&[second]), Err(BlockError::Transaction(0, TxError::MissingOrSpentInput))));
+    }
+
+    #[test]
+    fn test_utxo_rejects_invalid_spends() {
+        let wallet = Wallet::new();
+        let (ledger, funding) = funded_ledger(&wallet, 100);
+
+        let stolen = spend(&Wallet::new(), vec![funding.clone()], &[("B", 100)], 0);
+        assert_eq!(ledger.validate_transaction(&stolen), Err(TxError::InputNotOwned));
+
+        let overspent = spend(&wallet, vec![funding.clone()], &[("B", 100)], 1);
+        assert_eq!(ledger.validate_transaction(&overspent), Err(TxError::InsufficientFunds));
+
+        let unbalanced = spend(&wallet, vec![funding.clone()], &[("B", 90)], 0);
+        assert_eq!(ledger.validate_transaction(&unbalanced), Err(TxError::UnbalancedOutputs));
+
+        let mut account_style = Transaction::new(wallet.generate_address(), "B".to_string(), 10, 0, 0);
+        wallet.sign_transaction(&mut account_style).unwrap();
+        assert_eq!(ledger.validate_transaction(&account_style), Err(TxError::WrongLedgerModel));
+        assert_eq!(AccountLedger::default().validate_transaction(&overspent), Err(TxError::WrongLedgerModel));
+    }
+
+    #[test]
+    fn test_utxo_apply_then_revert() {
+        let wallet = Wallet::new();
+        let (mut ledger, funding) = funded_ledger(&wallet, 100);
+        let utxos = ledger.utxos.clone();
+        let root = ledger.state_root();
+
+        // 같은 블록에서 만든 출력을 바로 쓴다.
+        let first = spend(&wallet, vec![funding], &[(&wallet.generate_address(), 90)], 10);
+        let chained = OutPoint { txid: first.calculate_hash().unwrap(), index: 0 };
+        let second = spend(&wallet, vec![chained], &[("B", 90)], 0);
+        let coinbase = Transaction::new_coinbase("M".to_string(), 60);
+
+        let block = Block::new(Hash256::ZERO, 1, vec![coinbase, first, second], 0).unwrap();
+        ledger.check_transactions(&block.transactions).unwrap();
+        let root_after = ledger.state_root_after(&block.transactions);
+        let undo = ledger.apply_block(&block);
+        assert_eq!(ledger.state_root(), root_after);
+        assert_eq!(ledger.balance_of("B"), 90);
+        assert_eq!(ledger.balance_of("M"), 60);
+        assert_eq!(ledger.balance_of(&wallet.generate_address()), 0);
+
+        ledger.revert_block(&block, &undo);
+        assert_eq!(ledger.utxos, utxos);
+        assert_eq!(ledger.state_root(), root);
+    }
+}
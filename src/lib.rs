@@ -0,0 +1,19 @@
+pub mod merkle_tree;
+pub mod blockchain;
+pub mod encoding;
+pub mod error;
+pub mod hash;
+pub mod ledger;
+pub mod light_client;
+pub mod miner;
+pub mod transaction;
+pub mod utils;
+pub mod wallet;
+pub mod node;
+pub mod peer;
+pub mod pow;
+pub mod protocol;
+pub mod smart_contract;
+pub mod storage;
+pub mod state_tree;
+pub mod sync;
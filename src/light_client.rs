@@ -0,0 +1,276 @@
+#![allow(unused)]
+use std::collections::HashMap;
+
+use crate::{blockchain::{locator_heights, BlockHeader, HeaderIndex, ADJUSTMENT_INTERVAL, BLOCK_TIME, INITIAL_BITS}, error::{BlockError, SpvError}, hash::Hash256, ledger::AccountProof, merkle_tree::{self, MerkleProof}, pow::U256};
+
+#[derive(Debug, Clone)]
+pub struct HeaderNode {
+    pub header: BlockHeader,
+    pub height: usize,
+    pub chain_work: U256,
+}
+
+// 블록 본문 없이 헤더만 받는 체인. 링크와 PoW, 난이도 규칙은 전체 노드와 같은 HeaderIndex 로 확인하고
+// 트랜잭션과 잔액은 필요할 때 전체 노드에게 받은 증명(sync::fetch_proof)을 헤더의 머클 루트와 상태 루트에 맞춰 본다.
+#[derive(Debug)]
+pub struct HeaderChain {
+    pub headers: HashMap<Hash256, HeaderNode>,
+    // 누적 작업량이 가장 큰 브랜치의 해시 (높이 순)
+    best_chain: Vec<Hash256>,
+
+    // 전체 노드의 BlockChain 과 같은 값이어야 한다.
+    pub initial_bits: u32,
+    pub block_time: u128,
+    pub adjustment_interval: usize,
+}
+
+impl HeaderChain {
+    // genesis 는 검증하지 않고 믿는다. 연결할 전체 노드의 제네시스 헤더를 넣는다.
+    pub fn new(genesis: BlockHeader) -> HeaderChain {
+        let genesis_hash = genesis.block_hash;
+        let chain_work = genesis.work();
+        let mut headers = HashMap::new();
+        headers.insert(genesis_hash, HeaderNode { header: genesis, height: 0, chain_work });
+
+        HeaderChain {
+            headers,
+            best_chain: vec![genesis_hash],
+            initial_bits: INITIAL_BITS,
+            block_time: BLOCK_TIME,
+            adjustment_interval: ADJUSTMENT_INTERVAL,
+        }
+    }
+
+    // 규칙에 맞는 헤더를 넣고, 더 무거운 브랜치가 생기면 그쪽을 가장 좋은 체인으로 삼는다.
+    pub fn add_header(&mut self, header: BlockHeader) -> Result<(), BlockError> {
+        let block_hash = header.block_hash;
+        if self.headers.contains_key(&block_hash) {
+            return Err(BlockError::AlreadyKnown);
+        }
+        let height = self.check_header(&header)?;
+        let chain_work = self.headers[&header.previous_hash].chain_work.saturating_add(header.work());
+        self.headers.insert(block_hash, HeaderNode { header, height, chain_work });
+
+        if chain_work > self.tip_work() {
+            self.switch_to(&block_hash);
+        }
+        Ok(())
+    }
+
+    // 앞에서부터 넣다가 처음 거부된 헤더에서 멈춘다. 이미 아는 헤더는 건너뛴다.
+    pub fn add_headers(&mut self, headers: &[BlockHeader]) -> Result<(), BlockError> {
+        for header in headers {
+            match self.add_header(header.clone()) {
+                Ok(()) | Err(BlockError::AlreadyKnown) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // 포크 지점 위를 새 브랜치로 바꾼다.
+    fn switch_to(&mut self, new_tip: &Hash256) {
+        let mut branch = Vec::new();
+        let mut current = *new_tip;
+        while !self.is_active(&current) {
+            branch.push(current);
+            current = self.headers[&current].header.previous_hash;
+        }
+        let fork_height = self.headers[&current].height;
+        self.best_chain.truncate(fork_height + 1);
+        self.best_chain.extend(branch.into_iter().rev());
+    }
+
+    pub fn tip(&self) -> &BlockHeader {
+        &self.headers[&self.tip_hash()].header
+    }
+
+    pub fn tip_hash(&self) -> Hash256 {
+        *self.best_chain.last().unwrap()
+    }
+
+    pub fn tip_work(&self) -> U256 {
+        self.headers[&self.tip_hash()].chain_work
+    }
+
+    pub fn height(&self) -> usize {
+        self.best_chain.len() - 1
+    }
+
+    pub fn header(&self, block_hash: &Hash256) -> Option<&BlockHeader> {
+        self.headers.get(block_hash).map(|node| &node.header)
+    }
+
+    // 가장 좋은 체인에서 height 높이의 헤더
+    pub fn header_at(&self, height: usize) -> Option<&BlockHeader> {
+        self.best_chain.get(height).map(|block_hash| &self.headers[block_hash].header)
+    }
+
+    pub fn is_active(&self, block_hash: &Hash256) -> bool {
+        self.headers.get(block_hash)
+            .is_some_and(|node| self.best_chain.get(node.height) == Some(block_hash))
+    }
+
+    // 전체 노드에게 getheaders 를 보낼 때 쓴다.
+    pub fn block_locator(&self) -> Vec<Hash256> {
+        locator_heights(self.height()).into_iter().map(|height| self.best_chain[height]).collect()
+    }
+
+    // 팁이면 1. 가장 좋은 체인에 없으면 None
+    pub fn confirmations(&self, block_hash: &Hash256) -> Option<usize> {
+        if !self.is_active(block_hash) {
+            return None;
+        }
+        Some(self.best_chain.len() - self.headers[block_hash].height)
+    }
+
+    fn active_header(&self, block_hash: &Hash256) -> Result<&BlockHeader, SpvError> {
+        let header = self.header(block_hash).ok_or(SpvError::UnknownHeader)?;
+        if !self.is_active(block_hash) {
+            return Err(SpvError::NotInBestChain);
+        }
+        Ok(header)
+    }
+
+    // block_hash 블록에 txid 트랜잭션이 들어 있는지 확인하고 확정 블록 수를 돌려준다.
+    pub fn verify_transaction(&self, block_hash: &Hash256, txid: &Hash256, proof: &MerkleProof) -> Result<usize, SpvError> {
+        let header = self.active_header(block_hash)?;
+        if !proof.proves(txid)
+            || proof.tree_size != header.tx_count
+            || !merkle_tree::verify(&header.merkle_root, proof) {
+            return Err(SpvError::InvalidProof);
+        }
+        Ok(self.confirmations(block_hash).unwrap())
+    }
+
+    // block_hash 블록까지 적용한 상태에서 proof.address 의 잔액. 계정이 없다는 증명이면 0
+    pub fn verify_balance(&self, block_hash: &Hash256, proof: &AccountProof) -> Result<u64, SpvError> {
+        let header = self.active_header(block_hash)?;
+        if !proof.verify(&header.state_root) {
+            return Err(SpvError::InvalidProof);
+        }
+        Ok(proof.balance())
+    }
+}
+
+impl HeaderIndex for HeaderChain {
+    fn lookup(&self, block_hash: &Hash256) -> Option<(&BlockHeader, usize)> {
+        self.headers.get(block_hash).map(|node| (&node.header, node.height))
+    }
+
+    fn initial_bits(&self) -> u32 {
+        self.initial_bits
+    }
+
+    fn block_time(&self) -> u128 {
+        self.block_time
+    }
+
+    fn adjustment_interval(&self) -> usize {
+        self.adjustment_interval
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{blockchain::{Block, BlockChain}, pow::POW_LIMIT_BITS, transaction::Transaction, wallet::Wallet};
+
+    use super::*;
+
+    // 송금 블록 두 개가 있는 전체 노드와, 그 헤더만 받은 light client
+    fn synced_pair() -> (BlockChain, HeaderChain, Wallet) {
+        let mut blockchain = BlockChain::new();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let wallet = Wallet::new();
+        blockchain.ledger.set_balance(&wallet.generate_address(), 100);
+
+        for (nonce, amount) in [(0, 30), (1, 20)] {
+            let mut tx = Transaction::new(wallet.generate_address(), "B".to_string(), amount, 0, nonce);
+            wallet.sign_transaction(&mut tx).unwrap();
+            blockchain.mine_next_block("M", &[tx]).unwrap();
+        }
+
+        let mut light = HeaderChain::new(blockchain.chain[0].header.clone());
+        light.initial_bits = POW_LIMIT_BITS;
+        let headers: Vec<BlockHeader> = blockchain.chain[1..].iter().map(|block| block.header.clone()).collect();
+        light.add_headers(&headers).unwrap();
+        (blockchain, light, wallet)
+    }
+
+    #[test]
+    fn test_sync_headers() {
+        let (blockchain, mut light, _) = synced_pair();
+        assert_eq!(light.height(), 2);
+        assert_eq!(light.tip_hash(), blockchain.tip_hash());
+        assert_eq!(light.tip_work(), blockchain.tip_work());
+        assert!(matches!(light.add_header(blockchain.chain[1].header.clone()), Err(BlockError::AlreadyKnown)));
+
+        // 채굴하지 않은 헤더, 바뀐 헤더, 부모를 모르는 헤더
+        let unmined = Block::new(light.tip_hash(), 3, Vec::new(), 0x0300_0001).unwrap();
+        assert!(matches!(light.add_header(unmined.header), Err(BlockError::InsufficientWork)));
+        let mut tampered = Block::new(light.tip_hash(), 3, Vec::new(), POW_LIMIT_BITS).unwrap();
+        tampered.header.timestamp = light.tip().timestamp + 1;
+        tampered.mine_block().unwrap();
+        tampered.header.timestamp += 1;
+        assert!(matches!(light.add_header(tampered.header), Err(BlockError::InvalidHash)));
+        let orphan = Block::new(Hash256::digest(b"unknown"), 3, Vec::new(), POW_LIMIT_BITS).unwrap();
+        assert!(matches!(light.add_header(orphan.header), Err(BlockError::UnknownParent)));
+        assert_eq!(light.height(), 2);
+    }
+
+    #[test]
+    fn test_verify_transaction_and_balance() {
+        let (blockchain, light, wallet) = synced_pair();
+        let first = &blockchain.chain[1];
+        let tip = blockchain.chain.last().unwrap();
+
+        // 첫 블록의 송금은 coinbase 다음, 1번 잎
+        let txid = first.transactions[1].calculate_hash().unwrap();
+        let proof = first.merkle_tree().unwrap().proof(1).unwrap();
+        assert_eq!(light.verify_transaction(&first.header.block_hash, &txid, &proof), Ok(2));
+        assert_eq!(light.verify_transaction(&tip.header.block_hash, &txid, &proof), Err(SpvError::InvalidProof));
+        let coinbase = first.transactions[0].calculate_hash().unwrap();
+        assert_eq!(light.verify_transaction(&first.header.block_hash, &coinbase, &proof), Err(SpvError::InvalidProof));
+        assert_eq!(light.verify_transaction(&Hash256::ZERO, &txid, &proof), Err(SpvError::UnknownHeader));
+
+        let proof = blockchain.ledger.prove_account("B");
+        assert_eq!(light.verify_balance(&tip.header.block_hash, &proof), Ok(50));
+        // 이전 블록의 상태 루트와는 맞지 않는다.
+        assert_eq!(light.verify_balance(&first.header.block_hash, &proof), Err(SpvError::InvalidProof));
+        let mut inflated = proof.clone();
+        inflated.account.as_mut().unwrap().balance = 1000;
+        assert_eq!(light.verify_balance(&tip.header.block_hash, &inflated), Err(SpvError::InvalidProof));
+
+        let absent = blockchain.ledger.prove_account("nobody");
+        assert_eq!(light.verify_balance(&tip.header.block_hash, &absent), Ok(0));
+        assert_eq!(light.verify_balance(&tip.header.block_hash, &blockchain.ledger.prove_account(&wallet.generate_address())), Ok(50));
+    }
+
+    #[test]
+    fn test_switch_to_heavier_branch() {
+        let (blockchain, mut light, _) = synced_pair();
+        let first = &blockchain.chain[1];
+        let txid = first.transactions[1].calculate_hash().unwrap();
+        let proof = first.merkle_tree().unwrap().proof(1).unwrap();
+
+        // 제네시스에서 갈라져 더 길게 자란 빈 블록 브랜치
+        let mut parent = blockchain.chain[0].header.clone();
+        let mut branch = Vec::new();
+        for height in 1..=3 {
+            let mut block = Block::new(parent.block_hash, height, Vec::new(), POW_LIMIT_BITS).unwrap();
+            block.header.timestamp = parent.timestamp + 1;
+            block.mine_block().unwrap();
+            parent = block.header.clone();
+            branch.push(block.header);
+        }
+        light.add_headers(&branch[..2]).unwrap();
+        assert_eq!(light.tip_hash(), blockchain.tip_hash());
+        light.add_headers(&branch).unwrap();
+
+        assert_eq!(light.tip_hash(), parent.block_hash);
+        assert_eq!(light.height(), 3);
+        assert_eq!(light.header_at(1).unwrap().block_hash, branch[0].block_hash);
+        assert_eq!(light.confirmations(&first.header.block_hash), None);
+        assert_eq!(light.verify_transaction(&first.header.block_hash, &txid, &proof), Err(SpvError::NotInBestChain));
+    }
+}
@@ -1,97 +1,308 @@
 #![allow(unused)]
-use sha2::{Sha256, Digest};
-use crate::transaction::Transaction;
+use std::collections::HashSet;
 
+use crate::{error::TxError, hash::Hash256, transaction::Transaction};
+
+// 잎과 내부 노드를 다른 접두사로 해시해서, 내부 노드 값을 잎으로 내밀 수 없게 한다.
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+// 모든 층을 들고 있어서 증명은 O(log n), 잎 추가/교체는 바뀐 경로만 다시 해시한다.
 #[derive(Debug, Clone)]
 pub struct MerkleTree {
-    pub root: String,
-    pub leaf_nodes: Vec<String>,
+    pub root: Hash256,
+    // levels[0] 이 잎, 마지막 층이 루트 하나 (빈 트리면 잎 층만 있다)
+    levels: Vec<Vec<Hash256>>,
+    // append/update 때 중복을 바로 찾기 위한 잎 집합
+    leaf_set: HashSet<Hash256>,
 }
 
 impl MerkleTree {
     // &[] -> 슬라이스 참조
-    pub fn new(transaction: &[Transaction]) -> MerkleTree {
-        let leaf_nodes = transaction.iter().map(|tx| {
-            Self::hash(&tx.calculate_hash().expect("The transactions is not signed"))
-        }).collect::<Vec<String>>();
+    // 같은 트랜잭션이 두 번 들어 있으면 거부한다. (CVE-2012-2459 처럼 목록을 부풀려도 루트가 같아지는 변조 방지)
+    pub fn new(transaction: &[Transaction]) -> Result<MerkleTree, TxError> {
+        let txids = transaction.iter().map(|tx| tx.calculate_hash()).collect::<Result<Vec<Hash256>, TxError>>()?;
+        if first_duplicate(&txids).is_some() {
+            return Err(TxError::Duplicate);
+        }
+        Ok(MerkleTree::from_leaves(txids.iter().map(Self::hash_leaf).collect()))
+    }
 
-        let root = MerkleTree::calculate_root(&leaf_nodes);
+    pub fn empty() -> MerkleTree {
+        MerkleTree::from_leaves(Vec::new())
+    }
 
-        MerkleTree {
-            root,
-            leaf_nodes
+    pub fn from_leaves(leaf_nodes: Vec<Hash256>) -> MerkleTree {
+        let mut levels = vec![leaf_nodes];
+        while levels.last().unwrap().len() > 1 {
+            levels.push(Self::next_level(levels.last().unwrap()));
         }
+        let root = levels.last().unwrap().first().copied().unwrap_or_else(Self::empty_root);
+        let leaf_set = levels[0].iter().copied().collect();
+        MerkleTree { root, levels, leaf_set }
     }
 
-    pub fn hash(data: &str) -> String{
-        let mut hasher = Sha256::new();
-        hasher.update(data.as_bytes());
-        hex::encode(hasher.finalize())
+    pub fn leaf_nodes(&self) -> &[Hash256] {
+        &self.levels[0]
     }
 
-    pub fn calculate_root(leat_nodes: &[String]) -> String {
-        let mut nodes = leat_nodes.to_vec();
+    pub fn len(&self) -> usize {
+        self.levels[0].len()
+    }
 
-        while nodes.len() > 1 {
-            if nodes.len() % 2 != 0 {
-                nodes.push(nodes.last().unwrap().clone());
-            }
+    pub fn is_empty(&self) -> bool {
+        self.levels[0].is_empty()
+    }
+
+    // 트랜잭션이 들어오는 대로 블록 템플릿의 트리를 키울 때 쓴다.
+    pub fn append(&mut self, transaction: &Transaction) -> Result<(), TxError> {
+        let leaf = Self::hash_leaf(&transaction.calculate_hash()?);
+        if !self.leaf_set.insert(leaf) {
+            return Err(TxError::Duplicate);
+        }
+        self.levels[0].push(leaf);
+        self.rehash_path(self.levels[0].len() - 1);
+        Ok(())
+    }
+
+    // index 가 범위를 벗어나면 패닉
+    pub fn update(&mut self, index: usize, transaction: &Transaction) -> Result<(), TxError> {
+        let leaf = Self::hash_leaf(&transaction.calculate_hash()?);
+        let old = self.levels[0][index];
+        if old == leaf {
+            return Ok(());
+        }
+        if !self.leaf_set.insert(leaf) {
+            return Err(TxError::Duplicate);
+        }
+        self.leaf_set.remove(&old);
+        self.levels[0][index] = leaf;
+        self.rehash_path(index);
+        Ok(())
+    }
 
-            let mut new_level = Vec::new();
-            for i in (0..nodes.len()).step_by(2) {
-                let combined = format!("{}{}", nodes[i], nodes[i + 1]);
-                new_level.push(Self::hash(&combined));
+    // 잎 index 에서 루트까지의 노드만 다시 계산한다. 잎이 늘어나면 각 층의 오른쪽 끝이 자란다.
+    fn rehash_path(&mut self, mut index: usize) {
+        let mut level = 0;
+        while self.levels[level].len() > 1 {
+            let nodes = &self.levels[level];
+            let parent = index / 2;
+            let node = match nodes.get(2 * parent + 1) {
+                Some(right) => Self::hash_pair(&nodes[2 * parent], right),
+                None => nodes[2 * parent],
+            };
+            if self.levels.len() == level + 1 {
+                self.levels.push(Vec::new());
             }
-            nodes = new_level;
+            let upper = &mut self.levels[level + 1];
+            if parent < upper.len() {
+                upper[parent] = node;
+            } else {
+                upper.push(node);
+            }
+            index = parent;
+            level += 1;
         }
-        nodes[0].clone()
+        self.root = self.levels[level].first().copied().unwrap_or_else(Self::empty_root);
     }
 
-    pub fn get_merkle_path(&self, tx_index: usize) -> Vec<String> {
-        let mut path = Vec::new();
-        let mut index = tx_index;
-        let mut nodes = self.leaf_nodes.clone();
+    pub fn hash_leaf(txid: &Hash256) -> Hash256 {
+        let mut data = [0u8; 33];
+        data[0] = LEAF_PREFIX;
+        data[1..].copy_from_slice(txid.as_bytes());
+        Hash256::digest(&data)
+    }
 
+    pub fn hash_pair(left: &Hash256, right: &Hash256) -> Hash256 {
+        let mut data = [0u8; 65];
+        data[0] = NODE_PREFIX;
+        data[1..33].copy_from_slice(left.as_bytes());
+        data[33..].copy_from_slice(right.as_bytes());
+        Hash256::digest(&data)
+    }
+
+    // 트랜잭션이 없는 블록(제네시스 등)의 루트는 빈 입력의 해시
+    pub fn empty_root() -> Hash256 {
+        Hash256::digest(&[])
+    }
+
+    // 홀수 개인 층의 마지막 노드는 복제하지 않고 그대로 위 층으로 올린다.
+    fn next_level(nodes: &[Hash256]) -> Vec<Hash256> {
+        nodes.chunks(2).map(|pair| match pair {
+            [left, right] => Self::hash_pair(left, right),
+            [odd] => *odd,
+            _ => unreachable!(),
+        }).collect()
+    }
+
+    pub fn calculate_root(leat_nodes: &[Hash256]) -> Hash256 {
+        if leat_nodes.is_empty() {
+            return Self::empty_root();
+        }
+        let mut nodes = leat_nodes.to_vec();
         while nodes.len() > 1 {
-            if nodes.len() % 2 != 0 {
-                nodes.push(nodes.last().unwrap().clone());
+            nodes = Self::next_level(&nodes);
+        }
+        nodes[0]
+    }
+
+    // 위로 올려진 노드는 형제가 없으므로 경로에 들어가지 않는다.
+    pub fn proof(&self, tx_index: usize) -> Option<MerkleProof> {
+        let leaf = *self.levels[0].get(tx_index)?;
+        let mut siblings = Vec::new();
+        let mut index = tx_index;
+        for level in self.levels.iter().take_while(|level| level.len() > 1) {
+            if let Some(sibling) = level.get(index ^ 1) {
+                siblings.push(*sibling);
             }
+            index /= 2;
+        }
+        Some(MerkleProof { leaf, index: tx_index as u64, siblings, tree_size: self.len() as u64 })
+    }
 
-            let sibling_index = if index % 2 == 0 { index + 1 } else { index - 1 };
-            path.push(nodes[sibling_index].clone());
+    // 여러 잎을 한 번에 증명한다. 증명할 잎끼리 형제이거나 이미 계산할 수 있는 노드는 넣지 않는다.
+    pub fn multi_proof(&self, tx_indices: &[usize]) -> Option<MultiProof> {
+        let mut indices: Vec<usize> = tx_indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        if indices.is_empty() {
+            return None;
+        }
+        let leaves = indices.iter()
+            .map(|index| self.levels[0].get(*index).map(|leaf| (*index as u64, *leaf)))
+            .collect::<Option<Vec<_>>>()?;
 
-            index /= 2;
-            nodes = nodes.chunks(2).map(|chunk| {
-                let combined = format!("{}{}", chunk[0], chunk[1]);
-                Self::hash(&combined)
-            }).collect();
+        let mut siblings = Vec::new();
+        for level in self.levels.iter().take_while(|level| level.len() > 1) {
+            for (i, index) in indices.iter().enumerate() {
+                let sibling = index ^ 1;
+                let known = (i > 0 && indices[i - 1] == sibling) || indices.get(i + 1) == Some(&sibling);
+                if !known && sibling < level.len() {
+                    siblings.push(level[sibling]);
+                }
+            }
+            indices = indices.iter().map(|index| index / 2).collect();
+            indices.dedup();
         }
+        Some(MultiProof { tree_size: self.len() as u64, leaves, siblings })
+    }
 
-        path
+    pub fn get_merkle_path(&self, tx_index: usize) -> Vec<Hash256> {
+        self.proof(tx_index).map(|proof| proof.siblings).unwrap_or_default()
     }
 
-    pub fn verify_transaction(&self, transaction: &Transaction, tx_index: usize, path: Vec<String>) -> bool {
-        let mut hash = MerkleTree::hash(&transaction.calculate_hash().expect("The transactions is not signed"));
-        let mut index = tx_index;
+    pub fn verify_transaction(&self, transaction: &Transaction, tx_index: usize, path: Vec<Hash256>) -> bool {
+        let Ok(transaction_hash) = transaction.calculate_hash() else {
+            return false;
+        };
+        let proof = MerkleProof {
+            leaf: MerkleTree::hash_leaf(&transaction_hash),
+            index: tx_index as u64,
+            siblings: path,
+            tree_size: self.len() as u64,
+        };
+        verify(&self.root, &proof)
+    }
+}
 
-        for sibling_hash in path {
-            if index % 2 == 0 {
-                hash = Self::hash(&format!("{}{}", hash, sibling_hash));
-            } else {
-                hash = Self::hash(&format!("{}{}", sibling_hash, hash));
-            }
-            index /= 2;
+// 트리 없이 루트만으로 확인할 수 있는 포함 증명. leaf 는 MerkleTree::hash_leaf(txid)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub leaf: Hash256,
+    pub index: u64,
+    pub siblings: Vec<Hash256>,
+    pub tree_size: u64,
+}
+
+impl MerkleProof {
+    pub fn proves(&self, txid: &Hash256) -> bool {
+        self.leaf == MerkleTree::hash_leaf(txid)
+    }
+}
+
+pub fn verify(root: &Hash256, proof: &MerkleProof) -> bool {
+    if proof.index >= proof.tree_size {
+        return false;
+    }
+    let mut hash = proof.leaf;
+    let mut index = proof.index;
+    let mut size = proof.tree_size;
+    let mut siblings = proof.siblings.iter();
+
+    while size > 1 {
+        if !index.is_multiple_of(2) {
+            let Some(sibling) = siblings.next() else { return false };
+            hash = MerkleTree::hash_pair(sibling, &hash);
+        } else if index + 1 < size {
+            let Some(sibling) = siblings.next() else { return false };
+            hash = MerkleTree::hash_pair(&hash, sibling);
         }
+        index /= 2;
+        size = size.div_ceil(2);
+    }
 
-        println!("{:?} {:?}",hash, self.root);
-        hash == self.root
+    siblings.next().is_none() && hash == *root
+}
+
+// 여러 잎의 포함 증명. 공유하는 형제 노드는 한 번만 들어간다.
+// siblings 는 아래 층부터, 같은 층 안에서는 왼쪽부터
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiProof {
+    pub tree_size: u64,
+    // (잎 위치, 잎 해시), 위치 오름차순
+    pub leaves: Vec<(u64, Hash256)>,
+    pub siblings: Vec<Hash256>,
+}
+
+pub fn verify_multi(root: &Hash256, proof: &MultiProof) -> bool {
+    if proof.leaves.is_empty()
+        || proof.leaves.windows(2).any(|pair| pair[0].0 >= pair[1].0)
+        || proof.leaves.last().unwrap().0 >= proof.tree_size {
+        return false;
+    }
+    let mut nodes = proof.leaves.clone();
+    let mut size = proof.tree_size;
+    let mut siblings = proof.siblings.iter();
+
+    while size > 1 {
+        let mut parents = Vec::new();
+        let mut i = 0;
+        while i < nodes.len() {
+            let (index, hash) = nodes[i];
+            let parent = if !index.is_multiple_of(2) {
+                let Some(sibling) = siblings.next() else { return false };
+                MerkleTree::hash_pair(sibling, &hash)
+            } else if nodes.get(i + 1).is_some_and(|next| next.0 == index + 1) {
+                i += 1;
+                MerkleTree::hash_pair(&hash, &nodes[i].1)
+            } else if index + 1 < size {
+                let Some(sibling) = siblings.next() else { return false };
+                MerkleTree::hash_pair(&hash, sibling)
+            } else {
+                hash
+            };
+            parents.push((index / 2, parent));
+            i += 1;
+        }
+        nodes = parents;
+        size = size.div_ceil(2);
     }
+
+    siblings.next().is_none() && nodes[0].1 == *root
+}
+
+// 처음으로 앞에서 이미 나온 txid 의 위치
+pub fn first_duplicate(txids: &[Hash256]) -> Option<usize> {
+    let mut seen = HashSet::new();
+    txids.iter().position(|txid| !seen.insert(*txid))
 }
 
 #[cfg(test)]
 mod test {
     use p256::{ecdsa::{SigningKey, VerifyingKey}, elliptic_curve::rand_core::OsRng};
 
+    use crate::wallet::Wallet;
+
     use super::*;
 
     #[test]
@@ -100,16 +311,167 @@ mod test {
         let private_key = SigningKey::random(&mut OsRng);
         let _public_key = VerifyingKey::from(&private_key);
         
-        let mut tx1 = Transaction::new(String::from("A"), String::from("B"), 100, 0);
-        let mut tx2 = Transaction::new(String::from("C"), String::from("D"), 10, 0);
-        let mut tx3 = Transaction::new(String::from("E"), String::from("F"), 1, 0);
+        let wallet = Wallet::new();
+        let mut tx1 = Transaction::new(String::from("A"), String::from("B"), 100, 0, 0);
+        let mut tx2 = Transaction::new(String::from("C"), String::from("D"), 10, 0, 0);
+        let mut tx3 = Transaction::new(String::from("E"), String::from("F"), 1, 0, 0);
+        wallet.sign_transaction(&mut tx1).unwrap();
+        wallet.sign_transaction(&mut tx2).unwrap();
 
         let transactions = vec![tx1.clone(), tx2];
 
-        let merkle_tree = MerkleTree::new(&transactions);
+        let merkle_tree = MerkleTree::new(&transactions).unwrap();
         let merkle_path = merkle_tree.get_merkle_path(0);
 
         assert!(merkle_tree.verify_transaction(&tx1, 0, merkle_path));
+        assert_eq!(MerkleTree::new(&[tx3]).unwrap_err(), TxError::Unsigned);
         
     }
-}
\ No newline at end of file
+
+    fn signed_transactions(count: u64) -> Vec<Transaction> {
+        let wallet = Wallet::new();
+        (0..count).map(|nonce| {
+            let mut tx = Transaction::new(wallet.generate_address(), String::from("B"), 1, 0, nonce);
+            wallet.sign_transaction(&mut tx).unwrap();
+            tx
+        }).collect()
+    }
+
+    #[test]
+    fn test_odd_levels_are_promoted() {
+        let transactions = signed_transactions(5);
+        let merkle_tree = MerkleTree::new(&transactions).unwrap();
+
+        for (i, tx) in transactions.iter().enumerate() {
+            let path = merkle_tree.get_merkle_path(i);
+            assert!(merkle_tree.verify_transaction(tx, i, path.clone()));
+            assert!(!merkle_tree.verify_transaction(tx, (i + 1) % 5, path));
+        }
+        // 마지막 잎은 두 번 올라가서 형제가 하나뿐이다.
+        assert_eq!(merkle_tree.get_merkle_path(4).len(), 1);
+
+        assert_eq!(MerkleTree::calculate_root(&merkle_tree.leaf_nodes()[..1]), merkle_tree.leaf_nodes()[0]);
+        assert_eq!(MerkleTree::calculate_root(&[]).to_string(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    #[test]
+    fn test_reject_duplicate_mutation() {
+        let transactions = signed_transactions(3);
+        let merkle_tree = MerkleTree::new(&transactions).unwrap();
+
+        // 마지막 트랜잭션을 한 번 더 넣은 목록은 거부되고, 잎을 직접 늘려도 루트가 같아지지 않는다.
+        let mut mutated = transactions.clone();
+        mutated.push(transactions[2].clone());
+        assert_eq!(MerkleTree::new(&mutated).unwrap_err(), TxError::Duplicate);
+
+        let mut leaves = merkle_tree.leaf_nodes().to_vec();
+        leaves.push(leaves[2]);
+        assert_ne!(MerkleTree::calculate_root(&leaves), merkle_tree.root);
+
+        // 내부 노드 값을 txid 로 내밀어도 잎 접두사 때문에 같은 루트가 나오지 않는다.
+        let inner = MerkleTree::hash_pair(&merkle_tree.leaf_nodes()[0], &merkle_tree.leaf_nodes()[1]);
+        assert_eq!(MerkleTree::calculate_root(&[inner, merkle_tree.leaf_nodes()[2]]), merkle_tree.root);
+        assert_ne!(MerkleTree::calculate_root(&[MerkleTree::hash_leaf(&inner), merkle_tree.leaf_nodes()[2]]), merkle_tree.root);
+    }
+
+    #[test]
+    fn test_standalone_proof() {
+        let transactions = signed_transactions(7);
+        let merkle_tree = MerkleTree::new(&transactions).unwrap();
+        let root = merkle_tree.root;
+
+        for (i, tx) in transactions.iter().enumerate() {
+            let proof = merkle_tree.proof(i).unwrap();
+            assert!(proof.proves(&tx.calculate_hash().unwrap()));
+            assert!(verify(&root, &proof));
+
+            let mut wrong_index = proof.clone();
+            wrong_index.index = (wrong_index.index + 1) % 7;
+            assert!(!verify(&root, &wrong_index));
+        }
+        assert!(merkle_tree.proof(7).is_none());
+
+        // 마지막 잎은 올려진 노드라서 트리 크기가 다르면 경로 모양이 달라진다.
+        let mut wrong_size = merkle_tree.proof(6).unwrap();
+        wrong_size.tree_size += 1;
+        assert!(!verify(&root, &wrong_size));
+
+        let mut extra = merkle_tree.proof(0).unwrap();
+        extra.siblings.push(root);
+        assert!(!verify(&root, &extra));
+    }
+
+    #[test]
+    fn test_multi_proof() {
+        for size in 1..=9u64 {
+            let transactions = signed_transactions(size);
+            let merkle_tree = MerkleTree::new(&transactions).unwrap();
+
+            // 모든 부분집합
+            for mask in 1..(1u32 << size) {
+                let indices: Vec<usize> = (0..size as usize).filter(|i| mask & (1 << i) != 0).collect();
+                let proof = merkle_tree.multi_proof(&indices).unwrap();
+                assert!(verify_multi(&merkle_tree.root, &proof), "size {} indices {:?}", size, indices);
+
+                let single_total: usize = indices.iter().map(|i| merkle_tree.proof(*i).unwrap().siblings.len()).sum();
+                assert!(proof.siblings.len() <= single_total);
+            }
+        }
+
+        let transactions = signed_transactions(6);
+        let merkle_tree = MerkleTree::new(&transactions).unwrap();
+        let proof = merkle_tree.multi_proof(&[0, 1, 4]).unwrap();
+        // 0 과 1 은 서로 형제라서 형제 노드를 따로 넣지 않는다.
+        assert_eq!(proof.siblings.len(), 2);
+
+        let mut tampered = proof.clone();
+        tampered.leaves[2].1 = tampered.leaves[0].1;
+        assert!(!verify_multi(&merkle_tree.root, &tampered));
+        let mut unordered = proof.clone();
+        unordered.leaves.swap(0, 1);
+        assert!(!verify_multi(&merkle_tree.root, &unordered));
+        assert!(merkle_tree.multi_proof(&[]).is_none());
+        assert!(merkle_tree.multi_proof(&[6]).is_none());
+    }
+
+    #[test]
+    fn test_incremental_append_and_update() {
+        let transactions = signed_transactions(12);
+        let mut merkle_tree = MerkleTree::empty();
+        assert_eq!(merkle_tree.root, MerkleTree::empty_root());
+
+        // 하나씩 붙인 트리는 매번 처음부터 만든 트리와 같아야 한다.
+        for (i, tx) in transactions.iter().enumerate() {
+            merkle_tree.append(tx).unwrap();
+            let rebuilt = MerkleTree::new(&transactions[..=i]).unwrap();
+            assert_eq!(merkle_tree.root, rebuilt.root);
+            for j in 0..=i {
+                assert_eq!(merkle_tree.proof(j), rebuilt.proof(j));
+            }
+        }
+        assert_eq!(merkle_tree.append(&transactions[3]).unwrap_err(), TxError::Duplicate);
+
+        let replacement = signed_transactions(1).remove(0);
+        merkle_tree.update(5, &replacement).unwrap();
+        let mut replaced = transactions.clone();
+        replaced[5] = replacement;
+        assert_eq!(merkle_tree.root, MerkleTree::new(&replaced).unwrap().root);
+        assert!(merkle_tree.verify_transaction(&replaced[5], 5, merkle_tree.get_merkle_path(5)));
+        assert_eq!(merkle_tree.update(0, &replaced[1]).unwrap_err(), TxError::Duplicate);
+        // 자기 자리에 같은 트랜잭션을 다시 넣는 것은 괜찮다.
+        merkle_tree.update(1, &replaced[1]).unwrap();
+        // 교체되어 빠진 트랜잭션은 다시 붙일 수 있다.
+        merkle_tree.append(&transactions[5]).unwrap();
+    }
+
+    #[test]
+    fn test_proofs_for_large_tree() {
+        let leaves: Vec<Hash256> = (0..1000u32).map(|i| MerkleTree::hash_leaf(&Hash256::digest(&i.to_le_bytes()))).collect();
+        let merkle_tree = MerkleTree::from_leaves(leaves);
+        for i in 0..merkle_tree.len() {
+            let proof = merkle_tree.proof(i).unwrap();
+            assert!(proof.siblings.len() <= 10);
+            assert!(verify(&merkle_tree.root, &proof));
+        }
+    }
+}
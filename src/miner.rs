@@ -0,0 +1,206 @@
+#![allow(unused)]
+use std::{sync::{atomic::{AtomicBool, AtomicU64, Ordering}, mpsc, Arc, OnceLock}, thread, time::{Duration, Instant}};
+
+use crate::{blockchain::{Block, BlockHeader}, pow::{self, U256}};
+
+// 채굴을 멈추라는 신호. 복제해서 다른 스레드에 넘길 수 있다.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MiningReport {
+    // 취소됐거나 bits 가 잘못됐으면 None
+    pub block: Option<Block>,
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningReport {
+    // 초당 해시 수
+    pub fn hashrate(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 { self.hashes as f64 / seconds } else { 0.0 }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Miner {
+    pub threads: usize,
+    // 한 타임스탬프에서 훑을 nonce 개수. 다 쓰면 타임스탬프를 1 올려서 다시 훑는다.
+    pub nonce_space: u64,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Miner {
+        Miner { threads: threads.max(1), nonce_space: u64::MAX }
+    }
+
+    // nonce 공간을 스레드 수만큼 연속 구간으로 나눠서 동시에 찾는다.
+    pub fn mine(&self, mut block: Block, cancel: &CancelToken) -> MiningReport {
+        let start = Instant::now();
+        let hashes = AtomicU64::new(0);
+        let report = |block, hashes: &AtomicU64| MiningReport { block, hashes: hashes.load(Ordering::Relaxed), elapsed: start.elapsed() };
+
+        let Some(target) = pow::target_from_bits(block.header.bits).filter(|target| !target.is_zero() && *target <= pow::pow_limit()) else {
+            return report(None, &hashes);
+        };
+
+        let threads = self.threads.max(1) as u64;
+        // 처음에는 헤더에 들어 있던 nonce 부터 훑는다.
+        let mut first = block.header.nonce.min(self.nonce_space);
+        loop {
+            let chunk = (self.nonce_space - first).div_ceil(threads).max(1);
+            // nonce 앞부분은 한 번만 해시해 두고 워커마다 복사해서 쓴다.
+            let prefix = block.header.prefix_hasher();
+            let found = OnceLock::new();
+            thread::scope(|scope| {
+                for worker in 0..threads {
+                    let (prefix, found, hashes) = (&prefix, &found, &hashes);
+                    let start = first.saturating_add(worker.saturating_mul(chunk)).min(self.nonce_space);
+                    let end = start.saturating_add(chunk).min(self.nonce_space);
+                    scope.spawn(move || {
+                        let mut tried = 0;
+                        for nonce in start..end {
+                            if found.get().is_some() || cancel.is_cancelled() {
+                                break;
+                            }
+                            tried += 1;
+                            if U256::from_be_bytes(BlockHeader::hash_with_nonce(prefix, nonce).0) <= target {
+                                let _ = found.set(nonce);
+                                break;
+                            }
+                        }
+                        hashes.fetch_add(tried, Ordering::Relaxed);
+                    });
+                }
+            });
+
+            if let Some(nonce) = found.get() {
+                block.header.nonce = *nonce;
+                block.header.block_hash = block.header.calculate_hash();
+                let report = report(Some(block), &hashes);
+                println!("블록이 성공적으로 채굴되었습니다. Nonce: {}, Hash: {}, {:.0} H/s",
+                    nonce, report.block.as_ref().unwrap().header.block_hash, report.hashrate());
+                return report;
+            }
+            if cancel.is_cancelled() {
+                return report(None, &hashes);
+            }
+            // nonce 공간을 다 썼다. 타임스탬프를 바꾸면 새 해시 공간이 생긴다.
+            block.header.timestamp += 1;
+            first = 0;
+        }
+    }
+
+    // 백그라운드 스레드에서 채굴한다. 새 블록이나 트랜잭션이 오면 취소하고 다시 시작하면 된다.
+    pub fn spawn(&self, block: Block) -> MiningJob {
+        let cancel = CancelToken::new();
+        let (sender, result) = mpsc::channel();
+        self.spawn_with(block, cancel.clone(), move |report| {
+            let _ = sender.send(report);
+        });
+        MiningJob { cancel, result }
+    }
+
+    // spawn 과 같지만 결과를 done 에 넘긴다. 여러 작업의 결과를 한 곳에서 받을 때 쓴다.
+    pub fn spawn_with(&self, block: Block, cancel: CancelToken, done: impl FnOnce(MiningReport) + Send + 'static) {
+        let miner = self.clone();
+        thread::spawn(move || done(miner.mine(block, &cancel)));
+    }
+}
+
+impl Default for Miner {
+    fn default() -> Miner {
+        Miner::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+pub struct MiningJob {
+    cancel: CancelToken,
+    result: mpsc::Receiver<MiningReport>,
+}
+
+// 작업을 버리면 워커도 멈춘다.
+impl Drop for MiningJob {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+impl MiningJob {
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    // 아직 끝나지 않았으면 None
+    pub fn try_result(&self) -> Option<MiningReport> {
+        self.result.try_recv().ok()
+    }
+
+    pub fn wait(self) -> Option<MiningReport> {
+        self.result.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{hash::Hash256, pow::POW_LIMIT_BITS, transaction::Transaction};
+
+    fn block_with_bits(bits: u32) -> Block {
+        Block::new(Hash256::ZERO, 1, vec![Transaction::new_coinbase(String::from("M"), 50)], bits).unwrap()
+    }
+
+    #[test]
+    fn test_mine_with_threads() {
+        let report = Miner::new(4).mine(block_with_bits(0x1f10_0000), &CancelToken::new());
+        let block = report.block.clone().unwrap();
+
+        assert!(block.header.meets_target());
+        assert_eq!(block.header.block_hash, block.header.calculate_hash());
+        assert!(report.hashes > 0);
+        assert!(report.hashrate() > 0.0);
+    }
+
+    #[test]
+    fn test_cancel_mining() {
+        // 목표값 1 은 사실상 찾을 수 없다.
+        let job = Miner::new(2).spawn(block_with_bits(0x0300_0001));
+        thread::sleep(Duration::from_millis(50));
+        job.cancel();
+
+        let report = job.wait().unwrap();
+        assert!(report.block.is_none());
+        assert!(report.hashes > 0);
+    }
+
+    #[test]
+    fn test_roll_timestamp_when_nonces_run_out() {
+        let block = block_with_bits(0x2000_ffff);
+        let first_try = BlockHeader::hash_with_nonce(&block.header.prefix_hasher(), 0);
+        let timestamp = block.header.timestamp;
+        let miner = Miner { threads: 2, nonce_space: 1 };
+
+        let mined = miner.mine(block, &CancelToken::new()).block.unwrap();
+        assert_eq!(mined.header.nonce, 0);
+        assert!(mined.header.meets_target());
+        // 처음 타임스탬프로 안 풀렸으면 타임스탬프를 굴려서 찾았어야 한다.
+        if U256::from_be_bytes(first_try.0) > pow::target_from_bits(0x2000_ffff).unwrap() {
+            assert!(mined.header.timestamp > timestamp);
+        }
+    }
+}
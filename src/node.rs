@@ -1,108 +1,1152 @@
 #![allow(unused)]
 
-use std::{io::{Read, Write}, net::{SocketAddr, TcpListener, TcpStream}, thread};
+use std::{cmp::Reverse, collections::{hash_map::Entry, HashMap}, net::SocketAddr, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, RwLock, Weak}, time::Duration};
 
-use crate::{blockchain::BlockChain, transaction::TransactionPool};
+use tokio::{io::AsyncWriteExt, net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpListener, TcpStream}, sync::{mpsc, watch, Notify}, task::JoinSet, time::timeout};
 
+use crate::{blockchain::{Block, BlockChain, BlockStatus}, error::{BlockError, NetError, TxError}, hash::Hash256, ledger::Ledger, light_client::HeaderChain, miner::{CancelToken, Miner, MiningReport}, peer::{self, Peer, HANDSHAKE_TIMEOUT, OUTBOUND_QUEUE_SIZE}, protocol::{read_message_async, write_message_async, GetHeaders, GetProof, InvKind, Inventory, Message, VersionMessage, CAP_FULL_BLOCKS, CAP_RELAY, MAINNET, MAX_HEADERS, PROTOCOL_VERSION}, sync::{self, SyncReport}, transaction::{Transaction, TransactionPool}};
+
+// 블록 하나에 넣을 최대 트랜잭션 수 (coinbase 제외)
+const MAX_BLOCK_TRANSACTIONS: usize = 100;
+// 이 시간 동안 아무 메시지도 오지 않으면 끊는다. 보낼 것이 없는 쪽은 PING_INTERVAL 마다 ping 을 보낸다.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(90);
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+// 메시지 하나를 보내는 데 이보다 오래 걸리면 끊는다.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(20);
+
+// 연결마다 읽는 task 와 쓰는 task 가 하나씩 돈다. 여러 task 가 Arc<Node> 를 같이 쓰므로
+// 상태는 모두 잠금 뒤에 있고, 잠금은 blockchain, mempool, 채굴, peers 순서로만 잡는다.
+// 잠금은 std 잠금이라서, 블록 검증처럼 오래 잡는 처리는 spawn_blocking 으로 tokio 워커 밖에서 한다.
 pub struct Node {
     pub address: SocketAddr,
-    pub connected_nodes: Vec<SocketAddr>,
-    pub blockchain: BlockChain,
-    pub mempool: TransactionPool,
+    pub network: u32,
+    pub capabilities: u64,
+    pub blockchain: Arc<RwLock<BlockChain>>,
+    pub mempool: Arc<Mutex<TransactionPool>>,
+    // handshake 를 마친 연결만 들어간다. 주소마다 연결은 하나뿐이다.
+    pub peers: Mutex<HashMap<SocketAddr, Peer>>,
+    next_connection: AtomicU64,
+    pub miner: Miner,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub ping_interval: Duration,
+    // Some 이면 이 주소로 보상을 받으며 계속 채굴한다.
+    miner_address: Mutex<Option<String>>,
+    // 지금 돌고 있는 채굴 작업. 다시 시작하거나 멈추면 취소한다.
+    mining: Mutex<Option<CancelToken>>,
+    // 채굴 스레드가 결과를 보내는 곳. 받는 쪽은 start_mining 이 띄우는 완료 task 가 가져간다.
+    mined: mpsc::UnboundedSender<(CancelToken, MiningReport)>,
+    mining_reports: Mutex<Option<mpsc::UnboundedReceiver<(CancelToken, MiningReport)>>>,
+    shutdown: watch::Sender<bool>,
+    tasks: Mutex<JoinSet<()>>,
 }
 
 impl Node {
+    // 제네시스만 있는 체인으로 시작한다. 나머지는 connect 후 sync 로 받아온다.
     pub fn new(address: SocketAddr) -> Node {
-        
-        // 서버만 생성, 필요할 때 
-        // 부트스트랩 노드한테서 블록체인 값 받아오기
-        // 노드 생성
+        Node::with_blockchain(address, BlockChain::new())
+    }
 
+    // 다른 노드와 제네시스가 같은 체인이나 BlockChain::open 으로 다시 연 체인
+    pub fn with_blockchain(address: SocketAddr, blockchain: BlockChain) -> Node {
+        let (mined, mining_reports) = mpsc::unbounded_channel();
         Node {
             address,
-            connected_nodes: Vec::<SocketAddr>::new(),
-            blockchain: BlockChain::new(),
-            mempool: TransactionPool::new(),
+            network: MAINNET,
+            capabilities: CAP_FULL_BLOCKS | CAP_RELAY,
+            blockchain: Arc::new(RwLock::new(blockchain)),
+            mempool: Arc::new(Mutex::new(TransactionPool::new())),
+            peers: Mutex::new(HashMap::new()),
+            next_connection: AtomicU64::new(0),
+            miner: Miner::default(),
+            read_timeout: READ_TIMEOUT,
+            write_timeout: WRITE_TIMEOUT,
+            ping_interval: PING_INTERVAL,
+            miner_address: Mutex::new(None),
+            mining: Mutex::new(None),
+            mined,
+            mining_reports: Mutex::new(Some(mining_reports)),
+            shutdown: watch::channel(false).0,
+            tasks: Mutex::new(JoinSet::new()),
         }
     }
 
-    pub fn connect(&mut self, peer_addr: SocketAddr) {
-        if !self.connected_nodes.contains(&peer_addr) {
-            self.connected_nodes.push(peer_addr);
-            println!("Connected to: {}", peer_addr);
+    // handshake 때 보내는 자기소개. 팁이 바뀌면 내용도 바뀐다.
+    pub fn version_message(&self) -> VersionMessage {
+        let blockchain = self.blockchain.read().unwrap();
+        VersionMessage {
+            version: PROTOCOL_VERSION,
+            network: self.network,
+            best_hash: blockchain.tip_hash(),
+            best_height: blockchain.chain.len() as u64 - 1,
+            capabilities: self.capabilities,
         }
     }
-}
 
-fn handle_client(mut stream: TcpStream) {
-    let mut buffer = [0; 512];
+    pub fn is_connected(&self, peer_addr: &SocketAddr) -> bool {
+        self.peers.lock().unwrap().contains_key(peer_addr)
+    }
 
-    loop {
-        match stream.read(&mut buffer) {
-            Ok(size) if size > 0 => {
-                let message = String::from_utf8_lossy(&buffer[..size]);
-                println!("Received: {}", message);
+    // listener 로 들어오는 연결을 받는다. handshake 는 연결마다 따로 돌려서 느린 상대가 다른 연결을 막지 않게 한다.
+    pub fn serve(self: &Arc<Self>, listener: TcpListener) {
+        let node = Arc::clone(self);
+        let mut shutdown = self.shutdown.subscribe();
+        self.spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    _ = shutdown.wait_for(|stop| *stop) => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            println!("Connection failed: {}", e);
+                            continue;
+                        }
+                    },
+                };
+                let peer_node = Arc::clone(&node);
+                node.spawn(async move {
+                    if let Err(e) = peer_node.add_peer(stream).await {
+                        println!("Failed to accept peer: {}", e);
+                    }
+                });
+            }
+        });
+    }
 
-                let response = format!("Message received: {}", message);
-                stream.write(response.as_bytes()).unwrap();
+    // 동시에 같은 주소로 연결하면 먼저 등록한 연결만 남고, 나머지도 이미 연결된 것으로 보고 성공한다.
+    pub async fn connect(self: &Arc<Self>, peer_addr: SocketAddr) -> Result<(), NetError> {
+        if self.is_connected(&peer_addr) {
+            return Ok(());
+        }
+        let stream = timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(peer_addr)).await.map_err(|_| NetError::Timeout)??;
+        match self.add_peer(stream).await {
+            Err(NetError::AlreadyConnected) => Ok(()),
+            result => result,
+        }
+    }
+
+    // handshake 가 끝나야 peer 로 등록하고 읽기/쓰기 task 를 띄운다.
+    // 이미 연결된 주소인지 확인하고 넣는 것은 한 번의 잠금 안에서 한다. 중복이면 이 연결을 닫는다.
+    async fn add_peer(self: &Arc<Self>, mut stream: TcpStream) -> Result<(), NetError> {
+        let address = stream.peer_addr()?;
+        let handshake = peer::handshake(&mut stream, &self.version_message()).await?;
+        if *self.shutdown.borrow() {
+            return Err(NetError::Disconnected);
+        }
+        let (version, best_height) = (handshake.version, handshake.remote.best_height);
+
+        let (reader, writer) = stream.into_split();
+        let (outbound, queue) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+        let closing = Arc::new(Notify::new());
+        let connection = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        match self.peers.lock().unwrap().entry(address) {
+            Entry::Occupied(_) => return Err(NetError::AlreadyConnected),
+            Entry::Vacant(entry) => {
+                entry.insert(Peer::new(address, connection, handshake, outbound, Arc::clone(&closing)));
+            }
+        }
+        println!("Connected to: {} (version {}, height {})", address, version, best_height);
+        self.spawn(Arc::clone(self).read_loop(address, connection, reader, closing));
+        self.spawn(Arc::clone(self).write_loop(address, connection, writer, queue));
+        Ok(())
+    }
+
+    // 연결이 끊기거나, read_timeout 동안 조용하거나, 종료하거나, disconnect 될 때까지 읽는다.
+    async fn read_loop(self: Arc<Self>, address: SocketAddr, connection: u64, mut reader: OwnedReadHalf, closing: Arc<Notify>) {
+        let mut shutdown = self.shutdown.subscribe();
+        loop {
+            let message = tokio::select! {
+                _ = shutdown.wait_for(|stop| *stop) => break,
+                _ = closing.notified() => break,
+                read = timeout(self.read_timeout, read_message_async(&mut reader)) => match read {
+                    Ok(Ok(message)) => message,
+                    Ok(Err(NetError::Disconnected)) => break,
+                    Ok(Err(e)) => {
+                        println!("Dropping {}: {}", address, e);
+                        break;
+                    }
+                    Err(_) => {
+                        println!("Dropping {}: {}", address, NetError::Timeout);
+                        break;
+                    }
+                },
+            };
+            self.dispatch(address, message).await;
+        }
+        self.disconnect_connection(&address, connection);
+    }
+
+    // 큐에 들어온 순서대로 보낸다. peer 가 빠지면 큐가 닫히고, 남은 메시지를 마저 보낸 뒤 연결을 닫는다.
+    async fn write_loop(self: Arc<Self>, address: SocketAddr, connection: u64, mut writer: OwnedWriteHalf, mut queue: mpsc::Receiver<Message>) {
+        let mut nonce = 0;
+        loop {
+            let message = match timeout(self.ping_interval, queue.recv()).await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                // 한동안 보낼 것이 없었으면 살아 있다고 알린다.
+                Err(_) => {
+                    nonce += 1;
+                    Message::Ping(nonce)
+                }
+            };
+            let result = timeout(self.write_timeout, write_message_async(&mut writer, &message)).await
+                .unwrap_or(Err(NetError::Timeout));
+            if let Err(e) = result {
+                println!("Failed to send to {}: {}", address, e);
+                break;
+            }
+        }
+        self.disconnect_connection(&address, connection);
+        let _ = writer.shutdown().await;
+    }
+
+    // sync 나 증명 요청이 기다리는 응답이면 그쪽으로 넘기고, 나머지는 handle_message 가 처리한다.
+    async fn dispatch(self: &Arc<Self>, from: SocketAddr, message: Message) {
+        let responses = match message {
+            Message::Headers(_) | Message::Block(_) | Message::Proof(_) => {
+                self.peers.lock().unwrap().get(&from).and_then(|peer| peer.responses.clone())
+            }
+            _ => None,
+        };
+        let message = match responses {
+            Some(responses) => match responses.send(message).await {
+                Ok(()) => return,
+                Err(returned) => returned.0,
             },
-            Ok(_) => {
-                println!("Client Disconnected.");
+            None => message,
+        };
+        self.handle_blocking(from, message).await;
+    }
+
+    // 블록 검증처럼 오래 걸리고 std 잠금을 잡는 처리는 tokio 워커가 아닌 blocking 스레드에서 한다.
+    // 끝날 때까지 기다리므로 한 peer 의 메시지는 받은 순서대로 처리된다.
+    pub(crate) async fn handle_blocking(self: &Arc<Self>, from: SocketAddr, message: Message) {
+        let node = Arc::clone(self);
+        tokio::task::spawn_blocking(move || node.handle_message(from, message)).await
+            .expect("Message handler panicked.");
+    }
+
+    async fn receive_block_blocking(self: &Arc<Self>, block: Block) -> Result<(), BlockError> {
+        let node = Arc::clone(self);
+        tokio::task::spawn_blocking(move || node.receive_block(&block)).await
+            .expect("Block handler panicked.")
+    }
+
+    // peer 를 빼고 읽는 task 를 깨운다. 보내기 큐가 닫히므로 쓰는 task 도 끝난다.
+    pub fn disconnect(&self, peer_addr: &SocketAddr) {
+        if let Some(peer) = self.peers.lock().unwrap().remove(peer_addr) {
+            peer.close();
+            println!("Disconnected from: {}", peer_addr);
+        }
+    }
+
+    // 연결의 task 가 끝날 때 부른다. 그 사이 같은 주소로 새로 연결했으면 새 연결은 그대로 둔다.
+    fn disconnect_connection(&self, peer_addr: &SocketAddr, connection: u64) {
+        let peer = {
+            let mut peers = self.peers.lock().unwrap();
+            match peers.get(peer_addr) {
+                Some(peer) if peer.connection == connection => peers.remove(peer_addr),
+                _ => None,
+            }
+        };
+        if let Some(peer) = peer {
+            peer.close();
+            println!("Disconnected from: {}", peer_addr);
+        }
+    }
+
+    // 새 연결을 받지 않고 모든 연결을 닫은 뒤, 띄운 task 가 모두 끝날 때까지 기다린다.
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        self.stop_mining();
+        loop {
+            let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+            if tasks.is_empty() {
                 break;
             }
-            Err(e) => {
-                println!("Failed to read from stream: {e}");
+            while tasks.join_next().await.is_some() {}
+        }
+    }
+
+    fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.tasks.lock().unwrap();
+        // 끝난 task 는 여기서 치운다.
+        while tasks.try_join_next().is_some() {}
+        tasks.spawn(task);
+    }
+
+    // 다른 노드의 요청에 보낼 응답. 없는 블록은 건너뛴다.
+    pub fn respond(&self, message: &Message) -> Vec<Message> {
+        match message {
+            Message::Ping(nonce) => vec![Message::Pong(*nonce)],
+            Message::GetHeaders(request) => {
+                let blockchain = self.blockchain.read().unwrap();
+                vec![Message::Headers(blockchain.headers_after(&request.locator, &request.stop, MAX_HEADERS))]
+            }
+            Message::GetData(inventory) => inventory.iter()
+                .filter_map(|item| match item.kind {
+                    InvKind::Tx => self.mempool.lock().unwrap().get(&item.hash).cloned().map(Message::Tx),
+                    InvKind::Block => self.blockchain.read().unwrap().get_block(&item.hash).cloned().map(Message::Block),
+                })
+                .collect(),
+            Message::GetProof(request) => self.blockchain.read().unwrap()
+                .prove_transaction(&request.block_hash, &request.txid)
+                .map(Message::Proof)
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // 읽는 task 가 from 에게서 받은 메시지. 응답과 relay 는 보내기 큐에 넣기만 한다.
+    // 새 트랜잭션과 블록은 inv 로 알려오고, 없는 것만 getdata 로 받아서 검증을 통과하면 다시 알린다.
+    pub fn handle_message(&self, from: SocketAddr, message: Message) {
+        match message {
+            Message::Inv(items) => {
+                let wanted: Vec<Inventory> = items.into_iter()
+                    .filter(|item| {
+                        self.mark_known(from, item.hash);
+                        !self.has_inventory(item)
+                    })
+                    .collect();
+                if !wanted.is_empty() {
+                    self.send_to(from, Message::GetData(wanted));
+                }
+            }
+            Message::Tx(tx) => {
+                let Ok(txid) = tx.calculate_hash() else { return };
+                self.mark_known(from, txid);
+                if self.mempool.lock().unwrap().contains(&txid) {
+                    return;
+                }
+                if let Err(e) = self.add_transaction(tx) {
+                    println!("Rejected transaction {} from {}: {}", txid, from, e);
+                }
+            }
+            Message::Block(block) => {
+                let block_hash = block.header.block_hash;
+                self.mark_known(from, block_hash);
+                if self.blockchain.read().unwrap().get_block(&block_hash).is_some() {
+                    return;
+                }
+                match self.receive_block(&block) {
+                    Ok(()) => self.announce(Inventory { kind: InvKind::Block, hash: block_hash }),
+                    // 그 사이의 블록을 놓쳤다. 헤더부터 받아서 채운다.
+                    Err(BlockError::UnknownParent) => self.request_headers(from),
+                    Err(e) => println!("Rejected block {} from {}: {}", block_hash, from, e),
+                }
+            }
+            // 우리가 보낸 getheaders 의 응답. 없는 블록을 순서대로 요청하면 위의 Block 으로 하나씩 붙는다.
+            Message::Headers(headers) => {
+                let wanted: Vec<Inventory> = headers.iter()
+                    .filter(|header| {
+                        self.mark_known(from, header.block_hash);
+                        self.blockchain.read().unwrap().get_block(&header.block_hash).is_none()
+                    })
+                    .map(|header| Inventory { kind: InvKind::Block, hash: header.block_hash })
+                    .collect();
+                if !wanted.is_empty() {
+                    self.send_to(from, Message::GetData(wanted));
+                }
+            }
+            other => {
+                for reply in self.respond(&other) {
+                    self.send_to(from, reply);
+                }
+            }
+        }
+    }
+
+    fn has_inventory(&self, item: &Inventory) -> bool {
+        match item.kind {
+            InvKind::Tx => self.mempool.lock().unwrap().contains(&item.hash),
+            InvKind::Block => self.blockchain.read().unwrap().get_block(&item.hash).is_some(),
+        }
+    }
+
+    fn mark_known(&self, peer_addr: SocketAddr, hash: Hash256) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&peer_addr) {
+            peer.known.insert(hash);
+        }
+    }
+
+    // 큐가 가득 찬 peer 는 따라오지 못하는 것으로 보고 끊는다.
+    pub(crate) fn send_to(&self, peer_addr: SocketAddr, message: Message) {
+        let result = match self.peers.lock().unwrap().get(&peer_addr) {
+            Some(peer) => peer.send(message),
+            None => return,
+        };
+        if let Err(e) = result {
+            println!("Dropping {}: {}", peer_addr, e);
+            self.disconnect(&peer_addr);
+        }
+    }
+
+    fn request_headers(&self, peer_addr: SocketAddr) {
+        let locator = self.blockchain.read().unwrap().block_locator();
+        self.send_to(peer_addr, Message::GetHeaders(GetHeaders { locator, stop: Hash256::ZERO }));
+    }
+
+    // item 을 아직 모르는 peer 들에게 알린다. 트랜잭션은 relay 하는 peer 에게만 알린다.
+    fn announce(&self, item: Inventory) {
+        let mut failed = Vec::new();
+        for peer in self.peers.lock().unwrap().values_mut() {
+            if item.kind == InvKind::Tx && !peer.has_capability(CAP_RELAY) {
+                continue;
+            }
+            if peer.known.insert(item.hash)
+                && let Err(e) = peer.send(Message::Inv(vec![item])) {
+                println!("Failed to announce to {}: {}", peer.address, e);
+                failed.push(peer.address);
+            }
+        }
+        for peer_addr in failed {
+            self.disconnect(&peer_addr);
+        }
+    }
+
+    // 헤더를 먼저 받아 가장 무거운 체인을 정하고, 없는 블록 본문을 여러 peer 에게서 나눠 받아 높이 순으로 적용한다.
+    // 적용한 블록은 저장소에 남으므로 중간에 멈춰도 다시 열고 sync 하면 거기서부터 이어진다.
+    pub async fn sync(self: &Arc<Self>) -> Result<SyncReport, NetError> {
+        let mut report = SyncReport::default();
+        let node = Arc::clone(self);
+        let mut headers = tokio::task::spawn_blocking(move || node.header_chain()).await
+            .expect("Header chain task panicked.");
+
+        let mut peers: Vec<(SocketAddr, u64, bool)> = self.peers.lock().unwrap().values()
+            .map(|peer| (peer.address, peer.remote.best_height, peer.has_capability(CAP_FULL_BLOCKS)))
+            .collect();
+        peers.sort_by_key(|(_, best_height, _)| Reverse(*best_height));
+        for (address, _, _) in &peers {
+            match sync::download_headers(self, *address, &mut headers).await {
+                Ok(count) => report.headers += count,
+                Err(e) => println!("Header download from {} failed: {}", address, e),
+            }
+        }
+
+        let mut full_nodes: Vec<SocketAddr> = peers.iter()
+            .filter(|(_, _, full_blocks)| *full_blocks)
+            .map(|(address, _, _)| *address)
+            .collect();
+        // 체인이 거부한 블록을 보낸 peer 는 끊고, 그 peer 에게 받은 블록은 남은 peer 들에게서 다시 받는다.
+        let mut blocks = HashMap::new();
+        'download: loop {
+            let missing: Vec<Hash256> = {
+                let blockchain = self.blockchain.read().unwrap();
+                (1..=headers.height())
+                    .map(|height| headers.header_at(height).unwrap().block_hash)
+                    .filter(|block_hash| blockchain.get_block(block_hash).is_none())
+                    .collect()
+            };
+            let wanted: Vec<Hash256> = missing.iter().filter(|block_hash| !blocks.contains_key(*block_hash)).copied().collect();
+            if !wanted.is_empty() {
+                let (received, blocks_by_peer) = sync::download_blocks(self, &full_nodes, &wanted).await;
+                for (address, count) in blocks_by_peer {
+                    *report.blocks_by_peer.entry(address).or_default() += count;
+                }
+                blocks.extend(received);
+            }
+
+            for block_hash in &missing {
+                let Some((from, block)) = blocks.remove(block_hash) else {
+                    println!("Block {} is not available from any peer.", block_hash);
+                    break 'download;
+                };
+                match self.receive_block_blocking(block).await {
+                    Ok(()) => report.blocks += 1,
+                    // 그 사이 relay 로 먼저 받았다.
+                    Err(BlockError::AlreadyKnown) => {}
+                    Err(e @ BlockError::Storage(_)) => return Err(NetError::Rejected(e)),
+                    Err(e) => {
+                        println!("Rejected block {} from {}: {}", block_hash, from, e);
+                        self.disconnect(&from);
+                        full_nodes.retain(|address| *address != from);
+                        blocks.retain(|_, (address, _)| *address != from);
+                        continue 'download;
+                    }
+                }
+            }
+            break;
+        }
+        Ok(report)
+    }
+
+    // 활성 체인의 헤더로 시작하는 헤더 트리. sync 때 이 위에 peer 들의 헤더를 쌓는다.
+    fn header_chain(&self) -> HeaderChain {
+        let blockchain = self.blockchain.read().unwrap();
+        let mut headers = HeaderChain::new(blockchain.chain[0].header.clone());
+        headers.initial_bits = blockchain.initial_bits;
+        headers.block_time = blockchain.block_time;
+        headers.adjustment_interval = blockchain.adjustment_interval;
+        for block in &blockchain.chain[1..] {
+            if headers.add_header(block.header.clone()).is_err() {
                 break;
             }
         }
+        headers
+    }
+
+    pub fn receive_block(&self, block: &Block) -> Result<(), BlockError> {
+        {
+            let mut blockchain = self.blockchain.write().unwrap();
+            let status = blockchain.accept_block(block)?;
+            let mut mempool = self.mempool.lock().unwrap();
+            if let BlockStatus::Reorganized(transactions) = status {
+                mempool.return_transactions(transactions);
+            }
+            mempool.prune(|tx| blockchain.ledger.is_pending(tx));
+        }
+        // 팁이 바뀌었으니 지금 채굴 중인 블록은 쓸모없다.
+        self.restart_mining();
+        Ok(())
     }
-}
 
-// p2p에서는 수신용
-fn start_server(address: SocketAddr) {
-    let listener = TcpListener::bind(address).unwrap();
-    println!("Listening on {}", address);
+    pub fn add_transaction(&self, tx: Transaction) -> Result<(), TxError> {
+        let txid = {
+            let blockchain = self.blockchain.read().unwrap();
+            blockchain.validate_transaction(&tx)?;
+            let txid = tx.calculate_hash()?;
+            self.mempool.lock().unwrap().add_transaction(tx)?;
+            txid
+        };
+        self.announce(Inventory { kind: InvKind::Tx, hash: txid });
+        // 새 트랜잭션도 수수료를 받을 수 있도록 템플릿을 다시 만든다.
+        self.restart_mining();
+        Ok(())
+    }
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                thread::spawn(move ||{
-                    handle_client(stream);
-                });
-            },
+    // 채굴한 블록은 완료 task 가 받아서 체인에 붙이고 알린다. 처음 부를 때 그 task 를 띄운다.
+    pub fn start_mining(self: &Arc<Self>, miner_address: String) {
+        if let Some(reports) = self.mining_reports.lock().unwrap().take() {
+            self.spawn(Node::complete_mining(Arc::downgrade(self), reports, self.shutdown.subscribe()));
+        }
+        *self.miner_address.lock().unwrap() = Some(miner_address);
+        self.restart_mining();
+    }
+
+    pub fn stop_mining(&self) {
+        *self.miner_address.lock().unwrap() = None;
+        if let Some(job) = self.mining.lock().unwrap().take() {
+            job.cancel();
+        }
+    }
+
+    pub fn is_mining(&self) -> bool {
+        self.mining.lock().unwrap().is_some()
+    }
+
+    fn restart_mining(&self) {
+        if let Some(job) = self.mining.lock().unwrap().take() {
+            job.cancel();
+        }
+        let Some(miner_address) = self.miner_address.lock().unwrap().clone() else {
+            return;
+        };
+        let template = {
+            let blockchain = self.blockchain.read().unwrap();
+            let candidates = {
+                let mempool = self.mempool.lock().unwrap();
+                mempool.best_transactions(mempool.len())
+            };
+            // 풀에는 미래 nonce 나 합치면 잔액을 넘는 트랜잭션도 있으므로 블록에 넣을 수 있는 것만 고른다.
+            let transactions = blockchain.mineable_transactions(candidates, MAX_BLOCK_TRANSACTIONS);
+            let transactions = blockchain.with_coinbase(&miner_address, &transactions);
+            blockchain.block_template(&transactions)
+        };
+        let block = match template {
+            Ok(block) => block,
             Err(e) => {
-                println!("Connectiond failed: {}", e);
+                println!("Failed to build block template: {}", e);
+                return;
+            }
+        };
+        let job = CancelToken::new();
+        let (mined, token) = (self.mined.clone(), job.clone());
+        self.miner.spawn_with(block, job.clone(), move |report| {
+            let _ = mined.send((token, report));
+        });
+        // 그 사이 다른 곳에서 시작한 작업이 있으면 그것은 멈춘다.
+        if let Some(previous) = self.mining.lock().unwrap().replace(job) {
+            previous.cancel();
+        }
+    }
+
+    // 채굴 스레드의 결과를 받는다. 찾은 블록은 취소된 작업의 것이라도 체인에 넣어 보고, 붙으면 알린다.
+    // node 가 사라지거나 종료하면 끝난다.
+    async fn complete_mining(node: Weak<Node>, mut reports: mpsc::UnboundedReceiver<(CancelToken, MiningReport)>, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            let (job, report) = tokio::select! {
+                _ = shutdown.wait_for(|stop| *stop) => break,
+                received = reports.recv() => match received {
+                    Some(received) => received,
+                    None => break,
+                },
+            };
+            let Some(node) = node.upgrade() else { break };
+            match report.block {
+                Some(block) => {
+                    let block_hash = block.header.block_hash;
+                    match node.receive_block_blocking(block).await {
+                        Ok(()) => node.announce(Inventory { kind: InvKind::Block, hash: block_hash }),
+                        // 다시 만들어도 같은 템플릿이 나오므로 새 블록이나 트랜잭션이 들어올 때까지 기다린다.
+                        Err(e) => println!("Mined block {} was rejected: {}", block_hash, e),
+                    }
+                }
+                // 취소되지 않았는데 빈손이면 (bits 가 잘못됐거나) 템플릿을 다시 만든다.
+                None if !job.is_cancelled() => node.restart_mining(),
+                None => {}
             }
         }
     }
 }
 
-// p2p에서는 전송용
-fn start_client(address: SocketAddr, message: &str) {
-    match TcpStream::connect(address) {
-        Ok(mut stream) => {
-            stream.write(message.as_bytes()).unwrap();
+// 채굴 스레드는 node 가 사라지면 같이 멈춘다.
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.stop_mining();
+    }
+}
 
-            let mut buf = [0; 512];
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Instant};
 
-            match stream.read(&mut buf) {
-                Ok(size) => {
-                    let response = String::from_utf8_lossy(&buf[..size]);
-                    println!("Server response: {}", response);
-                },
-                Err(e) => {
-                    println!("Failed to receive data: {}", e);
-                }
+    use super::*;
+    use crate::{ledger::AccountLedger, pow::POW_LIMIT_BITS, protocol::TESTNET, wallet::Wallet};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_node_mines_pending_transactions() {
+        let mut node = Node::new("127.0.0.1:0".parse().unwrap());
+        node.blockchain.write().unwrap().initial_bits = POW_LIMIT_BITS;
+        node.miner = Miner::new(2);
+        let node = Arc::new(node);
+
+        // 채굴한 블록은 따로 부르지 않아도 체인에 붙는다.
+        let wallet = Wallet::new();
+        let address = wallet.generate_address();
+        node.start_mining(address.clone());
+        assert!(node.is_mining());
+        let subsidy = node.blockchain.read().unwrap().block_subsidy(1);
+        wait_until(|| node.blockchain.read().unwrap().ledger.balance_of(&address) >= subsidy).await;
+
+        // 트랜잭션이 들어오면 다시 만든 템플릿에 들어가야 한다.
+        let mut tx = Transaction::new(address.clone(), String::from("B"), 10, 1, 0);
+        wallet.sign_transaction(&mut tx).unwrap();
+        node.add_transaction(tx.clone()).unwrap();
+        wait_until(|| node.blockchain.read().unwrap().ledger.balance_of("B") == 10).await;
+        node.stop_mining();
+        assert!(!node.is_mining());
+
+        let blockchain = node.blockchain.read().unwrap();
+        assert!(blockchain.chain.iter().any(|block| block.transactions.iter().any(|included| included.signature == tx.signature)));
+        assert!(node.mempool.lock().unwrap().is_empty());
+    }
+
+    // 같은 제네시스로 시작하는 노드. 테스트에서는 난이도를 가장 낮게 둔다.
+    fn node_with(mut blockchain: BlockChain) -> Node {
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        Node::with_blockchain("127.0.0.1:0".parse().unwrap(), blockchain)
+    }
+
+    fn fresh_node(genesis: &Block) -> Node {
+        node_with(BlockChain::with_genesis(AccountLedger::default(), genesis.clone()))
+    }
+
+    // node 가 연결을 받기 시작하게 하고 접속할 주소를 돌려준다.
+    async fn start_serving(node: Node) -> (Arc<Node>, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let node = Arc::new(node);
+        node.serve(listener);
+        (node, address)
+    }
+
+    // 송금이 하나 들어 있는 blocks 개의 블록
+    fn mine_blocks(blockchain: &mut BlockChain, wallet: &Wallet, blocks: usize) {
+        for _ in 0..blocks {
+            let height = blockchain.chain.len();
+            if height == 2 {
+                let mut tx = Transaction::new(wallet.generate_address(), String::from("B"), 10, 1, 0);
+                wallet.sign_transaction(&mut tx).unwrap();
+                blockchain.mine_next_block("M", &[tx]).unwrap();
+            } else {
+                blockchain.mine_next_block(&wallet.generate_address(), &[]).unwrap();
             }
         }
-        Err(e) => {
-            println!("Falied to connect: {}", e);
+    }
+
+    async fn wait_until(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "condition not reached in time");
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
     }
-}
 
-fn broadcast_message(peers: Vec<SocketAddr>, msg: &str) {
-    for peer in peers {
-        start_client(peer, msg);
+    // node 의 흉내만 내는 연결. handshake 뒤에는 테스트가 직접 읽고 쓴다.
+    async fn raw_peer(address: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let local = Node::new("127.0.0.1:0".parse().unwrap()).version_message();
+        peer::handshake(&mut stream, &local).await.unwrap();
+        stream
     }
-}
\ No newline at end of file
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ping_over_tcp() {
+        let genesis = BlockChain::new().chain[0].clone();
+        let (_, address) = start_serving(fresh_node(&genesis)).await;
+
+        let mut stream = raw_peer(address).await;
+        write_message_async(&mut stream, &Message::Ping(5)).await.unwrap();
+        assert!(matches!(read_message_async(&mut stream).await, Ok(Message::Pong(5))));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_block_handling_does_not_block_workers() {
+        let genesis = BlockChain::new().chain[0].clone();
+        let (node, address) = start_serving(fresh_node(&genesis)).await;
+        let mut sender = raw_peer(address).await;
+        let mut pinger = raw_peer(address).await;
+
+        // 체인을 오래 잡고 있는 동안 블록이 오면, 그 처리가 하나뿐인 워커를 막으면 안 된다.
+        let blockchain = Arc::clone(&node.blockchain);
+        let (locked, wait_locked) = std::sync::mpsc::channel();
+        let holder = thread::spawn(move || {
+            let _guard = blockchain.write().unwrap();
+            locked.send(()).unwrap();
+            thread::sleep(Duration::from_secs(2));
+        });
+        wait_locked.recv().unwrap();
+
+        let mut block = Block::new(genesis.header.block_hash, 1, Vec::new(), POW_LIMIT_BITS).unwrap();
+        block.mine_block().unwrap();
+        write_message_async(&mut sender, &Message::Block(block)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        write_message_async(&mut pinger, &Message::Ping(7)).await.unwrap();
+        let pong = timeout(Duration::from_secs(1), read_message_async(&mut pinger)).await;
+        assert!(matches!(pong, Ok(Ok(Message::Pong(7)))));
+        holder.join().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connect_after_handshake() {
+        let server = Node::new("127.0.0.1:0".parse().unwrap());
+        server.blockchain.write().unwrap().initial_bits = POW_LIMIT_BITS;
+        server.blockchain.write().unwrap().mine_next_block("M", &[]).unwrap();
+        let server_tip = server.blockchain.read().unwrap().tip_hash();
+        let (server, address) = start_serving(server).await;
+
+        let mut client = Node::new("127.0.0.1:0".parse().unwrap());
+        client.capabilities = CAP_RELAY;
+        let client = Arc::new(client);
+        client.connect(address).await.unwrap();
+        assert!(client.is_connected(&address));
+        // 이미 연결된 주소는 다시 handshake 하지 않는다.
+        client.connect(address).await.unwrap();
+        {
+            let peers = client.peers.lock().unwrap();
+            assert_eq!(peers.len(), 1);
+            assert_eq!(peers[&address].remote.best_height, 1);
+            assert_eq!(peers[&address].remote.best_hash, server_tip);
+            assert!(peers[&address].has_capability(CAP_FULL_BLOCKS));
+        }
+
+        wait_until(|| server.peers.lock().unwrap().len() == 1).await;
+        let peers = server.peers.lock().unwrap();
+        let peer = peers.values().next().unwrap();
+        assert_eq!(peer.remote.best_height, 0);
+        assert!(!peer.has_capability(CAP_FULL_BLOCKS));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_duplicate_connections() {
+        let genesis = BlockChain::new().chain[0].clone();
+        let (_, address) = start_serving(fresh_node(&genesis)).await;
+
+        // 동시에 연결해도 등록되는 연결은 하나뿐이다.
+        let client = Arc::new(fresh_node(&genesis));
+        let (first, second) = tokio::join!(client.connect(address), client.connect(address));
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(client.peers.lock().unwrap().len(), 1);
+
+        // 다시 연결한 뒤에 끝나는 이전 연결은 새 연결을 지우지 않는다.
+        let stale = client.peers.lock().unwrap()[&address].connection;
+        client.disconnect(&address);
+        client.connect(address).await.unwrap();
+        client.disconnect_connection(&address, stale);
+        assert!(client.is_connected(&address));
+        let current = client.peers.lock().unwrap()[&address].connection;
+        assert_ne!(current, stale);
+        client.disconnect_connection(&address, current);
+        assert!(!client.is_connected(&address));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_refuse_other_network() {
+        let mut server = Node::new("127.0.0.1:0".parse().unwrap());
+        server.network = TESTNET;
+        let (server, address) = start_serving(server).await;
+
+        let client = Arc::new(Node::new("127.0.0.1:0".parse().unwrap()));
+        assert!(matches!(client.connect(address).await, Err(NetError::WrongNetwork { expected: MAINNET, found: TESTNET })));
+        assert!(client.peers.lock().unwrap().is_empty());
+        assert!(server.peers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_initial_block_download() {
+        let genesis = BlockChain::new().chain[0].clone();
+        let wallet = Wallet::new();
+        let source = fresh_node(&genesis);
+        mine_blocks(&mut source.blockchain.write().unwrap(), &wallet, 40);
+
+        // 같은 블록을 가진 두 노드에게서 나눠 받는다.
+        let mirror = fresh_node(&genesis);
+        let blocks = source.blockchain.read().unwrap().chain[1..].to_vec();
+        for block in &blocks {
+            mirror.receive_block(block).unwrap();
+        }
+        let tip = source.blockchain.read().unwrap().tip_hash();
+        let (_, source_address) = start_serving(source).await;
+        let (_, mirror_address) = start_serving(mirror).await;
+
+        let node = Arc::new(fresh_node(&genesis));
+        node.connect(source_address).await.unwrap();
+        node.connect(mirror_address).await.unwrap();
+        let report = node.sync().await.unwrap();
+        assert_eq!(report.headers, 40);
+        assert_eq!(report.blocks, 40);
+        assert_eq!(report.blocks_by_peer.values().sum::<usize>(), 40);
+        assert_eq!(node.blockchain.read().unwrap().tip_hash(), tip);
+        assert_eq!(node.blockchain.read().unwrap().ledger.balance_of("B"), 10);
+
+        // 이미 따라잡았으면 받을 것이 없다.
+        let report = node.sync().await.unwrap();
+        assert_eq!((report.headers, report.blocks), (0, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_drops_peer_with_bad_bodies() {
+        let genesis = BlockChain::new().chain[0].clone();
+        let wallet = Wallet::new();
+        let honest = fresh_node(&genesis);
+        mine_blocks(&mut honest.blockchain.write().unwrap(), &wallet, 20);
+
+        // 헤더는 같지만 본문에 트랜잭션을 하나씩 더 붙여 보내는 peer
+        let liar = fresh_node(&genesis);
+        let blocks = honest.blockchain.read().unwrap().chain[1..].to_vec();
+        for block in &blocks {
+            liar.receive_block(block).unwrap();
+        }
+        for block in &blocks {
+            let mut extra = Transaction::new(wallet.generate_address(), String::from("C"), 1, 0, 99);
+            wallet.sign_transaction(&mut extra).unwrap();
+            liar.blockchain.write().unwrap().block_index.get_mut(&block.header.block_hash).unwrap().block.transactions.push(extra);
+        }
+        let tip = honest.blockchain.read().unwrap().tip_hash();
+        let (_, honest_address) = start_serving(honest).await;
+        let (_, liar_address) = start_serving(liar).await;
+
+        // 본문을 줄 수 있는 peer 가 그 peer 뿐이면 아무 블록도 받지 못하고 연결을 끊는다.
+        let node = Arc::new(fresh_node(&genesis));
+        node.connect(liar_address).await.unwrap();
+        let report = node.sync().await.unwrap();
+        assert_eq!((report.headers, report.blocks), (20, 0));
+        assert!(!node.is_connected(&liar_address));
+
+        // 다른 peer 가 있으면 그쪽에서 다시 받아 끝까지 따라잡는다.
+        node.connect(liar_address).await.unwrap();
+        node.connect(honest_address).await.unwrap();
+        let report = node.sync().await.unwrap();
+        assert_eq!(report.blocks, 20);
+        assert_eq!(node.blockchain.read().unwrap().tip_hash(), tip);
+        assert_eq!(report.blocks_by_peer.get(&liar_address).copied().unwrap_or(0), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_node_mines_past_unmineable_transactions() {
+        let mut node = Node::new("127.0.0.1:0".parse().unwrap());
+        node.blockchain.write().unwrap().initial_bits = POW_LIMIT_BITS;
+        node.miner = Miner::new(2);
+        let node = Arc::new(node);
+
+        let (early, late) = (Wallet::new(), Wallet::new());
+        let signed = |wallet: &Wallet, receiver: &str, nonce| {
+            let mut tx = Transaction::new(wallet.generate_address(), receiver.to_string(), 10, 1, nonce);
+            wallet.sign_transaction(&mut tx).unwrap();
+            tx
+        };
+        {
+            let mut blockchain = node.blockchain.write().unwrap();
+            blockchain.ledger.set_balance(&early.generate_address(), 100);
+            blockchain.ledger.set_balance(&late.generate_address(), 15);
+        }
+        // 앞 nonce 가 없는 트랜잭션과, 하나씩은 되지만 합치면 잔액을 넘는 두 트랜잭션
+        let future = signed(&early, "C", 1);
+        node.add_transaction(future.clone()).unwrap();
+        node.add_transaction(signed(&late, "D", 0)).unwrap();
+        node.add_transaction(signed(&late, "D", 1)).unwrap();
+
+        node.start_mining(String::from("M"));
+        wait_until(|| node.blockchain.read().unwrap().chain.len() >= 4).await;
+        {
+            let blockchain = node.blockchain.read().unwrap();
+            assert_eq!(blockchain.ledger.balance_of("D"), 10);
+            assert_eq!(blockchain.ledger.balance_of("C"), 0);
+        }
+        assert!(node.mempool.lock().unwrap().iter().any(|tx| tx.signature == future.signature));
+
+        // 빠진 nonce 가 들어오면 기다리던 트랜잭션도 같이 들어간다.
+        node.add_transaction(signed(&early, "C", 0)).unwrap();
+        wait_until(|| node.blockchain.read().unwrap().ledger.balance_of("C") == 20).await;
+        node.stop_mining();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_full_blocks() {
+        let genesis = BlockChain::new().chain[0].clone();
+        let wallet = Wallet::new();
+        let address = wallet.generate_address();
+        let source = fresh_node(&genesis);
+        {
+            let mut blockchain = source.blockchain.write().unwrap();
+            let per_block = MAX_BLOCK_TRANSACTIONS as u64 - 1;
+            while blockchain.ledger.balance_of(&address) < 2 * per_block {
+                blockchain.mine_next_block(&address, &[]).unwrap();
+            }
+            // coinbase 를 더해 MAX_BLOCK_TRANSACTIONS 개씩 채운 블록 두 개
+            for block in 0..2 {
+                let transactions: Vec<Transaction> = (0..per_block).map(|i| {
+                    let mut tx = Transaction::new(address.clone(), String::from("B"), 1, 0, block * per_block + i);
+                    wallet.sign_transaction(&mut tx).unwrap();
+                    tx
+                }).collect();
+                blockchain.mine_next_block("M", &transactions).unwrap();
+            }
+        }
+        let (tip, height) = {
+            let blockchain = source.blockchain.read().unwrap();
+            assert_eq!(blockchain.chain.last().unwrap().header.tx_count, MAX_BLOCK_TRANSACTIONS as u64);
+            (blockchain.tip_hash(), blockchain.chain.len() - 1)
+        };
+        let (_, address) = start_serving(source).await;
+
+        let node = Arc::new(fresh_node(&genesis));
+        node.connect(address).await.unwrap();
+        let report = node.sync().await.unwrap();
+        assert_eq!((report.headers, report.blocks), (height, height));
+        assert_eq!(node.blockchain.read().unwrap().tip_hash(), tip);
+        assert_eq!(node.blockchain.read().unwrap().ledger.balance_of("B"), 2 * (MAX_BLOCK_TRANSACTIONS as u64 - 1));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_light_client_fetches_proof() {
+        let genesis = BlockChain::new().chain[0].clone();
+        let wallet = Wallet::new();
+        let source = fresh_node(&genesis);
+        mine_blocks(&mut source.blockchain.write().unwrap(), &wallet, 4);
+        let (block_hash, txid) = {
+            let blockchain = source.blockchain.read().unwrap();
+            let block = &blockchain.chain[2];
+            (block.header.block_hash, block.transactions[1].calculate_hash().unwrap())
+        };
+        let (_, address) = start_serving(source).await;
+
+        // 헤더만 받고, 증명은 필요할 때 따로 받는다.
+        let mut client = fresh_node(&genesis);
+        client.capabilities = CAP_RELAY;
+        let client = Arc::new(client);
+        client.connect(address).await.unwrap();
+        let mut headers = client.header_chain();
+        assert_eq!(sync::download_headers(&client, address, &mut headers).await.unwrap(), 4);
+
+        let proof = sync::fetch_proof(&client, address, GetProof { block_hash, txid }).await.unwrap();
+        assert_eq!(headers.verify_transaction(&block_hash, &txid, &proof), Ok(3));
+        assert!(client.blockchain.read().unwrap().get_block(&block_hash).is_none());
+
+        // 응답을 기다리는 요청이 있는 동안에는 겹쳐 열지 않는다.
+        let pending = sync::Responses::open(&client, address).unwrap();
+        assert!(matches!(sync::fetch_proof(&client, address, GetProof { block_hash, txid }).await, Err(NetError::Busy)));
+        drop(pending);
+        sync::fetch_proof(&client, address, GetProof { block_hash, txid }).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_resumes_after_restart() {
+        let dir = std::env::temp_dir().join(format!("blockchain_core_sync_resume_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let genesis = BlockChain::new().chain[0].clone();
+        let wallet = Wallet::new();
+        let source = fresh_node(&genesis);
+        mine_blocks(&mut source.blockchain.write().unwrap(), &wallet, 10);
+        let (source, address) = start_serving(source).await;
+
+        let open = || Arc::new(node_with(BlockChain::open_with_genesis(&dir, AccountLedger::default(), genesis.clone()).unwrap()));
+        {
+            let node = open();
+            node.connect(address).await.unwrap();
+            assert_eq!(node.sync().await.unwrap().blocks, 10);
+            node.shutdown().await;
+        }
+
+        // 꺼져 있는 동안 자란 만큼만 받는다.
+        mine_blocks(&mut source.blockchain.write().unwrap(), &wallet, 5);
+        let node = open();
+        assert_eq!(node.blockchain.read().unwrap().chain.len(), 11);
+        node.connect(address).await.unwrap();
+        let report = node.sync().await.unwrap();
+        assert_eq!((report.headers, report.blocks), (5, 5));
+        assert_eq!(node.blockchain.read().unwrap().tip_hash(), source.blockchain.read().unwrap().tip_hash());
+
+        node.shutdown().await;
+        drop(node);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 0-1-2-3-4-0 으로 이어진 다섯 노드. wallet 은 모든 노드에서 100 을 가지고 시작한다.
+    async fn gossip_ring(wallet: &Wallet) -> Vec<Arc<Node>> {
+        let genesis = BlockChain::new().chain[0].clone();
+        let mut nodes = Vec::new();
+        let mut addresses = Vec::new();
+        for _ in 0..5 {
+            let node = fresh_node(&genesis);
+            node.blockchain.write().unwrap().ledger.set_balance(&wallet.generate_address(), 100);
+            let (node, address) = start_serving(node).await;
+            nodes.push(node);
+            addresses.push(address);
+        }
+        for i in 0..5 {
+            nodes[i].connect(addresses[(i + 1) % 5]).await.unwrap();
+        }
+        wait_until(|| nodes.iter().all(|node| node.peers.lock().unwrap().len() == 2)).await;
+        nodes
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_transaction_reaches_all_mempools() {
+        let wallet = Wallet::new();
+        let nodes = gossip_ring(&wallet).await;
+
+        // 잔액보다 많이 보내는 트랜잭션은 받은 노드에서 멈춘다.
+        let mut invalid = Transaction::new(wallet.generate_address(), String::from("B"), 1000, 1, 0);
+        wallet.sign_transaction(&mut invalid).unwrap();
+        let from = *nodes[0].peers.lock().unwrap().keys().next().unwrap();
+        nodes[0].handle_message(from, Message::Tx(invalid.clone()));
+
+        let mut tx = Transaction::new(wallet.generate_address(), String::from("B"), 10, 1, 0);
+        wallet.sign_transaction(&mut tx).unwrap();
+        let txid = tx.calculate_hash().unwrap();
+        nodes[2].add_transaction(tx).unwrap();
+        wait_until(|| nodes.iter().all(|node| node.mempool.lock().unwrap().contains(&txid))).await;
+
+        let invalid = invalid.calculate_hash().unwrap();
+        for node in &nodes {
+            let mempool = node.mempool.lock().unwrap();
+            assert_eq!(mempool.len(), 1);
+            assert!(!mempool.contains(&invalid));
+            // 양쪽 이웃 모두 이 트랜잭션을 가진 것으로 기록되어 다시 보내지 않는다.
+            assert!(node.peers.lock().unwrap().values().all(|peer| peer.known.contains(&txid)));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mined_block_reaches_all_nodes() {
+        let wallet = Wallet::new();
+        let nodes = gossip_ring(&wallet).await;
+        let mut tx = Transaction::new(wallet.generate_address(), String::from("B"), 10, 1, 0);
+        wallet.sign_transaction(&mut tx).unwrap();
+        nodes[1].add_transaction(tx).unwrap();
+        wait_until(|| nodes.iter().all(|node| !node.mempool.lock().unwrap().is_empty())).await;
+
+        nodes[3].start_mining(String::from("M"));
+        wait_until(|| nodes[3].blockchain.read().unwrap().ledger.balance_of("B") == 10).await;
+        nodes[3].stop_mining();
+
+        wait_until(|| {
+            let tip = nodes[3].blockchain.read().unwrap().tip_hash();
+            nodes.iter().all(|node| node.blockchain.read().unwrap().tip_hash() == tip)
+        }).await;
+        for node in &nodes {
+            assert_eq!(node.blockchain.read().unwrap().ledger.balance_of("B"), 10);
+            assert!(node.mempool.lock().unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_catch_up_from_announced_block() {
+        let genesis = BlockChain::new().chain[0].clone();
+        let wallet = Wallet::new();
+        let (source, address) = start_serving(fresh_node(&genesis)).await;
+        let node = Arc::new(fresh_node(&genesis));
+        node.connect(address).await.unwrap();
+        wait_until(|| source.peers.lock().unwrap().len() == 1).await;
+
+        // 알리지 않은 블록 위에 새 블록을 알리면 부모를 모르므로 헤더부터 받아서 따라간다.
+        mine_blocks(&mut source.blockchain.write().unwrap(), &wallet, 5);
+        let tip = source.blockchain.read().unwrap().tip_hash();
+        source.announce(Inventory { kind: InvKind::Block, hash: tip });
+        wait_until(|| node.blockchain.read().unwrap().tip_hash() == tip).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shutdown_closes_connections() {
+        let genesis = BlockChain::new().chain[0].clone();
+        let (first, address) = start_serving(fresh_node(&genesis)).await;
+        let (second, _) = start_serving(fresh_node(&genesis)).await;
+        second.connect(address).await.unwrap();
+        wait_until(|| first.peers.lock().unwrap().len() == 1).await;
+
+        first.shutdown().await;
+        assert!(first.peers.lock().unwrap().is_empty());
+        assert!(first.tasks.lock().unwrap().is_empty());
+        // 상대도 연결이 닫힌 것을 보고 peer 를 뺀다. 새 연결은 받지 않는다.
+        wait_until(|| second.peers.lock().unwrap().is_empty()).await;
+        assert!(second.connect(address).await.is_err());
+        second.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_idle_peer_times_out() {
+        let genesis = BlockChain::new().chain[0].clone();
+        let mut server = fresh_node(&genesis);
+        server.read_timeout = Duration::from_millis(300);
+        let (server, address) = start_serving(server).await;
+
+        // ping 을 보내는 노드는 남고, 아무것도 보내지 않는 연결은 끊긴다.
+        let mut live = fresh_node(&genesis);
+        live.ping_interval = Duration::from_millis(50);
+        let live = Arc::new(live);
+        live.connect(address).await.unwrap();
+        let silent = raw_peer(address).await;
+        let silent_address = silent.local_addr().unwrap();
+        wait_until(|| server.peers.lock().unwrap().contains_key(&silent_address)).await;
+
+        wait_until(|| !server.is_connected(&silent_address)).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(server.peers.lock().unwrap().len(), 1);
+        assert!(live.is_connected(&address));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_slow_peer_is_dropped() {
+        let genesis = BlockChain::new().chain[0].clone();
+        let (server, address) = start_serving(fresh_node(&genesis)).await;
+
+        // 읽지 않는 상대에게 계속 보내면 소켓 버퍼와 보내기 큐가 차서 끊긴다.
+        let stalled = raw_peer(address).await;
+        let stalled_address = stalled.local_addr().unwrap();
+        wait_until(|| server.is_connected(&stalled_address)).await;
+        let mut sent = 0;
+        while server.is_connected(&stalled_address) {
+            server.send_to(stalled_address, Message::Ping(sent));
+            sent += 1;
+            if sent % OUTBOUND_QUEUE_SIZE as u64 == 0 {
+                tokio::task::yield_now().await;
+            }
+            assert!(sent < 10_000_000, "slow peer was never dropped");
+        }
+        drop(stalled);
+    }
+}
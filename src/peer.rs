@@ -0,0 +1,202 @@
+#![allow(unused)]
+use std::{collections::{HashSet, VecDeque}, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{net::TcpStream, sync::{mpsc::{self, error::TrySendError}, Notify}, time::timeout};
+
+use crate::{error::NetError, hash::Hash256, protocol::{read_message_async, write_message_async, Message, VersionMessage, MIN_PROTOCOL_VERSION}};
+
+// 상대가 이 시간 안에 version/verack 을 보내지 않으면 포기한다.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// peer 하나당 기억하는 inventory 수. 넘치면 오래된 것부터 잊는다.
+pub const MAX_KNOWN_INVENTORY: usize = 50_000;
+// 보내기 큐에 쌓아 둘 수 있는 메시지 수. 가득 차면 상대가 따라오지 못하는 것으로 보고 끊는다.
+pub const OUTBOUND_QUEUE_SIZE: usize = 1024;
+
+// 상대가 이미 가지고 있다고 알고 있는 트랜잭션과 블록의 해시.
+// 상대에게서 받았거나 상대에게 알린 것은 다시 보내지 않는다.
+#[derive(Debug, Default)]
+pub struct KnownInventory {
+    hashes: HashSet<Hash256>,
+    order: VecDeque<Hash256>,
+}
+
+impl KnownInventory {
+    // 새로 기억했으면 true
+    pub fn insert(&mut self, hash: Hash256) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_KNOWN_INVENTORY
+            && let Some(oldest) = self.order.pop_front() {
+            self.hashes.remove(&oldest);
+        }
+        true
+    }
+
+    pub fn contains(&self, hash: &Hash256) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct Handshake {
+    // 상대가 보낸 version
+    pub remote: VersionMessage,
+    // 양쪽이 모두 지원하는 프로토콜 버전
+    pub version: u32,
+}
+
+// 양쪽 모두 먼저 version 을 보내고, 상대 version 을 받아들일 수 있으면 verack 을 보낸다.
+// 서로 verack 을 받아야 끝난다. 거절하는 쪽은 연결을 끊으므로 상대도 실패한다.
+pub async fn handshake(stream: &mut TcpStream, local: &VersionMessage) -> Result<Handshake, NetError> {
+    let exchange = async {
+        write_message_async(stream, &Message::Version(local.clone())).await?;
+        let remote = match read_message_async(stream).await? {
+            Message::Version(remote) => remote,
+            other => return Err(NetError::UnexpectedMessage(other.command().to_string())),
+        };
+        if remote.network != local.network {
+            return Err(NetError::WrongNetwork { expected: local.network, found: remote.network });
+        }
+        if remote.version < MIN_PROTOCOL_VERSION {
+            return Err(NetError::UnsupportedVersion(remote.version));
+        }
+
+        write_message_async(stream, &Message::Verack).await?;
+        match read_message_async(stream).await? {
+            Message::Verack => {}
+            other => return Err(NetError::UnexpectedMessage(other.command().to_string())),
+        }
+        let version = local.version.min(remote.version);
+        Ok(Handshake { remote, version })
+    };
+    timeout(HANDSHAKE_TIMEOUT, exchange).await.map_err(|_| NetError::Timeout)?
+}
+
+// handshake 를 마친 연결. 읽고 쓰는 것은 node 의 peer 별 task 가 맡고, 여기에는 보내기 큐만 있다.
+#[derive(Debug)]
+pub struct Peer {
+    pub address: SocketAddr,
+    // 연결마다 다른 번호. 같은 주소로 다시 연결한 뒤에 이전 연결의 task 가 새 연결을 지우지 않게 한다.
+    pub connection: u64,
+    pub remote: VersionMessage,
+    pub version: u32,
+    pub known: KnownInventory,
+    outbound: mpsc::Sender<Message>,
+    // 끊을 때 읽는 task 를 깨운다.
+    closing: Arc<Notify>,
+    // sync 나 증명 요청이 이 peer 의 응답을 기다리는 동안 headers/block/proof 를 이쪽으로 돌린다.
+    pub(crate) responses: Option<mpsc::Sender<Message>>,
+}
+
+impl Peer {
+    pub fn new(address: SocketAddr, connection: u64, handshake: Handshake, outbound: mpsc::Sender<Message>, closing: Arc<Notify>) -> Peer {
+        Peer {
+            address,
+            connection,
+            remote: handshake.remote,
+            version: handshake.version,
+            known: KnownInventory::default(),
+            outbound,
+            closing,
+            responses: None,
+        }
+    }
+
+    // 큐에 넣기만 하고 기다리지 않는다. 큐가 가득 찼거나 쓰는 task 가 끝났으면 실패한다.
+    pub fn send(&self, message: Message) -> Result<(), NetError> {
+        self.outbound.try_send(message).map_err(|e| match e {
+            TrySendError::Full(_) => NetError::QueueFull,
+            TrySendError::Closed(_) => NetError::Disconnected,
+        })
+    }
+
+    pub fn has_capability(&self, capability: u64) -> bool {
+        self.remote.capabilities & capability == capability
+    }
+
+    pub(crate) fn close(&self) {
+        self.closing.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use crate::protocol::{CAP_FULL_BLOCKS, MAINNET, PROTOCOL_VERSION, TESTNET};
+
+    use super::*;
+
+    fn version(network: u32, version: u32) -> VersionMessage {
+        VersionMessage { version, network, best_hash: Hash256::ZERO, best_height: 0, capabilities: CAP_FULL_BLOCKS }
+    }
+
+    // 로컬 소켓 한 쌍에서 양쪽 handshake 를 동시에 돌린다.
+    async fn handshake_pair(ours: VersionMessage, theirs: VersionMessage) -> (Result<Handshake, NetError>, Result<Handshake, NetError>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            handshake(&mut stream, &theirs).await
+        });
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let client = handshake(&mut stream, &ours).await;
+        (client, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_negotiates_version() {
+        let (client, server) = handshake_pair(version(MAINNET, PROTOCOL_VERSION + 1), version(MAINNET, PROTOCOL_VERSION)).await;
+        let (client, server) = (client.unwrap(), server.unwrap());
+        assert_eq!(client.version, PROTOCOL_VERSION);
+        assert_eq!(server.version, PROTOCOL_VERSION);
+        assert_eq!(server.remote.version, PROTOCOL_VERSION + 1);
+        assert_eq!(client.remote.capabilities, CAP_FULL_BLOCKS);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_mismatch() {
+        let (client, server) = handshake_pair(version(MAINNET, PROTOCOL_VERSION), version(TESTNET, PROTOCOL_VERSION)).await;
+        assert!(matches!(client, Err(NetError::WrongNetwork { expected: MAINNET, found: TESTNET })));
+        assert!(matches!(server, Err(NetError::WrongNetwork { expected: TESTNET, found: MAINNET })));
+
+        let (client, server) = handshake_pair(version(MAINNET, PROTOCOL_VERSION), version(MAINNET, 0)).await;
+        assert!(matches!(client, Err(NetError::UnsupportedVersion(0))));
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // 연결만 받고 아무것도 보내지 않는 상대
+        let silent = tokio::spawn(async move { listener.accept().await.unwrap() });
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let result = handshake(&mut stream, &version(MAINNET, PROTOCOL_VERSION)).await;
+        assert!(matches!(result, Err(NetError::Timeout)));
+        drop(silent.await.unwrap());
+    }
+
+    #[test]
+    fn test_known_inventory_forgets_oldest() {
+        let mut known = KnownInventory::default();
+        assert!(known.insert(Hash256::digest(&[0])));
+        assert!(!known.insert(Hash256::digest(&[0])));
+        for i in 1..=MAX_KNOWN_INVENTORY as u64 {
+            known.insert(Hash256::digest(&i.to_le_bytes()));
+        }
+        assert_eq!(known.len(), MAX_KNOWN_INVENTORY);
+        assert!(!known.contains(&Hash256::digest(&[0])));
+        assert!(known.contains(&Hash256::digest(&1u64.to_le_bytes())));
+    }
+}
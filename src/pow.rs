@@ -0,0 +1,301 @@
+#![allow(unused)]
+use std::{fmt, ops::{Div, Not, Shl, Shr}};
+
+// 허용하는 가장 쉬운 목표값 (약 2^255)
+pub const POW_LIMIT_BITS: u32 = 0x207f_ffff;
+
+// 256비트 부호 없는 정수. 목표값과 누적 작업량에 쓴다. limb 0 이 최상위
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn from_u64(value: u64) -> U256 {
+        U256([0, 0, 0, value])
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 32]) -> U256 {
+        let mut limbs = [0u64; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            *limb = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        U256(limbs)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (chunk, limb) in bytes.chunks_exact_mut(8).zip(self.0.iter()) {
+            chunk.copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn is_zero(self) -> bool {
+        self == U256::ZERO
+    }
+
+    pub fn low_u64(&self) -> u64 {
+        self.0[3]
+    }
+
+    // 유효 비트 수
+    pub fn bits(&self) -> u32 {
+        for (i, limb) in self.0.iter().enumerate() {
+            if *limb != 0 {
+                return 64 * (4 - i as u32) - limb.leading_zeros();
+            }
+        }
+        0
+    }
+
+    fn bit(&self, i: u32) -> bool {
+        (self.0[3 - (i / 64) as usize] >> (i % 64)) & 1 == 1
+    }
+
+    fn set_bit(&mut self, i: u32) {
+        self.0[3 - (i / 64) as usize] |= 1 << (i % 64);
+    }
+
+    pub fn overflowing_add(self, other: U256) -> (U256, bool) {
+        let mut out = [0u64; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            out[i] = sum;
+            carry = c1 || c2;
+        }
+        (U256(out), carry)
+    }
+
+    pub fn saturating_add(self, other: U256) -> U256 {
+        match self.overflowing_add(other) {
+            (sum, false) => sum,
+            (_, true) => U256::MAX,
+        }
+    }
+
+    pub fn wrapping_sub(self, other: U256) -> U256 {
+        let mut out = [0u64; 4];
+        let mut borrow = false;
+        for i in (0..4).rev() {
+            let (diff, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            out[i] = diff;
+            borrow = b1 || b2;
+        }
+        U256(out)
+    }
+
+    pub fn checked_mul_u64(self, factor: u64) -> Option<U256> {
+        let mut out = [0u64; 4];
+        let mut carry = 0u128;
+        for i in (0..4).rev() {
+            let product = self.0[i] as u128 * factor as u128 + carry;
+            out[i] = product as u64;
+            carry = product >> 64;
+        }
+        (carry == 0).then_some(U256(out))
+    }
+
+    // 비트 단위 나눗셈. divisor 가 0 이면 패닉
+    pub fn div_rem(self, divisor: U256) -> (U256, U256) {
+        assert!(!divisor.is_zero(), "attempt to divide by zero");
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for i in (0..self.bits()).rev() {
+            let carry = remainder.bit(255);
+            remainder = remainder << 1;
+            if self.bit(i) {
+                remainder.0[3] |= 1;
+            }
+            if carry || remainder >= divisor {
+                remainder = remainder.wrapping_sub(divisor);
+                quotient.set_bit(i);
+            }
+        }
+        (quotient, remainder)
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> U256 {
+        if shift >= 256 {
+            return U256::ZERO;
+        }
+        let limbs = (shift / 64) as usize;
+        let bits = shift % 64;
+        let mut out = [0u64; 4];
+        for (i, limb) in out.iter_mut().enumerate().take(4 - limbs) {
+            let src = i + limbs;
+            *limb = self.0[src] << bits;
+            if bits > 0 && src + 1 < 4 {
+                *limb |= self.0[src + 1] >> (64 - bits);
+            }
+        }
+        U256(out)
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> U256 {
+        if shift >= 256 {
+            return U256::ZERO;
+        }
+        let limbs = (shift / 64) as usize;
+        let bits = shift % 64;
+        let mut out = [0u64; 4];
+        for (i, limb) in out.iter_mut().enumerate().skip(limbs) {
+            let src = i - limbs;
+            *limb = self.0[src] >> bits;
+            if bits > 0 && src >= 1 {
+                *limb |= self.0[src - 1] << (64 - bits);
+            }
+        }
+        U256(out)
+    }
+}
+
+impl Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256(self.0.map(|limb| !limb))
+    }
+}
+
+impl Div for U256 {
+    type Output = U256;
+
+    fn div(self, divisor: U256) -> U256 {
+        self.div_rem(divisor).0
+    }
+}
+
+impl fmt::Display for U256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.to_be_bytes()))
+    }
+}
+
+// Bitcoin 의 nBits 형식: 최상위 바이트는 목표값의 바이트 길이, 나머지 3바이트는 가수.
+// 부호 비트가 켜져 있거나 256비트를 넘으면 None
+pub fn target_from_bits(bits: u32) -> Option<U256> {
+    let size = bits >> 24;
+    let word = (bits & 0x007f_ffff) as u64;
+    if word != 0 && bits & 0x0080_0000 != 0 {
+        return None;
+    }
+    if word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32)) {
+        return None;
+    }
+    if size <= 3 {
+        Some(U256::from_u64(word >> (8 * (3 - size))))
+    } else {
+        Some(U256::from_u64(word) << (8 * (size - 3)))
+    }
+}
+
+pub fn bits_from_target(target: U256) -> u32 {
+    let mut size = target.bits().div_ceil(8);
+    let mut compact = if size <= 3 {
+        target.low_u64() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3))).low_u64()
+    } as u32;
+    // 가수의 최상위 비트는 부호 비트라서 비워 둔다.
+    if compact & 0x0080_0000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | (size << 24)
+}
+
+pub fn pow_limit() -> U256 {
+    target_from_bits(POW_LIMIT_BITS).unwrap_or(U256::MAX)
+}
+
+// 이 목표값으로 블록 하나를 찾는 데 필요한 평균 해시 횟수 2^256 / (target + 1)
+pub fn work_from_bits(bits: u32) -> U256 {
+    match target_from_bits(bits) {
+        Some(target) if !target.is_zero() => (!target / target.saturating_add(U256::from_u64(1))).saturating_add(U256::from_u64(1)),
+        _ => U256::ZERO,
+    }
+}
+
+// 실제 걸린 시간에 비례해서 목표값을 조정한다. 한 번에 4배 넘게 바뀌지 않고 pow_limit 보다 쉬워지지 않는다.
+pub fn retarget(bits: u32, actual_timespan: u128, expected_timespan: u128) -> u32 {
+    let Some(target) = target_from_bits(bits) else {
+        return bits;
+    };
+    if expected_timespan == 0 {
+        return bits;
+    }
+    let expected = expected_timespan.min(u64::MAX as u128 / 4) as u64;
+    let actual = (actual_timespan.min(u64::MAX as u128) as u64).clamp(expected / 4, expected * 4);
+
+    // 곱이 256비트를 넘지 않도록 목표값 아래쪽의 0 비트를 잠시 덜어낸다.
+    // compact 목표값은 유효 비트가 24개뿐이라 덜어내도 잃는 값이 없다.
+    let shift = (target.bits() + (64 - actual.leading_zeros())).saturating_sub(256);
+    let quotient = (target >> shift).checked_mul_u64(actual)
+        .map(|product| product / U256::from_u64(expected))
+        .unwrap_or(U256::MAX);
+    let adjusted = if quotient.bits() + shift > 256 { U256::MAX } else { quotient << shift };
+    bits_from_target(adjusted.min(pow_limit()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compact_bits() {
+        let target = target_from_bits(0x1d00_ffff).unwrap();
+        assert_eq!(target, U256::from_u64(0xffff) << 208);
+        assert_eq!(bits_from_target(target), 0x1d00_ffff);
+
+        assert_eq!(target_from_bits(0x0312_3456), Some(U256::from_u64(0x12_3456)));
+        assert_eq!(target_from_bits(0x0100_3456), Some(U256::ZERO));
+        assert_eq!(bits_from_target(U256::ZERO), 0);
+        // 부호 비트가 켜지지 않도록 한 바이트 늘어난다.
+        assert_eq!(bits_from_target(U256::from_u64(0x80)), 0x0200_8000);
+
+        assert_eq!(target_from_bits(0x0492_3456), None);
+        assert_eq!(target_from_bits(0xff12_3456), None);
+        assert_eq!(bits_from_target(pow_limit()), POW_LIMIT_BITS);
+    }
+
+    #[test]
+    fn test_work() {
+        assert_eq!(work_from_bits(0x1d00_ffff), U256::from_u64(0x1_0001_0001));
+        assert_eq!(work_from_bits(POW_LIMIT_BITS), U256::from_u64(2));
+        assert_eq!(work_from_bits(0x0492_3456), U256::ZERO);
+        assert!(work_from_bits(0x1f10_0000) < work_from_bits(0x1e10_0000));
+    }
+
+    #[test]
+    fn test_retarget() {
+        let target = target_from_bits(0x1d00_ffff).unwrap();
+
+        // 두 배 오래 걸리면 목표값도 두 배
+        assert_eq!(target_from_bits(retarget(0x1d00_ffff, 2000, 1000)).unwrap(), target << 1);
+        assert_eq!(retarget(0x1d00_ffff, 1000, 1000), 0x1d00_ffff);
+
+        // 한 번에 4배까지만
+        assert_eq!(target_from_bits(retarget(0x1d00_ffff, 1, 1000)).unwrap(), target >> 2);
+        assert_eq!(target_from_bits(retarget(0x1d00_ffff, 1_000_000, 1000)).unwrap(), target << 2);
+
+        assert_eq!(retarget(POW_LIMIT_BITS, 1_000_000, 1000), POW_LIMIT_BITS);
+
+        // 목표값이 커서 곱이 256비트를 넘는 경우에도 정확해야 한다.
+        let large = target_from_bits(0x1f10_0000).unwrap();
+        assert_eq!(target_from_bits(retarget(0x1f10_0000, 1, 540_000)).unwrap(), large >> 2);
+        assert_eq!(target_from_bits(retarget(0x1f10_0000, 810_000, 540_000)).unwrap(), (large >> 1).saturating_add(large));
+    }
+}
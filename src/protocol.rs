@@ -0,0 +1,460 @@
+#![allow(unused)]
+use std::io::{self, Read, Write};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{blockchain::{Block, BlockHeader}, encoding::{put_u32, put_u64, put_vec, Decode, DecodeError, Encode, Reader}, error::NetError, hash::Hash256, merkle_tree::MerkleProof, transaction::Transaction};
+
+// 프레임: [magic 4][command 12, 뒤는 0 으로 채운 ASCII][payload 길이 u32 LE][sha256(payload) 앞 4바이트][payload]
+pub const MAGIC: [u8; 4] = [0xb1, 0x0c, 0xc0, 0xde];
+pub const COMMAND_SIZE: usize = 12;
+pub const FRAME_HEADER_SIZE: usize = 4 + COMMAND_SIZE + 4 + 4;
+// 이보다 긴 payload 는 읽기 전에 거부한다.
+pub const MAX_PAYLOAD_SIZE: usize = 4 * 1024 * 1024;
+// 한 메시지에 담을 수 있는 inventory/헤더 개수
+pub const MAX_INVENTORY: usize = 50_000;
+pub const MAX_HEADERS: usize = 2_000;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+// 이보다 낮은 버전의 노드와는 연결하지 않는다.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// 네트워크 id. 다른 네트워크의 노드와는 handshake 에서 연결을 끊는다.
+pub const MAINNET: u32 = 0x6d61_696e;
+pub const TESTNET: u32 = 0x7465_7374;
+
+// VersionMessage::capabilities 비트
+// 블록 본문을 보내줄 수 있다. 없으면 헤더만 가진 노드
+pub const CAP_FULL_BLOCKS: u64 = 1 << 0;
+// 트랜잭션을 받아서 다른 노드에 전달한다.
+pub const CAP_RELAY: u64 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvKind {
+    Tx,
+    Block,
+}
+
+// 상대에게 가지고 있다고 알리거나 달라고 요청하는 객체
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Inventory {
+    pub kind: InvKind,
+    pub hash: Hash256,
+}
+
+// 연결하자마자 서로 보내는 자기소개
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMessage {
+    pub version: u32,
+    pub network: u32,
+    pub best_hash: Hash256,
+    pub best_height: u64,
+    pub capabilities: u64,
+}
+
+// locator 는 요청하는 쪽 활성 체인의 해시들 (팁부터 거슬러 올라가며 점점 듬성듬성하게).
+// 받는 쪽은 처음으로 아는 해시 다음부터 stop 까지(ZERO 면 MAX_HEADERS 개까지) 헤더를 보낸다.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetHeaders {
+    pub locator: Vec<Hash256>,
+    pub stop: Hash256,
+}
+
+// 헤더만 가진 노드가 block_hash 블록에 txid 가 들어 있다는 증명을 달라고 한다.
+// 블록이나 트랜잭션이 없으면 응답하지 않는다.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GetProof {
+    pub block_hash: Hash256,
+    pub txid: Hash256,
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Version(VersionMessage),
+    Verack,
+    Inv(Vec<Inventory>),
+    GetData(Vec<Inventory>),
+    Block(Block),
+    Tx(Transaction),
+    GetHeaders(GetHeaders),
+    Headers(Vec<BlockHeader>),
+    GetProof(GetProof),
+    Proof(MerkleProof),
+    Ping(u64),
+    Pong(u64),
+}
+
+impl Message {
+    pub fn command(&self) -> &'static str {
+        match self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Inv(_) => "inv",
+            Message::GetData(_) => "getdata",
+            Message::Block(_) => "block",
+            Message::Tx(_) => "tx",
+            Message::GetHeaders(_) => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::GetProof(_) => "getproof",
+            Message::Proof(_) => "proof",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+        }
+    }
+
+    // 최상위 Encode::encode 와 달리 인코딩 버전 바이트는 붙이지 않는다. 버전은 Version 메시지로 맞춘다.
+    fn encode_payload(&self, buf: &mut Vec<u8>) {
+        match self {
+            Message::Version(version) => {
+                put_u32(buf, version.version);
+                put_u32(buf, version.network);
+                version.best_hash.encode_to(buf);
+                put_u64(buf, version.best_height);
+                put_u64(buf, version.capabilities);
+            }
+            Message::Verack => {}
+            Message::Inv(inventory) | Message::GetData(inventory) => put_vec(buf, inventory),
+            Message::Block(block) => block.encode_to(buf),
+            Message::Tx(tx) => tx.encode_to(buf),
+            Message::GetHeaders(request) => {
+                put_vec(buf, &request.locator);
+                request.stop.encode_to(buf);
+            }
+            Message::Headers(headers) => put_vec(buf, headers),
+            Message::GetProof(request) => {
+                request.block_hash.encode_to(buf);
+                request.txid.encode_to(buf);
+            }
+            Message::Proof(proof) => proof.encode_to(buf),
+            Message::Ping(nonce) | Message::Pong(nonce) => put_u64(buf, *nonce),
+        }
+    }
+
+    fn decode_payload(command: &str, payload: &[u8]) -> Result<Message, NetError> {
+        let mut reader = Reader::new(payload);
+        let message = match command {
+            "version" => Message::Version(VersionMessage {
+                version: reader.u32()?,
+                network: reader.u32()?,
+                best_hash: Hash256::decode_from(&mut reader)?,
+                best_height: reader.u64()?,
+                capabilities: reader.u64()?,
+            }),
+            "verack" => Message::Verack,
+            "inv" => Message::Inv(bounded_vec(&mut reader, MAX_INVENTORY)?),
+            "getdata" => Message::GetData(bounded_vec(&mut reader, MAX_INVENTORY)?),
+            "block" => Message::Block(Block::decode_from(&mut reader)?),
+            "tx" => Message::Tx(Transaction::decode_from(&mut reader)?),
+            "getheaders" => Message::GetHeaders(GetHeaders {
+                locator: bounded_vec(&mut reader, MAX_HEADERS)?,
+                stop: Hash256::decode_from(&mut reader)?,
+            }),
+            "headers" => Message::Headers(bounded_vec(&mut reader, MAX_HEADERS)?),
+            "getproof" => Message::GetProof(GetProof {
+                block_hash: Hash256::decode_from(&mut reader)?,
+                txid: Hash256::decode_from(&mut reader)?,
+            }),
+            "proof" => Message::Proof(MerkleProof::decode_from(&mut reader)?),
+            "ping" => Message::Ping(reader.u64()?),
+            "pong" => Message::Pong(reader.u64()?),
+            _ => return Err(NetError::UnknownCommand(command.to_string())),
+        };
+        reader.finish()?;
+        Ok(message)
+    }
+
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        self.encode_payload(&mut payload);
+
+        let mut command = [0u8; COMMAND_SIZE];
+        command[..self.command().len()].copy_from_slice(self.command().as_bytes());
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&MAGIC);
+        frame.extend_from_slice(&command);
+        put_u32(&mut frame, payload.len() as u32);
+        frame.extend_from_slice(&checksum(&payload));
+        frame.extend_from_slice(&payload);
+        frame
+    }
+}
+
+// 개수부터 보고 너무 많으면 원소를 읽지 않는다.
+fn bounded_vec<T: Decode>(reader: &mut Reader, max: usize) -> Result<Vec<T>, NetError> {
+    let count = reader.count()?;
+    if count > max {
+        return Err(NetError::TooManyItems(count));
+    }
+    Ok((0..count).map(|_| T::decode_from(reader)).collect::<Result<_, _>>()?)
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    Hash256::digest(payload).0[..4].try_into().unwrap()
+}
+
+// 0 으로 채운 ASCII 명령어. 0 뒤에 다른 바이트가 오면 잘못된 프레임이다.
+fn parse_command(bytes: &[u8; COMMAND_SIZE]) -> Result<String, NetError> {
+    let len = bytes.iter().position(|byte| *byte == 0).unwrap_or(COMMAND_SIZE);
+    if bytes[len..].iter().any(|byte| *byte != 0) || !bytes[..len].is_ascii() {
+        return Err(NetError::InvalidCommand);
+    }
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+pub fn write_message(writer: &mut impl Write, message: &Message) -> Result<(), NetError> {
+    writer.write_all(&checked_frame(message)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub async fn write_message_async(writer: &mut (impl AsyncWrite + Unpin), message: &Message) -> Result<(), NetError> {
+    writer.write_all(&checked_frame(message)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+fn checked_frame(message: &Message) -> Result<Vec<u8>, NetError> {
+    let frame = message.to_frame();
+    let len = frame.len() - FRAME_HEADER_SIZE;
+    if len > MAX_PAYLOAD_SIZE {
+        return Err(NetError::PayloadTooLarge(len));
+    }
+    Ok(frame)
+}
+
+// 프레임 경계에서 연결이 닫히면 Disconnected, 프레임 중간에서 끊기면 Io 오류
+pub fn read_message(reader: &mut impl Read) -> Result<Message, NetError> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    let mut filled = 0;
+    while filled < FRAME_HEADER_SIZE {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Err(NetError::Disconnected),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let (command, len) = parse_header(&header)?;
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    decode_frame(&header, &command, &payload)
+}
+
+pub async fn read_message_async(reader: &mut (impl AsyncRead + Unpin)) -> Result<Message, NetError> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    let mut filled = 0;
+    while filled < FRAME_HEADER_SIZE {
+        match reader.read(&mut header[filled..]).await? {
+            0 if filled == 0 => return Err(NetError::Disconnected),
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            n => filled += n,
+        }
+    }
+
+    let (command, len) = parse_header(&header)?;
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    decode_frame(&header, &command, &payload)
+}
+
+// payload 를 읽기 전에 magic, 명령어, 길이를 확인한다.
+fn parse_header(header: &[u8; FRAME_HEADER_SIZE]) -> Result<(String, usize), NetError> {
+    if header[..4] != MAGIC {
+        return Err(NetError::InvalidMagic(header[..4].try_into().unwrap()));
+    }
+    let command = parse_command(header[4..16].try_into().unwrap())?;
+    let len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+    if len > MAX_PAYLOAD_SIZE {
+        return Err(NetError::PayloadTooLarge(len));
+    }
+    Ok((command, len))
+}
+
+fn decode_frame(header: &[u8; FRAME_HEADER_SIZE], command: &str, payload: &[u8]) -> Result<Message, NetError> {
+    if checksum(payload) != header[20..24] {
+        return Err(NetError::InvalidChecksum);
+    }
+    Message::decode_payload(command, payload)
+}
+
+impl Encode for Inventory {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.push(match self.kind {
+            InvKind::Tx => 1,
+            InvKind::Block => 2,
+        });
+        self.hash.encode_to(buf);
+    }
+}
+
+impl Decode for Inventory {
+    fn decode_from(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let kind = match reader.u8()? {
+            1 => InvKind::Tx,
+            2 => InvKind::Block,
+            flag => return Err(DecodeError::InvalidFlag(flag)),
+        };
+        Ok(Inventory { kind, hash: Hash256::decode_from(reader)? })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{pow::POW_LIMIT_BITS, wallet::Wallet};
+
+    use super::*;
+
+    fn signed_transaction() -> Transaction {
+        let wallet = Wallet::new();
+        let mut tx = Transaction::new(wallet.generate_address(), String::from("B"), 10, 1, 0);
+        wallet.sign_transaction(&mut tx).unwrap();
+        tx
+    }
+
+    fn round_trip(message: &Message) -> Message {
+        let frame = message.to_frame();
+        let mut reader = &frame[..];
+        let decoded = read_message(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(decoded.command(), message.command());
+        assert_eq!(decoded.to_frame(), frame);
+        decoded
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let tx = signed_transaction();
+        let block = Block::new(Hash256::digest(b"parent"), 1, vec![tx.clone()], POW_LIMIT_BITS).unwrap();
+        let inventory = vec![
+            Inventory { kind: InvKind::Tx, hash: tx.calculate_hash().unwrap() },
+            Inventory { kind: InvKind::Block, hash: block.header.block_hash },
+        ];
+
+        let messages = [
+            Message::Version(VersionMessage {
+                version: PROTOCOL_VERSION,
+                network: TESTNET,
+                best_hash: block.header.block_hash,
+                best_height: 7,
+                capabilities: CAP_FULL_BLOCKS | CAP_RELAY,
+            }),
+            Message::Verack,
+            Message::Inv(inventory.clone()),
+            Message::GetData(inventory.clone()),
+            Message::Block(block.clone()),
+            Message::Tx(tx.clone()),
+            Message::GetHeaders(GetHeaders { locator: vec![block.header.block_hash, Hash256::ZERO], stop: Hash256::ZERO }),
+            Message::Headers(vec![block.header.clone()]),
+            Message::GetProof(GetProof { block_hash: block.header.block_hash, txid: tx.calculate_hash().unwrap() }),
+            Message::Proof(block.merkle_tree().unwrap().proof(0).unwrap()),
+            Message::Ping(42),
+            Message::Pong(42),
+        ];
+        for message in messages.iter() {
+            round_trip(message);
+        }
+
+        match round_trip(&Message::Inv(inventory.clone())) {
+            Message::Inv(decoded) => assert_eq!(decoded, inventory),
+            other => panic!("expected inv, got {:?}", other),
+        }
+        match round_trip(&Message::Block(block.clone())) {
+            Message::Block(decoded) => assert_eq!(decoded.header.block_hash, block.header.block_hash),
+            other => panic!("expected block, got {:?}", other),
+        }
+
+        // 한 스트림에 여러 프레임이 이어져 있어도 하나씩 읽힌다.
+        let mut stream = Vec::new();
+        write_message(&mut stream, &Message::Ping(1)).unwrap();
+        write_message(&mut stream, &Message::Verack).unwrap();
+        let mut reader = &stream[..];
+        assert!(matches!(read_message(&mut reader), Ok(Message::Ping(1))));
+        assert!(matches!(read_message(&mut reader), Ok(Message::Verack)));
+        assert!(matches!(read_message(&mut reader), Err(NetError::Disconnected)));
+    }
+
+    #[test]
+    fn test_reject_malformed_frames() {
+        let frame = Message::Ping(9).to_frame();
+        let read = |bytes: &[u8]| read_message(&mut &bytes[..]);
+
+        let mut bad_magic = frame.clone();
+        bad_magic[0] ^= 0xff;
+        assert!(matches!(read(&bad_magic), Err(NetError::InvalidMagic(_))));
+
+        let mut bad_checksum = frame.clone();
+        bad_checksum[FRAME_HEADER_SIZE] ^= 1;
+        assert!(matches!(read(&bad_checksum), Err(NetError::InvalidChecksum)));
+
+        let mut unknown = frame.clone();
+        unknown[4..8].copy_from_slice(b"pang");
+        assert!(matches!(read(&unknown), Err(NetError::UnknownCommand(command)) if command == "pang"));
+
+        let mut bad_command = frame.clone();
+        bad_command[4 + COMMAND_SIZE - 1] = b'x';
+        assert!(matches!(read(&bad_command), Err(NetError::InvalidCommand)));
+
+        // 프레임이 중간에서 끊김
+        assert!(matches!(read(&frame[..10]), Err(NetError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+        assert!(matches!(read(&frame[..frame.len() - 1]), Err(NetError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+
+        // 명령어와 맞지 않는 payload: 체크섬은 맞지만 ping 에 바이트가 남는다.
+        let mut trailing = Message::Ping(9).to_frame();
+        trailing.push(0);
+        trailing[16..20].copy_from_slice(&9u32.to_le_bytes());
+        let sum = checksum(&trailing[FRAME_HEADER_SIZE..]);
+        trailing[20..24].copy_from_slice(&sum);
+        assert!(matches!(read(&trailing), Err(NetError::Malformed(DecodeError::TrailingBytes(1)))));
+
+        let mut truncated = Message::Verack.to_frame();
+        truncated[4..4 + COMMAND_SIZE].copy_from_slice(b"ping\0\0\0\0\0\0\0\0");
+        assert!(matches!(read(&truncated), Err(NetError::Malformed(DecodeError::UnexpectedEof))));
+    }
+
+    #[tokio::test]
+    async fn test_async_codec() {
+        let message = Message::Tx(signed_transaction());
+        let mut buf = Vec::new();
+        write_message_async(&mut buf, &message).await.unwrap();
+        assert_eq!(buf, message.to_frame());
+
+        let mut reader = &buf[..];
+        assert_eq!(read_message_async(&mut reader).await.unwrap().to_frame(), buf);
+        assert!(matches!(read_message_async(&mut reader).await, Err(NetError::Disconnected)));
+        assert!(matches!(read_message_async(&mut &buf[..10]).await, Err(NetError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_reject_oversized_frames() {
+        // 길이만 보고 payload 를 읽기 전에 거부한다.
+        let mut oversized = Message::Verack.to_frame();
+        oversized[16..20].copy_from_slice(&(MAX_PAYLOAD_SIZE as u32 + 1).to_le_bytes());
+        assert!(matches!(read_message(&mut &oversized[..]), Err(NetError::PayloadTooLarge(len)) if len == MAX_PAYLOAD_SIZE + 1));
+
+        let headers = vec![Block::new(Hash256::ZERO, 1, Vec::new(), POW_LIMIT_BITS).unwrap().header; MAX_HEADERS + 1];
+        let frame = Message::Headers(headers).to_frame();
+        assert!(matches!(read_message(&mut &frame[..]), Err(NetError::TooManyItems(count)) if count == MAX_HEADERS + 1));
+
+        // 보내는 쪽도 너무 큰 메시지는 쓰지 않는다.
+        let inventory = vec![Inventory { kind: InvKind::Tx, hash: Hash256::ZERO }; MAX_PAYLOAD_SIZE / 33 + 1];
+        let mut sink = Vec::new();
+        assert!(matches!(write_message(&mut sink, &Message::Inv(inventory)), Err(NetError::PayloadTooLarge(_))));
+        assert!(sink.is_empty());
+    }
+
+    #[test]
+    fn test_headers_fit_in_one_message() {
+        // 헤더에는 루트만 들어가므로 트랜잭션 수와 상관없이 크기가 같다.
+        let empty = Block::new(Hash256::ZERO, 1, Vec::new(), POW_LIMIT_BITS).unwrap().header;
+        let full = Block::new(Hash256::ZERO, 1, (0..8).map(|_| signed_transaction()).collect(), POW_LIMIT_BITS).unwrap().header;
+        assert_eq!(full.tx_count, 8);
+        assert_eq!(full.encode().len(), empty.encode().len());
+
+        let frame = Message::Headers(vec![full; MAX_HEADERS]).to_frame();
+        assert!(frame.len() - FRAME_HEADER_SIZE <= MAX_PAYLOAD_SIZE);
+        assert!(matches!(read_message(&mut &frame[..]), Ok(Message::Headers(headers)) if headers.len() == MAX_HEADERS));
+    }
+}
@@ -0,0 +1,373 @@
+#![allow(unused)]
+use std::{collections::{BTreeMap, HashMap}, ops::Bound};
+
+use crate::hash::Hash256;
+
+// 깊이 256 의 sparse merkle tree. 키의 비트를 위(MSB)부터 따라 내려가면 그 키의 잎에 닿는다.
+// 빈 서브트리는 높이와 상관없이 ZERO 이고, 양쪽 자식이 모두 비어 있는 노드도 ZERO 다. 그래서 빈 트리의 루트는 ZERO
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+pub const DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SparseMerkleTree {
+    // 키 -> 값의 해시. BTreeMap 순서가 곧 잎의 왼쪽부터 순서다.
+    leaves: BTreeMap<Hash256, Hash256>,
+    // 잎이 둘 이상 있는 노드와 그 자식들의 해시. (깊이, 그 깊이까지만 남긴 키) 로 찾는다.
+    // 잎이 하나뿐인 서브트리는 그 위에서 갈라지는 곳에만 기억하므로 노드 수는 잎 수에 비례한다.
+    nodes: HashMap<(usize, Hash256), Hash256>,
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> SparseMerkleTree {
+        SparseMerkleTree::default()
+    }
+
+    // 바뀐 잎의 경로만 다시 해시한다.
+    pub fn insert(&mut self, key: Hash256, value: Hash256) -> Option<Hash256> {
+        let prior = self.leaves.insert(key, value);
+        self.refresh_path(&key);
+        prior
+    }
+
+    pub fn remove(&mut self, key: &Hash256) -> Option<Hash256> {
+        let prior = self.leaves.remove(key);
+        if prior.is_some() {
+            self.refresh_path(key);
+        }
+        prior
+    }
+
+    pub fn get(&self, key: &Hash256) -> Option<Hash256> {
+        self.leaves.get(key).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn root(&self) -> Hash256 {
+        self.node(0, &Hash256::ZERO)
+    }
+
+    // changes 를 반영했을 때의 루트. 트리는 바꾸지 않는다. None 은 잎을 지운다는 뜻이다.
+    pub fn root_after(&self, changes: impl IntoIterator<Item = (Hash256, Option<Hash256>)>) -> Hash256 {
+        let changes: Vec<(Hash256, Option<Hash256>)> = changes.into_iter().collect::<BTreeMap<_, _>>().into_iter().collect();
+        self.node_after(0, &Hash256::ZERO, &changes)
+    }
+
+    // key 가 트리에 없으면 그 자리가 비어 있다는 증명이 된다.
+    pub fn prove(&self, key: &Hash256) -> SparseProof {
+        let mut proof = SparseProof::default();
+        for depth in 0..DEPTH {
+            let sibling = self.node(depth + 1, &child_prefix(key, depth, !bit(key, depth)));
+            if sibling != Hash256::ZERO {
+                proof.bitmap[depth / 8] |= 0x80 >> (depth % 8);
+                proof.siblings.push(sibling);
+            }
+        }
+        proof
+    }
+
+    pub fn hash_leaf(key: &Hash256, value: &Hash256) -> Hash256 {
+        let mut data = [0u8; 65];
+        data[0] = LEAF_PREFIX;
+        data[1..33].copy_from_slice(key.as_bytes());
+        data[33..].copy_from_slice(value.as_bytes());
+        Hash256::digest(&data)
+    }
+
+    pub fn hash_node(left: &Hash256, right: &Hash256) -> Hash256 {
+        if *left == Hash256::ZERO && *right == Hash256::ZERO {
+            return Hash256::ZERO;
+        }
+        let mut data = [0u8; 65];
+        data[0] = NODE_PREFIX;
+        data[1..33].copy_from_slice(left.as_bytes());
+        data[33..].copy_from_slice(right.as_bytes());
+        Hash256::digest(&data)
+    }
+
+    // 깊이 depth 에서 prefix 로 시작하는 키들의 서브트리 루트
+    fn node(&self, depth: usize, prefix: &Hash256) -> Hash256 {
+        if let Some(hash) = self.nodes.get(&(depth, *prefix)) {
+            return *hash;
+        }
+        let mut leaves = self.leaves_under(depth, prefix);
+        match (leaves.next(), leaves.next()) {
+            (None, _) => Hash256::ZERO,
+            (Some((key, value)), None) => single_leaf_root(key, value, depth),
+            _ => SparseMerkleTree::hash_node(
+                &self.node(depth + 1, &child_prefix(prefix, depth, false)),
+                &self.node(depth + 1, &child_prefix(prefix, depth, true)),
+            ),
+        }
+    }
+
+    // changes 는 키 순으로 정렬되어 있고 모두 prefix 아래에 있다.
+    fn node_after(&self, depth: usize, prefix: &Hash256, changes: &[(Hash256, Option<Hash256>)]) -> Hash256 {
+        match changes {
+            [] => self.node(depth, prefix),
+            [(key, value)] if depth == DEPTH => value.map_or(Hash256::ZERO, |value| SparseMerkleTree::hash_leaf(key, &value)),
+            _ => {
+                let (left, right) = changes.split_at(changes.partition_point(|(key, _)| !bit(key, depth)));
+                SparseMerkleTree::hash_node(
+                    &self.node_after(depth + 1, &child_prefix(prefix, depth, false), left),
+                    &self.node_after(depth + 1, &child_prefix(prefix, depth, true), right),
+                )
+            }
+        }
+    }
+
+    fn leaves_under(&self, depth: usize, prefix: &Hash256) -> impl Iterator<Item = (&Hash256, &Hash256)> {
+        self.leaves.range(*prefix..=last_key(prefix, depth))
+    }
+
+    // 잎에서 루트 쪽으로 올라가며 key 경로의 노드를 다시 계산한다.
+    // 경로 밖의 노드는 잎이 그대로이므로 기억해 둔 해시도 그대로 맞다.
+    // 이웃한 키와 갈라지는 깊이보다 아래에는 key 하나뿐이라 기억해 둔 노드가 없다.
+    fn refresh_path(&mut self, key: &Hash256) {
+        let before = self.leaves.range(..*key).next_back();
+        let after = self.leaves.range((Bound::Excluded(*key), Bound::Unbounded)).next();
+        let Some(split) = before.into_iter().chain(after).map(|(other, _)| common_prefix(key, other)).max() else {
+            self.nodes.clear();
+            return;
+        };
+        self.nodes.remove(&(split + 1, child_prefix(key, split + 1, false)));
+        for depth in (0..=split).rev() {
+            let prefix = child_prefix(key, depth, false);
+            let children = [prefix, child_prefix(&prefix, depth, true)];
+            self.nodes.remove(&(depth, prefix));
+            if self.leaves_under(depth, &prefix).nth(1).is_none() {
+                for child in children {
+                    self.nodes.remove(&(depth + 1, child));
+                }
+                continue;
+            }
+            let [left, right] = children.map(|child| {
+                let hash = self.node(depth + 1, &child);
+                if hash != Hash256::ZERO {
+                    self.nodes.insert((depth + 1, child), hash);
+                }
+                hash
+            });
+            self.nodes.insert((depth, prefix), SparseMerkleTree::hash_node(&left, &right));
+        }
+    }
+}
+
+// 잎 하나만 있는 깊이 depth 서브트리의 루트. 형제는 모두 비어 있다.
+fn single_leaf_root(key: &Hash256, value: &Hash256, depth: usize) -> Hash256 {
+    let mut hash = SparseMerkleTree::hash_leaf(key, value);
+    for level in (depth..DEPTH).rev() {
+        hash = if bit(key, level) {
+            SparseMerkleTree::hash_node(&Hash256::ZERO, &hash)
+        } else {
+            SparseMerkleTree::hash_node(&hash, &Hash256::ZERO)
+        };
+    }
+    hash
+}
+
+// key 의 앞 depth 비트에 right 를 붙이고 나머지는 0 으로 채운 키. 깊이 depth + 1 노드의 이름이다.
+fn child_prefix(key: &Hash256, depth: usize, right: bool) -> Hash256 {
+    let mut bytes = key.0;
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let keep = depth.saturating_sub(i * 8).min(8);
+        *byte &= !(0xffu8.checked_shr(keep as u32).unwrap_or(0));
+    }
+    if right {
+        bytes[depth / 8] |= 0x80 >> (depth % 8);
+    }
+    Hash256(bytes)
+}
+
+// 두 키가 위에서부터 같은 비트 수. 다른 두 키는 이 깊이의 노드에서 갈라진다.
+fn common_prefix(a: &Hash256, b: &Hash256) -> usize {
+    match a.0.iter().zip(b.0.iter()).position(|(x, y)| x != y) {
+        Some(i) => i * 8 + (a.0[i] ^ b.0[i]).leading_zeros() as usize,
+        None => DEPTH,
+    }
+}
+
+// 깊이 depth 에서 prefix 로 시작하는 가장 큰 키
+fn last_key(prefix: &Hash256, depth: usize) -> Hash256 {
+    let mut bytes = prefix.0;
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let keep = depth.saturating_sub(i * 8).min(8);
+        *byte |= 0xffu8.checked_shr(keep as u32).unwrap_or(0);
+    }
+    Hash256(bytes)
+}
+
+fn bit(key: &Hash256, depth: usize) -> bool {
+    key.0[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+// 루트에서 잎까지 각 깊이의 형제 노드. 비어 있는(ZERO) 형제는 빼고 bitmap 에 표시만 한다.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SparseProof {
+    // 깊이 d 의 형제가 비어 있지 않으면 d 번째 비트(MSB 부터)가 1
+    pub bitmap: [u8; 32],
+    // 비어 있지 않은 형제들, 루트 쪽부터
+    pub siblings: Vec<Hash256>,
+}
+
+impl SparseProof {
+    pub fn has_sibling(&self, depth: usize) -> bool {
+        bit(&Hash256(self.bitmap), depth)
+    }
+}
+
+// value 가 Some 이면 포함 증명, None 이면 key 자리가 비어 있다는 증명으로 확인한다.
+pub fn verify(root: &Hash256, key: &Hash256, value: Option<&Hash256>, proof: &SparseProof) -> bool {
+    let mut hash = value.map_or(Hash256::ZERO, |value| SparseMerkleTree::hash_leaf(key, value));
+    let mut siblings = proof.siblings.iter().rev();
+    for depth in (0..DEPTH).rev() {
+        let sibling = if proof.has_sibling(depth) {
+            let Some(sibling) = siblings.next() else { return false };
+            *sibling
+        } else {
+            Hash256::ZERO
+        };
+        hash = if bit(key, depth) {
+            SparseMerkleTree::hash_node(&sibling, &hash)
+        } else {
+            SparseMerkleTree::hash_node(&hash, &sibling)
+        };
+    }
+    siblings.next().is_none() && hash == *root
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tree_of(count: u8) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for i in 0..count {
+            tree.insert(Hash256::digest(&[i]), Hash256::digest(&[i, i]));
+        }
+        tree
+    }
+
+    #[test]
+    fn test_root_ignores_insertion_order() {
+        assert_eq!(SparseMerkleTree::new().root(), Hash256::ZERO);
+
+        let tree = tree_of(10);
+        let mut reversed = SparseMerkleTree::new();
+        for i in (0..10u8).rev() {
+            reversed.insert(Hash256::digest(&[i]), Hash256::digest(&[i, i]));
+        }
+        assert_eq!(reversed.root(), tree.root());
+
+        // 값이 바뀌거나 잎이 빠지면 루트가 바뀐다.
+        let mut changed = tree.clone();
+        changed.insert(Hash256::digest(&[3]), Hash256::ZERO);
+        assert_ne!(changed.root(), tree.root());
+        changed.remove(&Hash256::digest(&[3]));
+        assert_ne!(changed.root(), tree.root());
+        assert_eq!(changed.root(), {
+            let mut expected = tree.clone();
+            expected.remove(&Hash256::digest(&[3]));
+            expected.root()
+        });
+    }
+
+    // 잎 전체로 처음부터 계산한 루트
+    fn subtree_root(leaves: &[(Hash256, Hash256)], depth: usize) -> Hash256 {
+        match leaves {
+            [] => Hash256::ZERO,
+            [(key, value)] if depth == DEPTH => SparseMerkleTree::hash_leaf(key, value),
+            _ => {
+                let (left, right) = leaves.split_at(leaves.partition_point(|(key, _)| !bit(key, depth)));
+                SparseMerkleTree::hash_node(&subtree_root(left, depth + 1), &subtree_root(right, depth + 1))
+            }
+        }
+    }
+
+    fn full_root(tree: &SparseMerkleTree) -> Hash256 {
+        let leaves: Vec<(Hash256, Hash256)> = tree.leaves.iter().map(|(key, value)| (*key, *value)).collect();
+        subtree_root(&leaves, 0)
+    }
+
+    #[test]
+    fn test_incremental_root_matches_full_rebuild() {
+        let mut tree = SparseMerkleTree::new();
+        for i in 0..40u8 {
+            tree.insert(Hash256::digest(&[i]), Hash256::digest(&[i, i]));
+            assert_eq!(tree.root(), full_root(&tree));
+        }
+        // 앞 비트가 거의 같은 키들도 맞아야 한다.
+        let mut close = Hash256::digest(&[0]);
+        close.0[31] ^= 1;
+        tree.insert(close, Hash256::ZERO);
+        assert_eq!(tree.root(), full_root(&tree));
+
+        for i in (0..40u8).step_by(3) {
+            tree.remove(&Hash256::digest(&[i]));
+            assert_eq!(tree.root(), full_root(&tree));
+        }
+        assert!(tree.remove(&Hash256::digest(b"missing")).is_none());
+
+        // root_after 는 실제로 바꾼 뒤의 루트와 같고 트리는 그대로다.
+        let root = tree.root();
+        let changes = vec![
+            (Hash256::digest(&[1]), None),
+            (Hash256::digest(&[2]), Some(Hash256::ZERO)),
+            (Hash256::digest(b"new"), Some(Hash256::digest(b"value"))),
+            (Hash256::digest(&[0]), None),
+        ];
+        let expected = tree.root_after(changes.clone());
+        assert_eq!(tree.root(), root);
+        let mut changed = tree.clone();
+        for (key, value) in changes {
+            match value {
+                Some(value) => changed.insert(key, value),
+                None => changed.remove(&key),
+            };
+        }
+        assert_eq!(changed.root(), expected);
+        assert_eq!(full_root(&changed), expected);
+    }
+
+    #[test]
+    fn test_inclusion_and_non_inclusion() {
+        let tree = tree_of(10);
+        let root = tree.root();
+
+        for i in 0..10u8 {
+            let key = Hash256::digest(&[i]);
+            let value = tree.get(&key).unwrap();
+            let proof = tree.prove(&key);
+            assert!(verify(&root, &key, Some(&value), &proof));
+            // 있는 잎을 없다고 하거나 다른 값이라고 할 수 없다.
+            assert!(!verify(&root, &key, None, &proof));
+            assert!(!verify(&root, &key, Some(&Hash256::ZERO), &proof));
+        }
+
+        let missing = Hash256::digest(b"missing");
+        let proof = tree.prove(&missing);
+        assert!(verify(&root, &missing, None, &proof));
+        assert!(!verify(&root, &missing, Some(&Hash256::ZERO), &proof));
+        assert!(!verify(&tree_of(9).root(), &missing, None, &proof));
+
+        // bitmap 과 형제 수가 맞지 않으면 거부한다.
+        let mut padded = proof.clone();
+        padded.siblings.push(Hash256::ZERO);
+        assert!(!verify(&root, &missing, None, &padded));
+        let mut truncated = proof;
+        truncated.siblings.pop();
+        assert!(!verify(&root, &missing, None, &truncated));
+
+        // 빈 트리에서는 형제가 하나도 없다.
+        let proof = SparseMerkleTree::new().prove(&missing);
+        assert!(proof.siblings.is_empty());
+        assert!(verify(&Hash256::ZERO, &missing, None, &proof));
+    }
+}
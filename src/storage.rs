@@ -0,0 +1,536 @@
+#![allow(unused)]
+use std::{collections::HashMap, fs::{self, File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+use sha2::{Sha256, Digest};
+
+use crate::{blockchain::Block, encoding::{Decode, Encode}, hash::Hash256, transaction::Transaction};
+
+const BLOCKS_FILE: &str = "blocks.dat";
+const INDEX_FILE: &str = "index.dat";
+const UNDO_FILE: &str = "undo.dat";
+
+// blocks.dat 레코드: [payload 길이 u32][payload = Block::encode()][sha256(payload) 앞 4바이트]
+const RECORD_OVERHEAD: u64 = 8;
+// index.dat 레코드: [block_hash 32바이트][offset u64][payload 길이 u32][height u64]
+const INDEX_RECORD_SIZE: u64 = 52;
+// undo.dat 레코드: [block_hash 32바이트][payload 길이 u32][payload = Ledger::Undo 의 encode()][sha256(payload) 앞 4바이트]
+const UNDO_RECORD_OVERHEAD: u64 = 40;
+
+#[derive(Debug, Clone)]
+pub struct BlockLocation {
+    pub block_hash: Hash256,
+    pub offset: u64,
+    pub len: u32,
+    pub height: u64,
+}
+
+// append-only 블록 파일 + 인덱스 파일 + undo 파일.
+// 블록을 먼저 fsync 한 뒤 인덱스를 fsync 하므로, 인덱스에 있는 블록은 항상 온전히 디스크에 있다.
+// 포크된 브랜치의 블록도 들어올 수 있으므로 같은 높이에 여러 블록이 있을 수 있고,
+// 기록 순서상 부모 블록이 항상 자식보다 먼저 나온다.
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    blocks_file: File,
+    index_file: File,
+    undo_file: File,
+    locations: Vec<BlockLocation>,
+    by_hash: HashMap<Hash256, usize>,
+    by_height: HashMap<u64, Vec<usize>>,
+    // block_hash -> undo payload 의 (offset, 길이)
+    undo_locations: HashMap<Hash256, (u64, u32)>,
+    recovery: Recovery,
+}
+
+// open 할 때 잘라낸 꼬리 바이트 수. 모두 0 이면 깨끗하게 닫혔던 것이다.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recovery {
+    pub index_bytes_dropped: u64,
+    pub block_bytes_dropped: u64,
+    pub undo_bytes_dropped: u64,
+}
+
+impl Recovery {
+    pub fn is_clean(&self) -> bool {
+        *self == Recovery::default()
+    }
+}
+
+impl BlockStore {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<BlockStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let open_file = |name: &str| {
+            OpenOptions::new().read(true).append(true).create(true).open(dir.join(name))
+        };
+        let blocks_file = open_file(BLOCKS_FILE)?;
+        let index_file = open_file(INDEX_FILE)?;
+        let undo_file = open_file(UNDO_FILE)?;
+        sync_dir(&dir)?;
+
+        let mut store = BlockStore {
+            dir,
+            blocks_file,
+            index_file,
+            undo_file,
+            locations: Vec::new(),
+            by_hash: HashMap::new(),
+            by_height: HashMap::new(),
+            undo_locations: HashMap::new(),
+            recovery: Recovery::default(),
+        };
+        store.recover()?;
+        store.recover_undo()?;
+        Ok(store)
+    }
+
+    // 쓰다가 죽은 경우 남은 꼬리 레코드를 잘라낸다.
+    fn recover(&mut self) -> io::Result<()> {
+        let mut index_bytes = Vec::new();
+        (&self.index_file).seek(SeekFrom::Start(0))?;
+        (&self.index_file).read_to_end(&mut index_bytes)?;
+        let blocks_len = self.blocks_file.metadata()?.len();
+
+        let mut blocks_end = 0;
+        for record in index_bytes.chunks_exact(INDEX_RECORD_SIZE as usize) {
+            let block_hash = Hash256(record[..32].try_into().unwrap());
+            let offset = u64::from_le_bytes(record[32..40].try_into().unwrap());
+            let len = u32::from_le_bytes(record[40..44].try_into().unwrap());
+            let height = u64::from_le_bytes(record[44..52].try_into().unwrap());
+
+            let end = offset + len as u64 + RECORD_OVERHEAD;
+            if offset != blocks_end || end > blocks_len {
+                break;
+            }
+            blocks_end = end;
+
+            self.insert_location(BlockLocation { block_hash, offset, len, height });
+        }
+
+        let index_end = self.locations.len() as u64 * INDEX_RECORD_SIZE;
+        if index_end != index_bytes.len() as u64 {
+            self.recovery.index_bytes_dropped = index_bytes.len() as u64 - index_end;
+            self.index_file.set_len(index_end)?;
+            self.index_file.sync_all()?;
+        }
+        if blocks_end != blocks_len {
+            self.recovery.block_bytes_dropped = blocks_len - blocks_end;
+            self.blocks_file.set_len(blocks_end)?;
+            self.blocks_file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn recover_undo(&mut self) -> io::Result<()> {
+        let mut undo_bytes = Vec::new();
+        (&self.undo_file).seek(SeekFrom::Start(0))?;
+        (&self.undo_file).read_to_end(&mut undo_bytes)?;
+
+        let mut pos = 0;
+        while pos + UNDO_RECORD_OVERHEAD as usize <= undo_bytes.len() {
+            let block_hash = Hash256(undo_bytes[pos..pos + 32].try_into().unwrap());
+            let len = u32::from_le_bytes(undo_bytes[pos + 32..pos + 36].try_into().unwrap());
+            let end = pos + UNDO_RECORD_OVERHEAD as usize + len as usize;
+            if end > undo_bytes.len() || undo_bytes[end - 4..end] != checksum(&undo_bytes[pos + 36..end - 4]) {
+                break;
+            }
+            self.undo_locations.insert(block_hash, (pos as u64 + 36, len));
+            pos = end;
+        }
+
+        if pos != undo_bytes.len() {
+            self.recovery.undo_bytes_dropped = (undo_bytes.len() - pos) as u64;
+            self.undo_file.set_len(pos as u64)?;
+            self.undo_file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn insert_location(&mut self, location: BlockLocation) {
+        let position = self.locations.len();
+        self.by_hash.insert(location.block_hash, position);
+        self.by_height.entry(location.height).or_default().push(position);
+        self.locations.push(location);
+    }
+
+    pub fn recovery(&self) -> &Recovery {
+        &self.recovery
+    }
+
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    pub fn contains(&self, block_hash: &Hash256) -> bool {
+        self.by_hash.contains_key(block_hash)
+    }
+
+    pub fn height_of(&self, block_hash: &Hash256) -> Option<u64> {
+        self.by_hash.get(block_hash).map(|position| self.locations[*position].height)
+    }
+
+    pub fn append(&mut self, block: &Block, height: u64) -> io::Result<()> {
+        let payload = block.encode();
+        let offset = self.blocks_file.metadata()?.len();
+
+        let mut record = Vec::with_capacity(payload.len() + RECORD_OVERHEAD as usize);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+        record.extend_from_slice(&checksum(&payload));
+        self.blocks_file.write_all(&record)?;
+        self.blocks_file.sync_data()?;
+
+        let mut index_record = Vec::with_capacity(INDEX_RECORD_SIZE as usize);
+        index_record.extend_from_slice(block.header.block_hash.as_bytes());
+        index_record.extend_from_slice(&offset.to_le_bytes());
+        index_record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        index_record.extend_from_slice(&height.to_le_bytes());
+        self.index_file.write_all(&index_record)?;
+        self.index_file.sync_data()?;
+
+        let block_hash = block.header.block_hash;
+        self.insert_location(BlockLocation { block_hash, offset, len: payload.len() as u32, height });
+        Ok(())
+    }
+
+    pub fn has_undo(&self, block_hash: &Hash256) -> bool {
+        self.undo_locations.contains_key(block_hash)
+    }
+
+    pub fn append_undo<U: Encode>(&mut self, block_hash: &Hash256, undo: &U) -> io::Result<()> {
+        let payload = undo.encode();
+        let offset = self.undo_file.metadata()?.len();
+
+        let mut record = Vec::with_capacity(payload.len() + UNDO_RECORD_OVERHEAD as usize);
+        record.extend_from_slice(block_hash.as_bytes());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+        record.extend_from_slice(&checksum(&payload));
+        self.undo_file.write_all(&record)?;
+        self.undo_file.sync_data()?;
+
+        self.undo_locations.insert(*block_hash, (offset + 36, payload.len() as u32));
+        Ok(())
+    }
+
+    pub fn get_undo<U: Decode>(&self, block_hash: &Hash256) -> io::Result<Option<U>> {
+        let Some((offset, len)) = self.undo_locations.get(block_hash) else {
+            return Ok(None);
+        };
+        let mut payload = vec![0; *len as usize];
+        (&self.undo_file).seek(SeekFrom::Start(*offset))?;
+        (&self.undo_file).read_exact(&mut payload)?;
+        U::decode(&payload)
+            .map(Some)
+            .map_err(|e| invalid_data(&format!("invalid undo record: {}", e)))
+    }
+
+    // 포크가 있으면 같은 높이에 여러 블록이 돌아온다.
+    pub fn get_by_height(&self, height: u64) -> io::Result<Vec<Block>> {
+        self.by_height.get(&height)
+            .map(|positions| positions.iter().map(|position| self.read_block(&self.locations[*position])).collect())
+            .unwrap_or_else(|| Ok(Vec::new()))
+    }
+
+    pub fn get_by_hash(&self, block_hash: &Hash256) -> io::Result<Option<Block>> {
+        match self.by_hash.get(block_hash) {
+            Some(position) => self.read_block(&self.locations[*position]).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // 기록된 순서대로 (부모가 먼저)
+    pub fn load_blocks(&self) -> io::Result<Vec<Block>> {
+        self.locations.iter().map(|location| self.read_block(location)).collect()
+    }
+
+    fn read_block(&self, location: &BlockLocation) -> io::Result<Block> {
+        let mut record = vec![0; location.len as usize + RECORD_OVERHEAD as usize];
+        (&self.blocks_file).seek(SeekFrom::Start(location.offset))?;
+        (&self.blocks_file).read_exact(&mut record)?;
+
+        let len = u32::from_le_bytes(record[..4].try_into().unwrap());
+        if len != location.len {
+            return Err(invalid_data("block record length does not match index"));
+        }
+        let payload = &record[4..4 + len as usize];
+        if record[4 + len as usize..] != checksum(payload) {
+            return Err(invalid_data("block record checksum mismatch"));
+        }
+
+        let block = Block::decode(payload).map_err(|e| invalid_data(&format!("invalid block record: {}", e)))?;
+        if block.header.block_hash != location.block_hash {
+            return Err(invalid_data("block hash does not match index"));
+        }
+        Ok(block)
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(payload);
+    digest[..4].try_into().unwrap()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// 새로 만든 파일의 디렉터리 엔트리까지 디스크에 남기기 위해 필요하다.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{blockchain::{BlockChain, BlockStatus}, error::{BlockError, ChainError}, ledger::{AccountLedger, AccountUndo, Ledger}, pow::POW_LIMIT_BITS, wallet::Wallet};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blockchain_core_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn signed_block(previous_hash: Hash256, height: u64) -> Block {
+        let wallet = Wallet::new();
+        let mut tx = Transaction::new(wallet.generate_address(), String::from("B"), 10, 1, 0);
+        wallet.sign_transaction(&mut tx).unwrap();
+        let mut block = Block::new(previous_hash, height, vec![tx], POW_LIMIT_BITS).unwrap();
+        block.mine_block().unwrap();
+        block
+    }
+
+    #[test]
+    fn test_append_and_reopen() {
+        let dir = temp_dir("append_and_reopen");
+
+        let genesis = signed_block(Hash256::ZERO, 0);
+        let next = signed_block(genesis.header.block_hash, 1);
+        {
+            let mut store = BlockStore::open(&dir).unwrap();
+            store.append(&genesis, 0).unwrap();
+            store.append(&next, 1).unwrap();
+        }
+
+        let store = BlockStore::open(&dir).unwrap();
+        assert!(store.recovery().is_clean());
+        assert_eq!(store.len(), 2);
+        let loaded = store.get_by_hash(&next.header.block_hash).unwrap().unwrap();
+        assert_eq!(loaded.header.previous_hash, genesis.header.block_hash);
+        assert_eq!(loaded.transactions[0].signature, next.transactions[0].signature);
+        assert_eq!(loaded.header.calculate_hash(), next.header.block_hash);
+        assert_eq!(store.get_by_height(0).unwrap()[0].header.block_hash, genesis.header.block_hash);
+        assert_eq!(store.height_of(&next.header.block_hash), Some(1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_torn_write() {
+        let dir = temp_dir("recover_torn_write");
+
+        let genesis = signed_block(Hash256::ZERO, 0);
+        {
+            let mut store = BlockStore::open(&dir).unwrap();
+            store.append(&genesis, 0).unwrap();
+        }
+
+        // 블록 기록 도중 죽은 상황: 블록 파일에 쓰레기, 인덱스에 잘린 레코드
+        let mut blocks = OpenOptions::new().append(true).open(dir.join(BLOCKS_FILE)).unwrap();
+        blocks.write_all(&[7; 20]).unwrap();
+        let mut index = OpenOptions::new().append(true).open(dir.join(INDEX_FILE)).unwrap();
+        index.write_all(&[1; 10]).unwrap();
+
+        let store = BlockStore::open(&dir).unwrap();
+        assert_eq!(store.recovery(), &Recovery { index_bytes_dropped: 10, block_bytes_dropped: 20, undo_bytes_dropped: 0 });
+        assert_eq!(store.len(), 1);
+        assert_eq!(fs::metadata(dir.join(INDEX_FILE)).unwrap().len(), INDEX_RECORD_SIZE);
+        assert_eq!(store.get_by_height(0).unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_blockchain_reopen() {
+        let dir = temp_dir("blockchain_reopen");
+
+        let wallet = Wallet::new();
+        let mut tx = Transaction::new(wallet.generate_address(), String::from("B"), 10, 1, 0);
+        wallet.sign_transaction(&mut tx).unwrap();
+        let funded = || {
+            let mut ledger = AccountLedger::default();
+            ledger.set_balance(&wallet.generate_address(), 100);
+            ledger
+        };
+
+        let (tip, accounts, state_root) = {
+            let mut blockchain = BlockChain::open_with_ledger(&dir, funded()).unwrap();
+            blockchain.initial_bits = POW_LIMIT_BITS;
+            blockchain.add_block(&[tx]).unwrap();
+            (blockchain.tip_hash(), blockchain.ledger.accounts().clone(), blockchain.ledger.state_root())
+        };
+
+        let blockchain = BlockChain::open_with_ledger(&dir, funded()).unwrap();
+        assert_eq!(blockchain.chain.len(), 2);
+        assert_eq!(blockchain.chain.last().unwrap().header.block_hash, tip);
+        assert_eq!(blockchain.ledger.nonce_of(&wallet.generate_address()), 1);
+        assert_eq!(blockchain.ledger.accounts(), &accounts);
+        assert_eq!(blockchain.ledger.balance_of("B"), 10);
+        assert_eq!(blockchain.ledger.state_root(), state_root);
+        assert_eq!(blockchain.chain.last().unwrap().header.state_root, state_root);
+        assert!(blockchain.is_chain_valid());
+
+        let undo = blockchain.store.as_ref().unwrap().get_undo::<AccountUndo>(&tip).unwrap().unwrap();
+        assert_eq!(undo.prior_balances, vec![(wallet.generate_address(), 100)]);
+        assert_eq!(undo.created_accounts, vec!["B".to_string()]);
+        assert_eq!(undo.prior_nonces, vec![(wallet.generate_address(), 0)]);
+        drop(blockchain);
+
+        // 제네시스 전 잔액 없이 열면 다른 상태가 조용히 만들어지지 않고 실패한다.
+        assert!(matches!(BlockChain::open(&dir), Err(ChainError::StateRootMismatch { height: 0, .. })));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_blockchain_rolls_back_failed_undo_write() {
+        let dir = temp_dir("blockchain_rolls_back_failed_undo_write");
+
+        let wallet = Wallet::new();
+        let mut tx = Transaction::new(wallet.generate_address(), String::from("B"), 10, 1, 0);
+        wallet.sign_transaction(&mut tx).unwrap();
+        let mut ledger = AccountLedger::default();
+        ledger.set_balance(&wallet.generate_address(), 100);
+
+        let mut blockchain = BlockChain::open_with_ledger(&dir, ledger).unwrap();
+        blockchain.initial_bits = POW_LIMIT_BITS;
+        let genesis = blockchain.tip_hash();
+        let state_root = blockchain.ledger.state_root();
+        let mut block = blockchain.block_template(&[tx]).unwrap();
+        block.mine_block().unwrap();
+
+        // undo 기록만 실패하도록 읽기 전용 핸들로 바꾼다.
+        let store = blockchain.store.as_mut().unwrap();
+        let writable = std::mem::replace(&mut store.undo_file, File::open(dir.join(UNDO_FILE)).unwrap());
+        assert!(matches!(blockchain.accept_block(&block), Err(BlockError::Storage(_))));
+        assert_eq!(blockchain.chain.len(), 1);
+        assert_eq!(blockchain.tip_hash(), genesis);
+        assert_eq!(blockchain.ledger.state_root(), state_root);
+        assert_eq!(blockchain.tip_work(), blockchain.chain[0].header.work());
+
+        blockchain.store.as_mut().unwrap().undo_file = writable;
+        assert!(matches!(blockchain.accept_block(&block), Ok(BlockStatus::Connected)));
+        assert_eq!(blockchain.tip_hash(), block.header.block_hash);
+        drop(blockchain);
+
+        let blockchain = BlockChain::open_with_ledger(&dir, AccountLedger::from_state(
+            [(wallet.generate_address(), 100)].into(), HashMap::new())).unwrap();
+        assert_eq!(blockchain.tip_hash(), block.header.block_hash);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_blockchain_reopen_after_reorganize() {
+        let dir = temp_dir("blockchain_reopen_after_reorganize");
+
+        let mine_on = |parent: &Block| {
+            let mut block = Block::new(parent.header.block_hash, parent.header.height + 1, Vec::new(), POW_LIMIT_BITS).unwrap();
+            block.header.timestamp = parent.header.timestamp + 1;
+            block.mine_block().unwrap();
+            block
+        };
+
+        let tip = {
+            let mut blockchain = BlockChain::open(&dir).unwrap();
+            blockchain.initial_bits = POW_LIMIT_BITS;
+            let genesis = blockchain.chain[0].clone();
+            let a1 = mine_on(&genesis);
+            let b1 = mine_on(&a1);
+            let mut c1 = mine_on(&genesis);
+            c1.header.nonce += 1;
+            c1.mine_block().unwrap();
+            let c2 = mine_on(&c1);
+            let c3 = mine_on(&c2);
+
+            for block in [&a1, &b1, &c1, &c2, &c3] {
+                blockchain.accept_block(block).unwrap();
+            }
+            assert_eq!(blockchain.tip_hash(), c3.header.block_hash);
+            c3.header.block_hash
+        };
+
+        let blockchain = BlockChain::open(&dir).unwrap();
+        assert_eq!(blockchain.chain.len(), 4);
+        assert_eq!(blockchain.tip_hash(), tip);
+        assert_eq!(blockchain.block_index.len(), 6);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_blockchain_reopen_keeps_side_branches() {
+        let dir = temp_dir("blockchain_reopen_keeps_side_branches");
+
+        let mine_on = |parent: &Block, nonce: u64| {
+            let mut block = Block::new(parent.header.block_hash, parent.header.height + 1, Vec::new(), POW_LIMIT_BITS).unwrap();
+            block.header.timestamp = parent.header.timestamp + 1;
+            block.header.nonce = nonce;
+            block.mine_block().unwrap();
+            block
+        };
+
+        let (genesis, a1, b1) = {
+            let mut blockchain = BlockChain::open(&dir).unwrap();
+            blockchain.initial_bits = POW_LIMIT_BITS;
+            let genesis = blockchain.chain[0].clone();
+            let a1 = mine_on(&genesis, 0);
+            let b1 = mine_on(&genesis, a1.header.nonce + 1);
+            blockchain.accept_block(&a1).unwrap();
+            assert!(matches!(blockchain.accept_block(&b1), Ok(BlockStatus::SideBranch)));
+            (genesis, a1, b1)
+        };
+
+        // 다시 열어도 사이드 브랜치를 알고 있어서 그 위로 재구성할 수 있다.
+        let b2 = mine_on(&b1, 0);
+        {
+            let mut blockchain = BlockChain::open(&dir).unwrap();
+            blockchain.initial_bits = POW_LIMIT_BITS;
+            assert_eq!(blockchain.tip_hash(), a1.header.block_hash);
+            assert!(blockchain.block_index.contains_key(&b1.header.block_hash));
+            assert!(matches!(blockchain.accept_block(&b2), Ok(BlockStatus::Reorganized(_))));
+        }
+
+        // 연결되기 전에 저장만 된 더 무거운 브랜치는 다시 열 때 검증해서 옮겨 가고, 잘못된 브랜치는 버린다.
+        let a2 = mine_on(&a1, 0);
+        let a3 = mine_on(&a2, 0);
+        let mut invalid = Block::new(a3.header.block_hash, 4, Vec::new(), POW_LIMIT_BITS).unwrap();
+        invalid.header.timestamp = a3.header.timestamp + 1;
+        invalid.header.state_root = Hash256::digest(b"wrong");
+        invalid.mine_block().unwrap();
+        {
+            let mut store = BlockStore::open(&dir).unwrap();
+            for (block, height) in [(&a2, 2), (&a3, 3), (&invalid, 4)] {
+                store.append(block, height).unwrap();
+            }
+        }
+        let blockchain = BlockChain::open(&dir).unwrap();
+        assert_eq!(blockchain.tip_hash(), a3.header.block_hash);
+        assert!(!blockchain.block_index.contains_key(&invalid.header.block_hash));
+        assert!(blockchain.block_index.contains_key(&b2.header.block_hash));
+        assert!(blockchain.store.as_ref().unwrap().has_undo(&a3.header.block_hash));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
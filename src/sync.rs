@@ -0,0 +1,177 @@
+#![allow(unused)]
+use std::{collections::{HashMap, HashSet, VecDeque}, net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use tokio::{sync::mpsc, task::JoinSet, time::timeout};
+
+use crate::{blockchain::{Block, BlockHeader}, error::{BlockError, NetError}, hash::Hash256, light_client::HeaderChain, node::Node, merkle_tree::MerkleProof, protocol::{GetHeaders, GetProof, InvKind, Inventory, Message, MAX_HEADERS}};
+
+// 동기화 중 peer 가 이 시간 안에 응답하지 않으면 그 peer 는 포기한다.
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+// getdata 한 번에 요청할 블록 수. 작을수록 여러 peer 에게 고르게 나뉜다.
+pub const BLOCKS_PER_REQUEST: usize = 16;
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    // 새로 받은 헤더 수
+    pub headers: usize,
+    // 체인에 적용한 블록 수
+    pub blocks: usize,
+    // peer 별로 받아온 블록 수
+    pub blocks_by_peer: HashMap<SocketAddr, usize>,
+}
+
+// 열려 있는 동안 address 가 보내는 headers/block/proof 메시지를 받는다. 닫히면 다시 node 가 처리한다.
+pub struct Responses {
+    node: Arc<Node>,
+    address: SocketAddr,
+    // 연 연결의 번호. 그 사이 다시 연결된 peer 의 채널은 닫지 않는다.
+    connection: u64,
+    receiver: mpsc::Receiver<Message>,
+}
+
+impl Responses {
+    // 한 peer 에는 한 번에 하나만 연다. 둘이 열리면 서로의 응답을 가져간다.
+    pub fn open(node: &Arc<Node>, address: SocketAddr) -> Result<Responses, NetError> {
+        let (sender, receiver) = mpsc::channel(BLOCKS_PER_REQUEST);
+        let mut peers = node.peers.lock().unwrap();
+        let peer = peers.get_mut(&address).ok_or(NetError::Disconnected)?;
+        if peer.responses.is_some() {
+            return Err(NetError::Busy);
+        }
+        peer.responses = Some(sender);
+        Ok(Responses { node: Arc::clone(node), address, connection: peer.connection, receiver })
+    }
+
+    // peer 가 끊기면 보내는 쪽도 사라지므로 Disconnected
+    pub async fn next(&mut self) -> Result<Message, NetError> {
+        timeout(SYNC_TIMEOUT, self.receiver.recv()).await
+            .map_err(|_| NetError::Timeout)?
+            .ok_or(NetError::Disconnected)
+    }
+}
+
+impl Drop for Responses {
+    fn drop(&mut self) {
+        if let Some(peer) = self.node.peers.lock().unwrap().get_mut(&self.address)
+            && peer.connection == self.connection {
+            peer.responses = None;
+        }
+    }
+}
+
+// peer 가 더 보낼 헤더가 없을 때까지 getheaders 를 보낸다. 받은 헤더는 headers 에서 검증한다.
+pub async fn download_headers(node: &Arc<Node>, address: SocketAddr, headers: &mut HeaderChain) -> Result<usize, NetError> {
+    let mut responses = Responses::open(node, address)?;
+    let mut count = 0;
+    loop {
+        let request = GetHeaders { locator: headers.block_locator(), stop: Hash256::ZERO };
+        node.send_to(address, Message::GetHeaders(request));
+        let received = loop {
+            match responses.next().await? {
+                Message::Headers(received) => break received,
+                other => node.handle_blocking(address, other).await,
+            }
+        };
+
+        let known = headers.headers.len();
+        headers.add_headers(&received).map_err(NetError::Rejected)?;
+        count += headers.headers.len() - known;
+        // 새 헤더가 없는데 가득 채워 보내는 peer 와는 끝나지 않는다.
+        if received.len() < MAX_HEADERS || headers.headers.len() == known {
+            return Ok(count);
+        }
+    }
+}
+
+// 헤더만 가진 쪽이 필요할 때 포함 증명을 받는다. 검증은 HeaderChain::verify_transaction 이 한다.
+// 상대에게 블록이나 트랜잭션이 없으면 SYNC_TIMEOUT 뒤에 실패한다.
+pub async fn fetch_proof(node: &Arc<Node>, address: SocketAddr, request: GetProof) -> Result<MerkleProof, NetError> {
+    let mut responses = Responses::open(node, address)?;
+    node.send_to(address, Message::GetProof(request));
+    loop {
+        match responses.next().await? {
+            Message::Proof(proof) => return Ok(proof),
+            other => node.handle_blocking(address, other).await,
+        }
+    }
+}
+
+// hashes 를 BLOCKS_PER_REQUEST 개씩 나눠 peers 에게서 동시에 받는다. 받은 블록은 보낸 peer 와 함께 돌려준다.
+// 실패한 peer 의 묶음은 남은 peer 들이 다시 가져가고, 아무도 못 받은 블록은 결과에서 빠진다.
+// 헤더와 맞지 않는 블록을 보낸 peer 는 끊는다.
+pub async fn download_blocks(node: &Arc<Node>, peers: &[SocketAddr], hashes: &[Hash256]) -> (HashMap<Hash256, (SocketAddr, Block)>, HashMap<SocketAddr, usize>) {
+    let batches = Arc::new(Mutex::new(hashes.chunks(BLOCKS_PER_REQUEST).map(<[Hash256]>::to_vec).collect::<VecDeque<_>>()));
+    let mut blocks = HashMap::new();
+    let mut blocks_by_peer = HashMap::new();
+    let mut peers = peers.to_vec();
+
+    // 한 번에 끝나지 않으면 실패하지 않은 peer 들로 남은 묶음을 다시 돌린다.
+    while !peers.is_empty() && !batches.lock().unwrap().is_empty() {
+        let mut workers = JoinSet::new();
+        for &address in &peers {
+            let (node, batches) = (Arc::clone(node), Arc::clone(&batches));
+            workers.spawn(async move {
+                let mut fetched = Vec::new();
+                let mut responses = match Responses::open(&node, address) {
+                    Ok(responses) => responses,
+                    Err(e) => return (address, fetched, Some(e)),
+                };
+                loop {
+                    let Some(batch) = batches.lock().unwrap().pop_front() else { break };
+                    match fetch_blocks(&node, &mut responses, &batch).await {
+                        Ok(received) => fetched.extend(received),
+                        Err(e) => {
+                            println!("Block download from {} failed: {}", address, e);
+                            batches.lock().unwrap().push_back(batch);
+                            return (address, fetched, Some(e));
+                        }
+                    }
+                }
+                (address, fetched, None)
+            });
+        }
+
+        while let Some(worker) = workers.join_next().await {
+            let (address, fetched, failed) = worker.expect("Block download task panicked.");
+            *blocks_by_peer.entry(address).or_default() += fetched.len();
+            blocks.extend(fetched.into_iter().map(|block| (block.header.block_hash, (address, block))));
+            if let Some(e) = failed {
+                if let NetError::Rejected(_) = e {
+                    node.disconnect(&address);
+                }
+                peers.retain(|peer| *peer != address);
+            }
+        }
+    }
+    (blocks, blocks_by_peer)
+}
+
+// 요청한 블록을 모두 받아야 성공이다. 없는 블록은 보내지 않으므로 SYNC_TIMEOUT 뒤에 실패한다.
+// 요청하지 않은 블록 (그 사이 relay 된 것) 은 node 가 처리한다.
+async fn fetch_blocks(node: &Arc<Node>, responses: &mut Responses, batch: &[Hash256]) -> Result<Vec<Block>, NetError> {
+    let inventory = batch.iter().map(|hash| Inventory { kind: InvKind::Block, hash: *hash }).collect();
+    node.send_to(responses.address, Message::GetData(inventory));
+
+    let mut waiting: HashSet<Hash256> = batch.iter().copied().collect();
+    let mut received = Vec::with_capacity(batch.len());
+    while !waiting.is_empty() {
+        let block = match responses.next().await? {
+            Message::Block(block) => block,
+            other => {
+                node.handle_blocking(responses.address, other).await;
+                continue;
+            }
+        };
+        // 헤더 체인에서 고른 해시이므로 본문의 헤더가 그 해시로 계산되어야 하고, 본문은 헤더의 머클 루트에 맞아야 한다.
+        if block.header.calculate_hash() != block.header.block_hash {
+            return Err(NetError::Rejected(BlockError::InvalidHash));
+        }
+        if waiting.remove(&block.header.block_hash) {
+            block.check_merkle_root().map_err(NetError::Rejected)?;
+            received.push(block);
+        } else {
+            node.handle_blocking(responses.address, Message::Block(block)).await;
+        }
+    }
+    Ok(received)
+}
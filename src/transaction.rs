@@ -1,7 +1,20 @@
 #![allow(unused)]
 use p256::ecdsa::{signature::{Signer, Verifier}, Signature, SigningKey, VerifyingKey};
-use sha2::{Sha256, Digest};
-use crate::utils::current_timestamp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use crate::{encoding::{put_str, put_u128, put_u64, put_vec, Encode}, error::TxError, hash::Hash256, utils::current_timestamp};
+
+// UTXO 모드에서 입력이 가리키는 이전 트랜잭션의 출력
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: Hash256,
+    pub index: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxOutput {
+    pub address: String,
+    pub amount: u64,
+}
 
 #[derive(Debug, Clone)]
 pub struct Transaction {
@@ -9,48 +22,91 @@ pub struct Transaction {
     pub receiver: String,
     pub amount: u64,
     pub fee: u64,
-    pub signature: Option<String>,
+    // 송신자 계정의 몇 번째 트랜잭션인지. 같은 nonce 는 한 번만 쓸 수 있다.
+    pub nonce: u64,
+    // UTXO 모드에서만 쓴다. 계정 모드에서는 비어 있어야 한다.
+    pub inputs: Vec<OutPoint>,
+    pub outputs: Vec<TxOutput>,
+    pub signature: Option<Signature>,
     pub public_key: Option<VerifyingKey>,
     pub timestamp: u128
 }
 
 impl Transaction {
-    pub fn new(sender: String, receiver: String, amount: u64, fee: u64) -> Transaction {
+    pub fn new(sender: String, receiver: String, amount: u64, fee: u64, nonce: u64) -> Transaction {
         Transaction { 
             sender, 
             receiver, 
             amount,
             fee,
+            nonce,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
             signature: None,
             public_key: None,
             timestamp: current_timestamp()
         }
     }
 
+    // 채굴 보상. 송신자가 없고 서명도 없다.
+    pub fn new_coinbase(receiver: String, amount: u64) -> Transaction {
+        Transaction::new(String::new(), receiver, amount, 0, 0)
+    }
+
+    // UTXO 모드 트랜잭션. inputs 합계 = outputs 합계 + fee 여야 한다.
+    pub fn new_spend(sender: String, inputs: Vec<OutPoint>, outputs: Vec<TxOutput>, fee: u64) -> Transaction {
+        let mut transaction = Transaction::new(sender, String::new(), 0, fee, 0);
+        transaction.inputs = inputs;
+        transaction.outputs = outputs;
+        transaction
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.sender.is_empty() && self.inputs.is_empty() && self.signature.is_none() && self.public_key.is_none()
+    }
+
+    // 둘 중 하나만 블록에 들어갈 수 있는 관계인지 (같은 nonce, 또는 같은 출력을 쓰는 경우)
+    pub fn conflicts_with(&self, other: &Transaction) -> bool {
+        if self.inputs.is_empty() && other.inputs.is_empty() {
+            return self.sender == other.sender && self.nonce == other.nonce;
+        }
+        self.inputs.iter().any(|input| other.inputs.contains(input))
+    }
+
+    // 서명과 공개키를 뺀 나머지 필드의 encoding. 길이 접두사가 있어서 필드 경계가 모호하지 않다.
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_str(&mut buf, &self.sender);
+        put_str(&mut buf, &self.receiver);
+        put_u64(&mut buf, self.amount);
+        put_u64(&mut buf, self.fee);
+        put_u64(&mut buf, self.nonce);
+        put_vec(&mut buf, &self.inputs);
+        put_vec(&mut buf, &self.outputs);
+        put_u128(&mut buf, self.timestamp);
+        buf
+    }
+
     // 서명을 포함하지 않아야 verify할 때 true가 나올 것.
-    pub fn calculate_hash_sign(&self) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(self.sender.as_bytes());
-        hasher.update(self.receiver.as_bytes());
-        hasher.update(self.amount.to_string().as_bytes());
-        hasher.update(self.timestamp.to_string().as_bytes());
-        hex::encode(hasher.finalize())
-    }
-
-    pub fn calculate_hash(&self) -> Result<String, Box<dyn std::error::Error>> {
-
-        // 서명한 transaction hash만 가능하게
-        let mut hasher = Sha256::new();
-        hasher.update(self.sender.as_bytes());
-        hasher.update(self.receiver.as_bytes());
-        hasher.update(self.amount.to_string().as_bytes());
-        if let Some(signature) = self.signature.as_ref() {
-            hasher.update(signature.to_string().as_bytes());
-        } else {
-            return Err("Not signed transaction".into());
+    pub fn calculate_hash_sign(&self) -> Hash256 {
+        Hash256::digest(&self.signed_bytes())
+    }
+
+    // 서명까지 포함한 전체 encoding 의 해시 (txid)
+    pub fn calculate_hash(&self) -> Result<Hash256, TxError> {
+
+        // 서명한 transaction hash만 가능하게 (coinbase 제외)
+        if self.signature.is_none() && !self.is_coinbase() {
+            return Err(TxError::Unsigned);
         }
-        hasher.update(self.timestamp.to_string().as_bytes());
-        Ok(hex::encode(hasher.finalize()))
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf);
+        Ok(Hash256::digest(&buf))
+    }
+
+    // 송신자 잔액에서 빠져나가는 총액
+    pub fn total_cost(&self) -> Option<u64> {
+        self.amount.checked_add(self.fee)
     }
 
     pub fn cmp_by_fee(&self, other: &Transaction) -> std::cmp::Ordering {
@@ -59,32 +115,220 @@ impl Transaction {
 }
 
 pub struct TransactionPool {
-    // 검증되지 않은 트랜잭션들
-    pub transactions: Vec<Transaction>,
+    // 검증되지 않은 트랜잭션들. txid 로 찾는다.
+    transactions: HashMap<Hash256, Transaction>,
+    // 들어온 순서
+    order: Vec<Hash256>,
 }
 
 impl TransactionPool {
     pub fn new() -> TransactionPool {
-        TransactionPool { transactions: Vec::new() }
+        TransactionPool { transactions: HashMap::new(), order: Vec::new() }
     }
 
-    pub fn add_transaction(&mut self, tx: Transaction) {
-        self.transactions.push(tx.clone());
+    // 서로 충돌하는 트랜잭션은 하나만 풀에 들어갈 수 있다.
+    pub fn add_transaction(&mut self, tx: Transaction) -> Result<(), TxError> {
+        let txid = tx.calculate_hash()?;
+        if self.conflicts(&tx) {
+            return Err(TxError::Duplicate);
+        }
         println!("Transaction added to pool: {:?}", tx);
+        self.insert(txid, tx);
+        Ok(())
+    }
+
+    // 체인 재구성으로 빠진 트랜잭션을 다시 풀에 넣는다. 이미 있는 것은 건너뛴다.
+    pub fn return_transactions(&mut self, transactions: Vec<Transaction>) {
+        for tx in transactions {
+            if let Ok(txid) = tx.calculate_hash()
+                && !self.conflicts(&tx) {
+                self.insert(txid, tx);
+            }
+        }
+    }
+
+    fn insert(&mut self, txid: Hash256, tx: Transaction) {
+        self.transactions.insert(txid, tx);
+        self.order.push(txid);
+    }
+
+    pub fn get(&self, txid: &Hash256) -> Option<&Transaction> {
+        self.transactions.get(txid)
+    }
+
+    pub fn contains(&self, txid: &Hash256) -> bool {
+        self.transactions.contains_key(txid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    // 들어온 순서대로
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.order.iter().map(|txid| &self.transactions[txid])
+    }
+
+    fn conflicts(&self, tx: &Transaction) -> bool {
+        self.transactions.values().any(|pooled| pooled.conflicts_with(tx))
+    }
+
+    // 블록에 들어가서 더는 적용할 수 없는 트랜잭션을 버린다.
+    pub fn prune(&mut self, is_pending: impl Fn(&Transaction) -> bool) {
+        self.transactions.retain(|_, tx| is_pending(tx));
+        let transactions = &self.transactions;
+        self.order.retain(|txid| transactions.contains_key(txid));
+    }
+
+    fn take(&mut self, count: usize) -> Vec<Transaction> {
+        let count = count.min(self.order.len());
+        self.order.drain(..count)
+            .map(|txid| self.transactions.remove(&txid).expect("Every ordered txid is pooled."))
+            .collect()
     }
 
     pub fn select_transcations(&mut self, limit: usize) -> Vec<Transaction> {
-        self.transactions.drain(..limit.min(self.transactions.len())).collect()
+        let mut transactions = self.take(limit);
+        order_by_nonce(&mut transactions);
+        transactions
     }
 
     pub fn select_transcations_by_fee(&mut self, limit: usize) -> Vec<Transaction> {
-        let mut transactions: Vec<Transaction> = self.transactions.drain(..).collect();
-        transactions.sort_by(|a, b| a.cmp_by_fee(b));
-        transactions.into_iter().take(limit).collect()
+        let transactions = self.best_transactions(limit);
+        let selected: HashSet<Hash256> = transactions.iter().filter_map(|tx| tx.calculate_hash().ok()).collect();
+        self.transactions.retain(|txid, _| !selected.contains(txid));
+        self.order.retain(|txid| !selected.contains(txid));
+        transactions
+    }
+
+    // 풀에서 빼지 않고 수수료 순으로 골라 본다. 채굴할 블록 템플릿을 만들 때 쓴다.
+    // 송신자마다 이어진 nonce 를 줄 세우고 각 줄의 맨 앞끼리 수수료를 비교하므로,
+    // 앞 nonce 가 골라지기 전에는 뒤 nonce 가 골라지지 않는다.
+    pub fn best_transactions(&self, limit: usize) -> Vec<Transaction> {
+        let mut queues: Vec<VecDeque<&Transaction>> = Vec::new();
+        let mut by_sender: HashMap<&str, usize> = HashMap::new();
+        for tx in self.iter() {
+            // UTXO 트랜잭션은 nonce 를 쓰지 않으므로 각자 줄을 선다.
+            if !tx.inputs.is_empty() {
+                queues.push(VecDeque::from([tx]));
+                continue;
+            }
+            let queue = *by_sender.entry(&tx.sender).or_insert_with(|| {
+                queues.push(VecDeque::new());
+                queues.len() - 1
+            });
+            queues[queue].push_back(tx);
+        }
+        for queue in queues.iter_mut() {
+            queue.make_contiguous().sort_by_key(|tx| tx.nonce);
+            // 중간에 빠진 nonce 가 있으면 그 뒤는 이번 블록에 넣을 수 없다.
+            let run = queue.iter().zip(queue.iter().skip(1)).take_while(|(a, b)| b.nonce == a.nonce + 1).count() + 1;
+            queue.truncate(run);
+        }
+
+        let mut transactions = Vec::new();
+        while transactions.len() < limit {
+            let best = queues.iter().enumerate()
+                .filter_map(|(i, queue)| queue.front().map(|tx| (i, tx)))
+                .min_by(|(_, a), (_, b)| a.cmp_by_fee(b))
+                .map(|(i, _)| i);
+            let Some(best) = best else {
+                break;
+            };
+            transactions.push(queues[best].pop_front().expect("Best queue is not empty.").clone());
+        }
+        transactions
     }
 
     // 네트워크 혼잡도에 따른 동적 수수료
     pub fn dynamic_fee(&self, base_fee: u64, congestion_level: u64) -> u64 {
         base_fee + congestion_level * 2
     }
-}
\ No newline at end of file
+}
+
+impl Default for TransactionPool {
+    fn default() -> TransactionPool {
+        TransactionPool::new()
+    }
+}
+
+// 같은 송신자의 트랜잭션끼리만 nonce 순서로 자리를 바꾼다. 송신자 사이의 순서는 그대로 둔다.
+fn order_by_nonce(transactions: &mut [Transaction]) {
+    let mut by_sender: HashMap<String, Vec<Transaction>> = HashMap::new();
+    for tx in transactions.iter() {
+        by_sender.entry(tx.sender.clone()).or_default().push(tx.clone());
+    }
+    for pending in by_sender.values_mut() {
+        pending.sort_by_key(|tx| std::cmp::Reverse(tx.nonce));
+    }
+    for slot in transactions.iter_mut() {
+        let sender = slot.sender.clone();
+        *slot = by_sender.get_mut(&sender).and_then(|pending| pending.pop()).expect("Every sender has a slot.");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wallet::Wallet;
+
+    use super::*;
+
+    fn signed_transaction(wallet: &Wallet, fee: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction::new(wallet.generate_address(), String::from("B"), 10, fee, nonce);
+        wallet.sign_transaction(&mut tx).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_pool_orders_nonces() {
+        let wallet = Wallet::new();
+        let other = Wallet::new();
+        let mut pool = TransactionPool::new();
+
+        // 나중 nonce 가 수수료를 더 많이 낸다.
+        pool.add_transaction(signed_transaction(&wallet, 1, 0)).unwrap();
+        pool.add_transaction(signed_transaction(&wallet, 9, 1)).unwrap();
+        pool.add_transaction(signed_transaction(&other, 5, 0)).unwrap();
+        assert_eq!(pool.add_transaction(signed_transaction(&wallet, 3, 1)), Err(TxError::Duplicate));
+
+        // 수수료가 가장 높은 nonce 1 은 nonce 0 이 골라진 뒤에야 후보가 된다.
+        let order: Vec<(u64, u64)> = pool.best_transactions(2).iter().map(|tx| (tx.fee, tx.nonce)).collect();
+        assert_eq!(order, vec![(5, 0), (1, 0)]);
+
+        let selected = pool.select_transcations_by_fee(3);
+        let order: Vec<(u64, u64)> = selected.iter().map(|tx| (tx.fee, tx.nonce)).collect();
+        assert_eq!(order, vec![(5, 0), (1, 0), (9, 1)]);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_pool_skips_nonce_gaps() {
+        let wallet = Wallet::new();
+        let mut pool = TransactionPool::new();
+        for nonce in [3, 0, 1] {
+            pool.add_transaction(signed_transaction(&wallet, 10 + nonce, nonce)).unwrap();
+        }
+
+        let nonces: Vec<u64> = pool.select_transcations_by_fee(10).iter().map(|tx| tx.nonce).collect();
+        assert_eq!(nonces, vec![0, 1]);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.iter().next().unwrap().nonce, 3);
+    }
+
+    #[test]
+    fn test_pool_prune() {
+        let wallet = Wallet::new();
+        let mut pool = TransactionPool::new();
+        for nonce in 0..3 {
+            pool.add_transaction(signed_transaction(&wallet, 1, nonce)).unwrap();
+        }
+
+        pool.prune(|tx| tx.nonce >= 2);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.iter().next().unwrap().nonce, 2);
+    }
+}
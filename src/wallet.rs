@@ -2,7 +2,7 @@
 use sha2::{Sha256, Digest};
 use p256::{ecdsa::{signature::{Signer, Verifier}, Signature, SigningKey, VerifyingKey}, elliptic_curve::rand_core::OsRng};
 
-use crate::transaction::Transaction;
+use crate::{error::WalletError, transaction::Transaction};
 
 pub struct Wallet {
     private_key: SigningKey,
@@ -18,11 +18,7 @@ impl Wallet {
     }
 
     pub fn generate_address(&self) -> String {
-        // TODO: binding 왜 써야 했었지??
-        let binding = self.public_key.to_encoded_point(false);
-        let public_key_bytes = binding.as_bytes();
-        let hashed_public_key = Wallet::hash_public_key(public_key_bytes);
-        hex::encode(hashed_public_key)
+        address_from_public_key(&self.public_key)
     }
 
     pub fn hash_public_key(public_key: &[u8]) -> Vec<u8> {
@@ -32,24 +28,40 @@ impl Wallet {
     }
 
     // 생성자의 개인키로 서명함으로써 생성자가 이 트랜잭션을 만들었다고 알린다.
-    pub fn sign_transaction(&self, transaction: &mut Transaction) {
+    pub fn sign_transaction(&self, transaction: &mut Transaction) -> Result<(), WalletError> {
         let transaction_hash = transaction.calculate_hash_sign();
-        let signature: Signature = self.private_key.sign(transaction_hash.as_bytes());
+        let signature: Signature = self.private_key.try_sign(transaction_hash.as_bytes())
+            .map_err(|_| WalletError::SigningFailed)?;
 
-        transaction.signature = Some(hex::encode(signature.to_bytes()));
-        transaction.public_key = Some(self.public_key.clone());
+        transaction.signature = Some(signature);
+        transaction.public_key = Some(self.public_key);
+        Ok(())
     }
 
-    pub fn verify_signature(&self, transaction: &Transaction) -> bool {
+    pub fn verify_signature(&self, transaction: &Transaction) -> Result<(), WalletError> {
         let transaction_hash = transaction.calculate_hash_sign();
-        let signature_bytes = hex::decode(transaction.signature.clone().expect("Not signed transaction."))
-            .expect("Invalid signature.");
-        let signature = Signature::from_slice(&signature_bytes).unwrap();
-        self.public_key.verify(transaction_hash.as_bytes(), &signature).is_ok()
+        let signature = transaction.signature.ok_or(WalletError::Unsigned)?;
+        if transaction.public_key != Some(self.public_key) {
+            return Err(WalletError::KeyMismatch);
+        }
+        self.public_key.verify(transaction_hash.as_bytes(), &signature)
+            .map_err(|_| WalletError::InvalidSignature)
     }
 
 }
 
+impl Default for Wallet {
+    fn default() -> Wallet {
+        Wallet::new()
+    }
+}
+
+// 주소는 공개키(비압축 SEC1)의 해시. 트랜잭션 검증에서도 같은 함수로 sender 를 확인한다.
+pub fn address_from_public_key(public_key: &VerifyingKey) -> String {
+    let encoded_point = public_key.to_encoded_point(false);
+    hex::encode(Wallet::hash_public_key(encoded_point.as_bytes()))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -66,10 +78,10 @@ mod test {
     fn test_verify_transaction() {
         let wallet = Wallet::new();
 
-        let mut transaction = Transaction::new(String::from("A"), String::from("B"), 1000,1);
-        wallet.sign_transaction(&mut transaction);
+        let mut transaction = Transaction::new(String::from("A"), String::from("B"), 1000, 1, 0);
+        wallet.sign_transaction(&mut transaction).unwrap();
 
-        let is_valid = wallet.verify_signature(&transaction);
-        assert!(is_valid);
+        assert!(wallet.verify_signature(&transaction).is_ok());
+        assert_eq!(Wallet::new().verify_signature(&transaction), Err(WalletError::KeyMismatch));
     }
 }
\ No newline at end of file